@@ -0,0 +1,122 @@
+use crate::claims::{Artifact, BuildEnvironment, FetchedInput};
+use serde::{Deserialize, Serialize};
+
+/// The version of the `AttestationDocument` JSON schema. Bump this whenever fields are added,
+/// removed or change their meaning.
+pub const ATTESTATION_DOCUMENT_SCHEMA_VERSION: u32 = 7;
+
+/// The attestation of a build as written to the `.cert` files next to the artifacts and published
+/// in the transparency log. Only `attestation` is signed; the other fields repeat what it covers
+/// in readable form (the lists via the digests in `AttestationClaims`), so verifiers must
+/// check them against it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AttestationDocument {
+    pub schema_version: u32,
+    pub commit_hash: String,
+
+    /// Can be recomputed from a clone of the commit, see `source_tree::source_tree_digest`.
+    pub source_tree_digest: String,
+
+    /// The rootfs of the sandbox and the dependency cache of hermetic builds.
+    pub build_environment: BuildEnvironment,
+    pub build_environment_digest: String,
+
+    /// The downloads pinned by the lockfiles in the source tree.
+    pub fetched_inputs: Vec<FetchedInput>,
+    pub fetched_inputs_digest: String,
+    pub artifacts: Vec<Artifact>,
+    pub artifacts_digest: String,
+    pub run_id: u32,
+
+    /// The SLSA provenance of the build as an in-toto Statement (JSON), exactly as covered by
+    /// `AttestationClaims::provenance_digest`, see `provenance::provenance_statement`.
+    pub provenance: String,
+
+    /// Hex encoded nonce from the host (empty if there was none).
+    pub nonce: String,
+
+    /// Base64 encoded PCRs as reported by the NSM.
+    pub pcr0: String,
+    pub pcr1: String,
+    pub pcr2: String,
+
+    /// The base64 encoded COSE document from the NSM.
+    pub attestation: String,
+}
+
+impl AttestationDocument {
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Parses a document, rejecting schema versions other than the one we know.
+    pub fn from_json(s: &str) -> anyhow::Result<AttestationDocument> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            schema_version: u32,
+        }
+
+        // check the version first, as the other fields may differ between versions
+        let versioned: Versioned = serde_json::from_str(s)
+            .map_err(|e| anyhow::anyhow!("Failed to parse the attestation document: {}", e))?;
+        if versioned.schema_version != ATTESTATION_DOCUMENT_SCHEMA_VERSION {
+            anyhow::bail!(
+                "Unsupported attestation document schema version {} (expected {})",
+                versioned.schema_version,
+                ATTESTATION_DOCUMENT_SCHEMA_VERSION
+            );
+        }
+        let document = serde_json::from_str(s)
+            .map_err(|e| anyhow::anyhow!("Failed to parse the attestation document: {}", e))?;
+        Ok(document)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_document(artifact_name: &str) -> AttestationDocument {
+        AttestationDocument {
+            schema_version: ATTESTATION_DOCUMENT_SCHEMA_VERSION,
+            commit_hash: "commit".to_string(),
+            source_tree_digest: "tree".to_string(),
+            build_environment: BuildEnvironment::default(),
+            build_environment_digest: "environment".to_string(),
+            fetched_inputs: vec![],
+            fetched_inputs_digest: "inputs".to_string(),
+            artifacts: vec![Artifact {
+                name: artifact_name.to_string(),
+                hash: "aaaa".to_string(),
+            }],
+            artifacts_digest: "digest".to_string(),
+            run_id: 42,
+            provenance: "{}".to_string(),
+            nonce: "00".to_string(),
+            pcr0: "fake0".to_string(),
+            pcr1: "fake1".to_string(),
+            pcr2: "fake2".to_string(),
+            attestation: "fake signature".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_with_special_characters() {
+        let document = sample_document(r#"app "v1"\n\"#);
+        let json = document.to_json().unwrap();
+        assert_eq!(AttestationDocument::from_json(&json).unwrap(), document);
+    }
+
+    #[test]
+    fn test_rejects_unknown_schema_version() {
+        let mut document = sample_document("app");
+        document.schema_version = ATTESTATION_DOCUMENT_SCHEMA_VERSION + 1;
+        let json = document.to_json().unwrap();
+        let err = AttestationDocument::from_json(&json).unwrap_err();
+        assert!(err.to_string().contains("Unsupported"));
+
+        // documents from before the schema version are rejected as well
+        let err = AttestationDocument::from_json(r#"{"commit_hash": "commit"}"#).unwrap_err();
+        assert!(err.to_string().contains("Failed to parse"));
+    }
+}
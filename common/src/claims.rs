@@ -0,0 +1,281 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// The version of `AttestationClaims`. Bump this whenever its fields or their meaning change, so
+/// that verifiers reject documents they do not understand instead of misreading them.
+pub const CLAIMS_VERSION: u32 = 7;
+
+/// The NSM rejects larger user data.
+pub const MAX_USER_DATA_LEN: usize = 512;
+
+/// An artifact as reported by the attestation hook.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Artifact {
+    pub name: String,
+    pub hash: String,
+}
+
+/// An input that the build downloads, as pinned by a lockfile in the source tree (see
+/// `lockfiles::fetched_inputs`). The hash is prefixed with its algorithm in the notation of the
+/// lockfile, e.g. `sha256:<hex>` for crates, `sha512-<base64>` for npm packages, `h1:<base64>` for
+/// Go modules and `git:<commit>` for git dependencies.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FetchedInput {
+    pub url: String,
+    pub hash: String,
+
+    /// The size in bytes, if known (lockfiles do not record it).
+    pub size: Option<u64>,
+}
+
+/// Where a build ran, apart from the enclave client itself (which the PCRs cover).
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct BuildEnvironment {
+    /// The digest (see `rootfs::rootfs_digest`) of the read-only rootfs that the sandbox ran on.
+    /// `None` for builds outside of a sandbox.
+    pub rootfs_digest: Option<String>,
+
+    /// The digest (see `source_tree::source_tree_digest`) of the dependency cache of a hermetic
+    /// build, which had no network access after the checkout. `None` for other builds.
+    pub hermetic_cache_digest: Option<String>,
+
+    /// The manifest digest of the build environment image that the rootfs was unpacked from, if
+    /// the job asked for one (see `EnclaveClientArgs::build_environment_image`).
+    pub image_digest: Option<String>,
+
+    /// The SHA-256 (hex) of the allowlist that permitted the image, if any.
+    pub image_allowlist_digest: Option<String>,
+}
+
+/// What the enclave client vouches for in the user data of the attestation document. The claims
+/// are encoded as CBOR, whose map keys are the (fixed) field names in declaration order, so the
+/// encoding is canonical and no field value can be mistaken for a separator.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AttestationClaims {
+    pub version: u32,
+    pub commit_hash: String,
+
+    /// See `source_tree::source_tree_digest`, taken right after the checkout.
+    pub source_tree_digest: String,
+
+    /// See `build_environment_digest`. The digests of the environment do not fit separately.
+    pub build_environment_digest: String,
+
+    /// See `fetched_inputs_digest`.
+    pub fetched_inputs_digest: String,
+
+    /// See `artifacts_digest`. The list itself would quickly exceed `MAX_USER_DATA_LEN`.
+    pub artifacts_digest: String,
+
+    /// See `provenance::provenance_digest`. The statement repeats the other claims, but as a
+    /// whole it is what downstream tooling consumes.
+    pub provenance_digest: String,
+}
+
+impl AttestationClaims {
+    pub fn new(
+        commit_hash: String,
+        source_tree_digest: String,
+        build_environment: &BuildEnvironment,
+        fetched_inputs: &[FetchedInput],
+        artifacts: &[Artifact],
+        provenance_digest: String,
+    ) -> AttestationClaims {
+        AttestationClaims {
+            version: CLAIMS_VERSION,
+            commit_hash,
+            source_tree_digest,
+            build_environment_digest: build_environment_digest(build_environment),
+            fetched_inputs_digest: fetched_inputs_digest(fetched_inputs),
+            artifacts_digest: artifacts_digest(artifacts),
+            provenance_digest,
+        }
+    }
+
+    /// The encoding that goes into the user data of the attestation document.
+    pub fn to_user_data(&self) -> anyhow::Result<Vec<u8>> {
+        let user_data = serde_cbor::to_vec(self)?;
+        if user_data.len() > MAX_USER_DATA_LEN {
+            anyhow::bail!(
+                "The attestation claims take {} bytes, but at most {} bytes fit into the user data",
+                user_data.len(),
+                MAX_USER_DATA_LEN
+            );
+        }
+        Ok(user_data)
+    }
+
+    /// Decodes the claims from the user data, rejecting versions other than `CLAIMS_VERSION`.
+    pub fn from_user_data(user_data: &[u8]) -> anyhow::Result<AttestationClaims> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            version: u32,
+        }
+
+        // check the version first, as the other fields may differ between versions
+        let versioned: Versioned = serde_cbor::from_slice(user_data)
+            .map_err(|e| anyhow::anyhow!("Failed to decode the attestation claims: {}", e))?;
+        if versioned.version != CLAIMS_VERSION {
+            anyhow::bail!(
+                "Unsupported attestation claims version {} (expected {})",
+                versioned.version,
+                CLAIMS_VERSION
+            );
+        }
+        let claims = serde_cbor::from_slice(user_data)
+            .map_err(|e| anyhow::anyhow!("Failed to decode the attestation claims: {}", e))?;
+        Ok(claims)
+    }
+}
+
+/// The SHA-256 (hex) over the CBOR encoding of the artifact list. The order is the order in which
+/// the artifacts were reported.
+pub fn artifacts_digest(artifacts: &[Artifact]) -> String {
+    let encoded = serde_cbor::to_vec(&artifacts).expect("artifacts can always be encoded");
+    format!("{:x}", Sha256::digest(encoded))
+}
+
+/// The SHA-256 (hex) over the CBOR encoding of the build environment.
+pub fn build_environment_digest(build_environment: &BuildEnvironment) -> String {
+    let encoded =
+        serde_cbor::to_vec(build_environment).expect("the environment can always be encoded");
+    format!("{:x}", Sha256::digest(encoded))
+}
+
+/// The SHA-256 (hex) over the CBOR encoding of the fetched inputs, in the order of the list.
+pub fn fetched_inputs_digest(fetched_inputs: &[FetchedInput]) -> String {
+    let encoded = serde_cbor::to_vec(&fetched_inputs).expect("inputs can always be encoded");
+    format!("{:x}", Sha256::digest(encoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifact(name: &str, hash: &str) -> Artifact {
+        Artifact {
+            name: name.to_string(),
+            hash: hash.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_claims_roundtrip() {
+        let claims = AttestationClaims::new(
+            "commit".to_string(),
+            "tree".to_string(),
+            &BuildEnvironment::default(),
+            &[],
+            &[artifact("app", "aaaa")],
+            "provenance".to_string(),
+        );
+        let user_data = claims.to_user_data().unwrap();
+        assert_eq!(
+            AttestationClaims::from_user_data(&user_data).unwrap(),
+            claims
+        );
+    }
+
+    #[test]
+    fn test_separators_in_values_are_unambiguous() {
+        // these two would have been the same comma-joined user data
+        let a = AttestationClaims::new(
+            "c".to_string(),
+            "t".to_string(),
+            &BuildEnvironment::default(),
+            &[],
+            &[artifact("a,b=c", "d")],
+            "p".to_string(),
+        );
+        let b = AttestationClaims::new(
+            "c".to_string(),
+            "t".to_string(),
+            &BuildEnvironment::default(),
+            &[],
+            &[artifact("a", "b=c,d")],
+            "p".to_string(),
+        );
+        assert_ne!(a.to_user_data().unwrap(), b.to_user_data().unwrap());
+
+        // and neither do the separators of the old artifact list format
+        let a = [artifact("a;b", "c")];
+        let b = [artifact("a", "b;c")];
+        assert_ne!(artifacts_digest(&a), artifacts_digest(&b));
+    }
+
+    #[test]
+    fn test_encoding_is_stable() {
+        // any change here breaks the verification of existing attestations
+        let claims = AttestationClaims::new(
+            "commit".to_string(),
+            "tree".to_string(),
+            &BuildEnvironment::default(),
+            &[],
+            &[artifact("app", "aaaa")],
+            "provenance".to_string(),
+        );
+        let user_data = claims.to_user_data().unwrap();
+        assert_eq!(user_data[0], 0xa7); // a map with seven entries
+        assert_eq!(&user_data[1..9], b"\x67version");
+        assert_eq!(
+            claims.artifacts_digest,
+            format!(
+                "{:x}",
+                Sha256::digest(b"\x81\xa2\x64name\x63app\x64hash\x64aaaa")
+            )
+        );
+        assert_eq!(
+            claims.build_environment_digest,
+            format!(
+                "{:x}",
+                Sha256::digest(
+                    b"\xa4\x6drootfs_digest\xf6\x75hermetic_cache_digest\xf6\x6cimage_digest\xf6\x76image_allowlist_digest\xf6"
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_version() {
+        let mut claims = AttestationClaims::new(
+            "commit".to_string(),
+            "tree".to_string(),
+            &BuildEnvironment::default(),
+            &[],
+            &[],
+            "provenance".to_string(),
+        );
+        claims.version = CLAIMS_VERSION + 1;
+        let user_data = serde_cbor::to_vec(&claims).unwrap();
+        let err = AttestationClaims::from_user_data(&user_data).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Unsupported attestation claims version"));
+    }
+
+    #[test]
+    fn test_rejects_oversized_claims() {
+        let claims = AttestationClaims::new(
+            "c".repeat(MAX_USER_DATA_LEN),
+            "t".to_string(),
+            &BuildEnvironment::default(),
+            &[],
+            &[],
+            "p".to_string(),
+        );
+        assert!(claims.to_user_data().is_err());
+
+        // but the largest realistic claims fit
+        let digest = "f".repeat(64);
+        let claims = AttestationClaims {
+            version: CLAIMS_VERSION,
+            commit_hash: "f".repeat(40),
+            source_tree_digest: digest.clone(),
+            build_environment_digest: digest.clone(),
+            fetched_inputs_digest: digest.clone(),
+            artifacts_digest: digest.clone(),
+            provenance_digest: digest,
+        };
+        assert!(claims.to_user_data().is_ok());
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::{bail, Context};
+use std::path::{Component, Path, PathBuf};
+
+/// Reads the commit that is checked out in `checkout` straight from its `.git` directory. We do
+/// not run `git` here, as the repository (including its config) is controlled by the build.
+pub fn read_head_commit(checkout: &Path) -> anyhow::Result<String> {
+    let git_dir = resolve_git_dir(checkout)?;
+    let head = std::fs::read_to_string(git_dir.join("HEAD"))
+        .with_context(|| format!("Failed to read HEAD of {:?}", checkout))?;
+    let head = head.trim();
+
+    let commit_hash = match head.strip_prefix("ref:") {
+        Some(reference) => resolve_ref(&git_dir, reference.trim())?,
+        None => head.to_string(),
+    };
+    if !is_object_name(&commit_hash) {
+        bail!(
+            "HEAD of {:?} does not point to a commit: {:?}",
+            checkout,
+            commit_hash
+        );
+    }
+    Ok(commit_hash)
+}
+
+/// `.git` is usually a directory, but it is a `gitdir: <path>` file for worktrees and submodules.
+fn resolve_git_dir(checkout: &Path) -> anyhow::Result<PathBuf> {
+    let dot_git = checkout.join(".git");
+    let metadata = std::fs::symlink_metadata(&dot_git)
+        .with_context(|| format!("{:?} is not a git checkout", checkout))?;
+    if metadata.is_dir() {
+        return Ok(dot_git);
+    }
+    if !metadata.is_file() {
+        bail!("{:?} is neither a directory nor a file", dot_git);
+    }
+    let content = std::fs::read_to_string(&dot_git)?;
+    let git_dir = content
+        .trim()
+        .strip_prefix("gitdir:")
+        .with_context(|| format!("{:?} does not point to a git directory", dot_git))?;
+    Ok(checkout.join(git_dir.trim()))
+}
+
+fn resolve_ref(git_dir: &Path, reference: &str) -> anyhow::Result<String> {
+    let path = Path::new(reference);
+    if !reference.starts_with("refs/")
+        || !path.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        bail!("Invalid ref in HEAD: {:?}", reference);
+    }
+
+    // loose refs take precedence over packed ones
+    if let Ok(content) = std::fs::read_to_string(git_dir.join(path)) {
+        return Ok(content.trim().to_string());
+    }
+    let packed_refs = std::fs::read_to_string(git_dir.join("packed-refs"))
+        .with_context(|| format!("Failed to resolve {}", reference))?;
+    packed_refs
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.starts_with('^'))
+        .filter_map(|line| line.split_once(' '))
+        .find(|(_, name)| *name == reference)
+        .map(|(commit_hash, _)| commit_hash.to_string())
+        .with_context(|| format!("Failed to resolve {}", reference))
+}
+
+/// SHA-1 or SHA-256 object names.
+fn is_object_name(s: &str) -> bool {
+    (s.len() == 40 || s.len() == 64) && s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn temp_checkout() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join(".git/refs/heads")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_read_head_commit_from_loose_and_packed_refs() {
+        let dir = temp_checkout();
+        let checkout = dir.path();
+        std::fs::write(checkout.join(".git/HEAD"), "ref: refs/heads/main\n").unwrap();
+        std::fs::write(
+            checkout.join(".git/packed-refs"),
+            format!("# pack-refs with: peeled\n{} refs/heads/main\n", COMMIT),
+        )
+        .unwrap();
+        assert_eq!(read_head_commit(checkout).unwrap(), COMMIT);
+
+        let other = "fedcba9876543210fedcba9876543210fedcba98";
+        std::fs::write(
+            checkout.join(".git/refs/heads/main"),
+            format!("{}\n", other),
+        )
+        .unwrap();
+        assert_eq!(read_head_commit(checkout).unwrap(), other);
+
+        // detached
+        std::fs::write(checkout.join(".git/HEAD"), COMMIT).unwrap();
+        assert_eq!(read_head_commit(checkout).unwrap(), COMMIT);
+    }
+
+    #[test]
+    fn test_read_head_commit_through_gitdir_file() {
+        let dir = temp_checkout();
+        let checkout = dir.path();
+        std::fs::rename(checkout.join(".git"), checkout.join("actual-git-dir")).unwrap();
+        std::fs::write(checkout.join(".git"), "gitdir: actual-git-dir\n").unwrap();
+        std::fs::write(checkout.join("actual-git-dir/HEAD"), COMMIT).unwrap();
+        assert_eq!(read_head_commit(checkout).unwrap(), COMMIT);
+    }
+
+    #[test]
+    fn test_rejects_invalid_heads() {
+        let dir = temp_checkout();
+        let checkout = dir.path();
+        assert!(read_head_commit(checkout).is_err());
+
+        std::fs::write(checkout.join(".git/HEAD"), "ref: refs/../../../etc/passwd").unwrap();
+        assert!(read_head_commit(checkout).is_err());
+
+        std::fs::write(checkout.join(".git/HEAD"), "not a commit").unwrap();
+        assert!(read_head_commit(checkout).is_err());
+
+        assert!(read_head_commit(&checkout.join("missing")).is_err());
+    }
+}
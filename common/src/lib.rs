@@ -1,10 +1,20 @@
+pub mod attestation_document;
+pub mod claims;
+pub mod git;
+pub mod lockfiles;
 pub mod messages;
 pub mod protocol;
+pub mod provenance;
+pub mod rootfs;
+pub mod secure_channel;
+pub mod source_tree;
+pub mod transport;
 
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
+use std::path::PathBuf;
 use tokio::time;
 use tokio_vsock::VsockAddr;
 use tracing_subscriber::layer::SubscriberExt;
@@ -69,6 +79,21 @@ pub enum RunnerStartMode {
     SandboxPlus,
 }
 
+/// The kind of evidence the enclave client produces for its attestations.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[clap(rename_all = "snake_case")]
+pub enum AttestationBackend {
+    /// The Nitro Secure Module of the enclave.
+    Nitro,
+
+    /// The NSM emulator of the enclave client (see its `--nsm-emulator-dir`). The documents look
+    /// like the ones from Nitro, but are signed by a local root certificate.
+    Emulated,
+
+    /// No attestation at all, the documents only contain placeholders (e.g. for running locally).
+    None,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct FakeRunnerArgs {
     /// The branch to check out (optional, defaults to main)
@@ -117,19 +142,135 @@ pub struct EnclaveClientArgs {
     /// Whether to simulate the runner or not.
     pub fake_runner_args: Option<FakeRunnerArgs>,
 
-    /// Whether to compute a fake attestation document (e.g. for running locally)
-    pub use_fake_attestation: bool,
+    /// Which backend produces the attestations (e.g. `None` for running locally)
+    pub attestation_backend: AttestationBackend,
+
+    /// Build hermetically: the sandbox loses its network access right after the checkout, and the
+    /// build takes its dependencies from this read-only cache directory (as seen by the enclave
+    /// client), whose digest is attested.
+    pub hermetic_cache_path: Option<PathBuf>,
+
+    /// The cgroup limits of the sandbox.
+    pub resource_limits: ResourceLimits,
+
+    /// Run the sandbox on this image instead of the rootfs baked into the enclave image.
+    pub build_environment_image: Option<BuildEnvironmentImage>,
+}
+
+/// A build environment image as an OCI image layout tarball (e.g. `skopeo copy
+/// docker-daemon:<image> oci-archive:<path>`). The enclave client only unpacks images whose
+/// digest is on its (measured) allowlist.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildEnvironmentImage {
+    /// The tarball, as seen by the enclave client (in `nitro` mode it has to be part of the
+    /// enclave image).
+    pub path: PathBuf,
+
+    /// The digest of the image manifest, e.g. `sha256:<hex>`.
+    pub digest: String,
+}
+
+/// Checks that an image digest is a (lowercase) `sha256:<hex>` digest, so that it is safe to
+/// use as a path in the image layout as well.
+pub fn validate_image_digest(digest: &str) -> anyhow::Result<()> {
+    let valid = digest.strip_prefix("sha256:").is_some_and(|hex| {
+        hex.len() == 64
+            && hex
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    });
+    if !valid {
+        anyhow::bail!("Invalid image digest {:?}, expected sha256:<hex>", digest);
+    }
+    Ok(())
+}
+
+impl EnclaveClientArgs {
+    /// Rejects combinations that the enclave client cannot honor:
+    ///
+    /// - hermetic builds need a sandbox (whose network can be cut off) and are not supported with
+    ///   the actual runner yet: it talks to GitHub for as long as the job runs (for the job
+    ///   status, the logs and the artifacts), which the cut-off after the checkout would break,
+    /// - resource limits need a sandbox, as the runner in `Direct` mode shares the cgroup of the
+    ///   enclave client,
+    /// - build environment images need a sandbox to run on.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let direct = matches!(self.runner_start_mode, RunnerStartMode::Direct);
+        if self.hermetic_cache_path.is_some() {
+            if direct {
+                anyhow::bail!(
+                    "Hermetic builds require the sandbox or sandbox_plus runner start mode"
+                );
+            }
+            if self.fake_runner_args.is_none() {
+                anyhow::bail!(
+                    "Hermetic builds are not supported with the actual GitHub runner yet, as it \
+                     needs GitHub for the whole job (job status, logs and artifacts) and the \
+                     sandbox loses its network after the checkout; use the simulated runner"
+                );
+            }
+        }
+        if self.resource_limits != ResourceLimits::default() && direct {
+            anyhow::bail!("Resource limits require the sandbox or sandbox_plus runner start mode");
+        }
+        if let Some(image) = &self.build_environment_image {
+            if direct {
+                anyhow::bail!(
+                    "Build environment images require the sandbox or sandbox_plus runner start mode"
+                );
+            }
+            validate_image_digest(&image.digest)?;
+        }
+        self.resource_limits.validate()
+    }
+}
+
+/// The cgroup limits of the sandbox, so that a build cannot starve the enclave client. `None`
+/// leaves the resource unlimited.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    /// Memory (without swap) in bytes.
+    pub memory_bytes: Option<u64>,
+
+    /// CPU time in thousandths of a CPU, e.g. `1500` for one and a half CPUs.
+    pub milli_cpus: Option<u64>,
+
+    /// The number of processes and threads.
+    pub pids: Option<u64>,
+
+    /// The relative weight of the block I/O (10 to 1000).
+    pub block_io_weight: Option<u16>,
+}
+
+impl ResourceLimits {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.memory_bytes == Some(0) || self.milli_cpus == Some(0) || self.pids == Some(0) {
+            anyhow::bail!("Resource limits of zero would not let the build run at all");
+        }
+        if let Some(block_io_weight) = self.block_io_weight {
+            if !(10..=1000).contains(&block_io_weight) {
+                anyhow::bail!(
+                    "The block I/O weight must be between 10 and 1000, got {}",
+                    block_io_weight
+                );
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Display for EnclaveClientArgs {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "EnclaveClientArgs {{ runner_args: {}, runner_start_mode: {:?}, fake_runner_args: {}, use_fake_attestation: {} }}",
+            "EnclaveClientArgs {{ runner_args: {}, runner_start_mode: {:?}, fake_runner_args: {}, attestation_backend: {:?}, hermetic_cache_path: {:?}, resource_limits: {:?}, build_environment_image: {:?} }}",
             self.runner_args,
             self.runner_start_mode,
             self.fake_runner_args.as_ref().map_or("None".to_string(), |args| args.to_string()),
-            self.use_fake_attestation,
+            self.attestation_backend,
+            self.hermetic_cache_path,
+            self.resource_limits,
+            self.build_environment_image,
         )
     }
 }
@@ -210,6 +351,69 @@ mod tests {
         assert_eq!(redact_token("123"), "***");
     }
 
+    #[test]
+    fn test_validate() {
+        let mut args = EnclaveClientArgs {
+            runner_args: RunnerArgs {
+                github_repository: "owner/repo".to_string(),
+                github_reg_token: "reg_token".to_string(),
+                github_pat_token: "pat_token".to_string(),
+                runner_version: "2.328.0".to_string(),
+                runner_user: "runner".to_string(),
+                runner_uid: 1001,
+                runner_gid: 1001,
+            },
+            runner_start_mode: RunnerStartMode::Direct,
+            fake_runner_args: None,
+            attestation_backend: AttestationBackend::None,
+            hermetic_cache_path: None,
+            resource_limits: ResourceLimits::default(),
+            build_environment_image: None,
+        };
+        assert!(args.validate().is_ok());
+
+        // hermetic builds
+        args.hermetic_cache_path = Some(PathBuf::from("/cache"));
+        assert!(args.validate().is_err());
+        args.runner_start_mode = RunnerStartMode::Sandbox;
+        let error = args.validate().unwrap_err().to_string();
+        assert!(error.contains("not supported with the actual GitHub runner"));
+        args.fake_runner_args = Some(parse_fake_runner_args("subproject".to_string()).unwrap());
+        assert!(args.validate().is_ok());
+
+        // resource limits
+        args.resource_limits.pids = Some(512);
+        assert!(args.validate().is_ok());
+        args.resource_limits.block_io_weight = Some(5);
+        assert!(args.validate().is_err());
+        args.resource_limits.block_io_weight = Some(500);
+        args.resource_limits.memory_bytes = Some(0);
+        assert!(args.validate().is_err());
+        args.resource_limits.memory_bytes = Some(1 << 30);
+        assert!(args.validate().is_ok());
+        args.runner_start_mode = RunnerStartMode::Direct;
+        args.hermetic_cache_path = None;
+        assert!(args.validate().is_err());
+
+        // build environment images
+        args.resource_limits = ResourceLimits::default();
+        args.build_environment_image = Some(BuildEnvironmentImage {
+            path: PathBuf::from("/images/rust.tar"),
+            digest: format!("sha256:{}", "a".repeat(64)),
+        });
+        assert!(args.validate().is_err());
+        args.runner_start_mode = RunnerStartMode::SandboxPlus;
+        assert!(args.validate().is_ok());
+        for digest in [
+            "sha256:../../etc".to_string(),
+            format!("sha256:{}", "A".repeat(64)),
+            format!("sha512:{}", "a".repeat(64)),
+            "a".repeat(64),
+        ] {
+            assert!(validate_image_digest(&digest).is_err(), "{}", digest);
+        }
+    }
+
     #[test]
     fn test_parse_fake_runner_args() {
         let args = parse_fake_runner_args("subproject".to_string()).unwrap();
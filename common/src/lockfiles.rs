@@ -0,0 +1,438 @@
+use crate::claims::FetchedInput;
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::warn;
+
+/// The registries whose download URLs can be derived from the lockfile entries.
+const CRATES_IO_INDEXES: [&str; 2] = [
+    "registry+https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+const CRATES_IO_DOWNLOAD_URL: &str = "https://static.crates.io/crates";
+const GO_PROXY_URL: &str = "https://proxy.golang.org";
+
+/// Collects the network-fetched inputs that the lockfiles in a source tree pin: `Cargo.lock`,
+/// `package-lock.json` and `go.sum`. The build can still fetch other things (or ignore the
+/// lockfiles, e.g. without `cargo build --locked` or `npm ci`), so this is what the build was
+/// supposed to download, not a record of the traffic. A lockfile that cannot be parsed does not
+/// fail the build: it is recorded itself, as a `file:` input with its path and digest, so that
+/// the claims show which pins were not measured. The result is sorted and deduplicated.
+pub fn fetched_inputs(root: &Path) -> anyhow::Result<Vec<FetchedInput>> {
+    let mut inputs = vec![];
+    collect_inputs(root, root, &mut inputs)
+        .with_context(|| format!("Failed to collect the fetched inputs in {:?}", root))?;
+    inputs.sort_by(|a, b| (&a.url, &a.hash).cmp(&(&b.url, &b.hash)));
+    inputs.dedup();
+    Ok(inputs)
+}
+
+fn collect_inputs(root: &Path, dir: &Path, inputs: &mut Vec<FetchedInput>) -> anyhow::Result<()> {
+    for dir_entry in std::fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let path = dir_entry.path();
+        let file_type = dir_entry.file_type()?;
+        let name = dir_entry.file_name();
+        if file_type.is_dir() {
+            // the packages in `node_modules` are covered by the lockfile next to it
+            if name != ".git" && name != "node_modules" {
+                collect_inputs(root, &path, inputs)?;
+            }
+            continue;
+        }
+        if !file_type.is_file() {
+            continue;
+        }
+
+        let parse = match name.to_str() {
+            Some("Cargo.lock") => parse_cargo_lock,
+            Some("package-lock.json") => parse_package_lock,
+            Some("go.sum") => parse_go_sum,
+            _ => continue,
+        };
+        let content = std::fs::read(&path)?;
+        let parsed = std::str::from_utf8(&content)
+            .map_err(anyhow::Error::from)
+            .and_then(parse);
+        match parsed {
+            Ok(parsed) => inputs.extend(parsed),
+            Err(err) => {
+                let relative_path = path.strip_prefix(root)?;
+                warn!(
+                    "Recording {:?} as unmeasured, failed to parse it: {:?}",
+                    relative_path, err
+                );
+                inputs.push(FetchedInput {
+                    url: format!("file:{}", relative_path.display()),
+                    hash: format!("sha256:{:x}", Sha256::digest(&content)),
+                    size: Some(content.len() as u64),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Registry crates carry their SHA-256 as `checksum`, git dependencies their commit after `#`.
+/// Crates from the workspace itself have no `source`. Lockfiles of format version 1 keep the
+/// checksums in `[metadata]` instead, keyed by `checksum <name> <version> (<source>)`.
+fn parse_cargo_lock(content: &str) -> anyhow::Result<Vec<FetchedInput>> {
+    #[derive(Deserialize)]
+    struct CargoLock {
+        #[serde(default)]
+        package: Vec<Package>,
+        #[serde(default)]
+        metadata: BTreeMap<String, String>,
+    }
+
+    #[derive(Deserialize)]
+    struct Package {
+        name: String,
+        version: String,
+        source: Option<String>,
+        checksum: Option<String>,
+    }
+
+    let cargo_lock: CargoLock = toml::from_str(content)?;
+    let mut inputs = vec![];
+    for package in cargo_lock.package {
+        let Some(source) = package.source else {
+            continue;
+        };
+        if let Some(repository) = source.strip_prefix("git+") {
+            let (url, commit) = repository
+                .split_once('#')
+                .with_context(|| format!("{} has no commit in {:?}", package.name, source))?;
+            inputs.push(FetchedInput {
+                url: url.to_string(),
+                hash: format!("git:{}", commit),
+                size: None,
+            });
+            continue;
+        }
+
+        let checksum = package
+            .checksum
+            .or_else(|| {
+                let key = format!("checksum {} {} ({})", package.name, package.version, source);
+                cargo_lock.metadata.get(&key).cloned()
+            })
+            // version 1 marks the crates without a checksum as `<none>`
+            .filter(|checksum| checksum != "<none>")
+            .with_context(|| format!("{} {} has no checksum", package.name, package.version))?;
+        let url = if CRATES_IO_INDEXES.contains(&source.as_str()) {
+            format!(
+                "{}/{}/{}-{}.crate",
+                CRATES_IO_DOWNLOAD_URL, package.name, package.name, package.version
+            )
+        } else {
+            // the download URL of other registries is in their index
+            format!("{}#{}@{}", source, package.name, package.version)
+        };
+        inputs.push(FetchedInput {
+            url,
+            hash: format!("sha256:{}", checksum),
+            size: None,
+        });
+    }
+    Ok(inputs)
+}
+
+/// Lockfile versions 2 and 3 list all packages in `packages`; version 1 nests them in
+/// `dependencies`. Linked, local and bundled packages are not downloaded and are skipped.
+fn parse_package_lock(content: &str) -> anyhow::Result<Vec<FetchedInput>> {
+    #[derive(Deserialize)]
+    struct PackageLock {
+        packages: Option<BTreeMap<String, Package>>,
+        dependencies: Option<BTreeMap<String, Package>>,
+    }
+
+    #[derive(Deserialize)]
+    struct Package {
+        resolved: Option<String>,
+        integrity: Option<String>,
+        #[serde(default)]
+        link: bool,
+        #[serde(default)]
+        dependencies: BTreeMap<String, serde_json::Value>,
+    }
+
+    fn push(inputs: &mut Vec<FetchedInput>, name: &str, package: Package) -> anyhow::Result<()> {
+        let Some(url) = package.resolved else {
+            return Ok(());
+        };
+        if package.link || url.starts_with("file:") {
+            return Ok(());
+        }
+        // git dependencies carry their commit in the URL instead
+        let hash = match package.integrity {
+            Some(integrity) => integrity,
+            None => match url.split_once('#') {
+                Some((_, commit)) if url.starts_with("git") => format!("git:{}", commit),
+                _ => bail!("{} has no integrity", name),
+            },
+        };
+        inputs.push(FetchedInput {
+            url,
+            hash,
+            size: None,
+        });
+        Ok(())
+    }
+
+    fn push_nested(
+        inputs: &mut Vec<FetchedInput>,
+        dependencies: BTreeMap<String, Package>,
+    ) -> anyhow::Result<()> {
+        for (name, package) in dependencies {
+            let nested = package
+                .dependencies
+                .iter()
+                .map(|(name, value)| Ok((name.clone(), Package::deserialize(value)?)))
+                .collect::<Result<BTreeMap<_, _>, serde_json::Error>>()?;
+            push(inputs, &name, package)?;
+            push_nested(inputs, nested)?;
+        }
+        Ok(())
+    }
+
+    let package_lock: PackageLock = serde_json::from_str(content)?;
+    let mut inputs = vec![];
+    if let Some(packages) = package_lock.packages {
+        for (name, package) in packages {
+            push(&mut inputs, &name, package)?;
+        }
+    } else if let Some(dependencies) = package_lock.dependencies {
+        push_nested(&mut inputs, dependencies)?;
+    }
+    Ok(inputs)
+}
+
+/// Every line is `<module> <version>[/go.mod] h1:<hash>`, i.e. the hash of the module zip or of
+/// its `go.mod`, which the Go module proxy serves under separate URLs.
+fn parse_go_sum(content: &str) -> anyhow::Result<Vec<FetchedInput>> {
+    let mut inputs = vec![];
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let [module, version, hash] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+            bail!("Invalid line {:?}", line);
+        };
+        let (version, extension) = match version.strip_suffix("/go.mod") {
+            Some(version) => (version, "mod"),
+            None => (version, "zip"),
+        };
+        inputs.push(FetchedInput {
+            url: format!(
+                "{}/{}/@v/{}.{}",
+                GO_PROXY_URL,
+                escape_go_path(module),
+                escape_go_path(version),
+                extension
+            ),
+            hash: hash.to_string(),
+            size: None,
+        });
+    }
+    Ok(inputs)
+}
+
+/// The module proxy protocol escapes upper case letters as `!` followed by the lower case one.
+fn escape_go_path(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c.is_ascii_uppercase() {
+            escaped.push('!');
+            escaped.push(c.to_ascii_lowercase());
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARGO_LOCK: &str = r#"
+version = 4
+
+[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = ["anyhow", "nsm-io"]
+
+[[package]]
+name = "anyhow"
+version = "1.0.93"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "4c95c10ba0b00a02636238b814946408b1322d5ac4760326e6fb8ec956d85775"
+
+[[package]]
+name = "nsm-io"
+version = "0.1.0"
+source = "git+https://github.com/aws/aws-nitro-enclaves-nsm-api.git?rev=4f468c4#4f468c4e3b2d5c1f8d3e7c6a0b9f1e2d3c4b5a69"
+"#;
+
+    const PACKAGE_LOCK: &str = r#"{
+  "name": "app",
+  "lockfileVersion": 3,
+  "packages": {
+    "": { "name": "app", "dependencies": { "left-pad": "^1.3.0" } },
+    "node_modules/left-pad": {
+      "version": "1.3.0",
+      "resolved": "https://registry.npmjs.org/left-pad/-/left-pad-1.3.0.tgz",
+      "integrity": "sha512-XI5MPzVNApjAyhQzphX8BkmKsKUxD4LdyK24iZeQEsyXuHCs9yOZ4wzPfBvLtaP+FD7jXTOOJd5CN00IKwa9mQ=="
+    },
+    "node_modules/local": { "resolved": "packages/local", "link": true }
+  }
+}"#;
+
+    const GO_SUM: &str = "
+github.com/BurntSushi/toml v1.4.0 h1:kuoIxZQy2WRRk1pttg9asf+WVv6tWQuBNVmK4+nqcTM=
+github.com/BurntSushi/toml v1.4.0/go.mod h1:ukJfTF/6rtPPRCnwkur4qwRxa8vTRFBF0uk2lLoLwho=
+";
+
+    #[test]
+    fn test_parse_cargo_lock() {
+        let inputs = parse_cargo_lock(CARGO_LOCK).unwrap();
+        assert_eq!(
+            inputs,
+            vec![
+                FetchedInput {
+                    url: "https://static.crates.io/crates/anyhow/anyhow-1.0.93.crate".to_string(),
+                    hash: "sha256:4c95c10ba0b00a02636238b814946408b1322d5ac4760326e6fb8ec956d85775"
+                        .to_string(),
+                    size: None,
+                },
+                FetchedInput {
+                    url: "https://github.com/aws/aws-nitro-enclaves-nsm-api.git?rev=4f468c4"
+                        .to_string(),
+                    hash: "git:4f468c4e3b2d5c1f8d3e7c6a0b9f1e2d3c4b5a69".to_string(),
+                    size: None,
+                },
+            ]
+        );
+        assert!(parse_cargo_lock("[[package]]\nname = 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_cargo_lock_version_1() {
+        let cargo_lock = r#"
+[[package]]
+name = "anyhow"
+version = "1.0.93"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = ["anyhow"]
+
+[metadata]
+"checksum anyhow 1.0.93 (registry+https://github.com/rust-lang/crates.io-index)" = "4c95c10ba0b00a02636238b814946408b1322d5ac4760326e6fb8ec956d85775"
+"#;
+        let inputs = parse_cargo_lock(cargo_lock).unwrap();
+        assert_eq!(
+            inputs,
+            vec![FetchedInput {
+                url: "https://static.crates.io/crates/anyhow/anyhow-1.0.93.crate".to_string(),
+                hash: "sha256:4c95c10ba0b00a02636238b814946408b1322d5ac4760326e6fb8ec956d85775"
+                    .to_string(),
+                size: None,
+            }]
+        );
+
+        let cargo_lock = cargo_lock.replace(
+            "= \"4c95c10ba0b00a02636238b814946408b1322d5ac4760326e6fb8ec956d85775\"",
+            "= \"<none>\"",
+        );
+        assert!(parse_cargo_lock(&cargo_lock).is_err());
+    }
+
+    #[test]
+    fn test_parse_package_lock() {
+        let inputs = parse_package_lock(PACKAGE_LOCK).unwrap();
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(
+            inputs[0].url,
+            "https://registry.npmjs.org/left-pad/-/left-pad-1.3.0.tgz"
+        );
+        assert!(inputs[0].hash.starts_with("sha512-"));
+
+        // lockfile version 1
+        let package_lock = r#"{
+          "lockfileVersion": 1,
+          "dependencies": {
+            "a": {
+              "resolved": "https://registry.npmjs.org/a/-/a-1.0.0.tgz",
+              "integrity": "sha1-a",
+              "dependencies": {
+                "b": { "resolved": "https://registry.npmjs.org/b/-/b-2.0.0.tgz", "integrity": "sha1-b" }
+              }
+            }
+          }
+        }"#;
+        let inputs = parse_package_lock(package_lock).unwrap();
+        assert_eq!(
+            inputs.iter().map(|i| i.hash.as_str()).collect::<Vec<_>>(),
+            vec!["sha1-a", "sha1-b"]
+        );
+    }
+
+    #[test]
+    fn test_parse_go_sum() {
+        let inputs = parse_go_sum(GO_SUM).unwrap();
+        assert_eq!(
+            inputs[0].url,
+            "https://proxy.golang.org/github.com/!burnt!sushi/toml/@v/v1.4.0.zip"
+        );
+        assert_eq!(
+            inputs[1].url,
+            "https://proxy.golang.org/github.com/!burnt!sushi/toml/@v/v1.4.0.mod"
+        );
+        assert_eq!(
+            inputs[1].hash,
+            "h1:ukJfTF/6rtPPRCnwkur4qwRxa8vTRFBF0uk2lLoLwho="
+        );
+        assert!(parse_go_sum("github.com/a/b v1.0.0").is_err());
+    }
+
+    #[test]
+    fn test_fetched_inputs_of_a_source_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("web/node_modules/left-pad")).unwrap();
+        std::fs::create_dir_all(root.join("tools")).unwrap();
+        std::fs::write(root.join("Cargo.lock"), CARGO_LOCK).unwrap();
+        std::fs::write(root.join("web/package-lock.json"), PACKAGE_LOCK).unwrap();
+        std::fs::write(root.join("tools/go.sum"), GO_SUM).unwrap();
+        // ignored, as are duplicates
+        std::fs::write(
+            root.join("web/node_modules/left-pad/package-lock.json"),
+            "not json",
+        )
+        .unwrap();
+        std::fs::write(root.join("tools/Cargo.lock"), CARGO_LOCK).unwrap();
+
+        let inputs = fetched_inputs(root).unwrap();
+        assert_eq!(inputs.len(), 5);
+        let mut sorted = inputs.clone();
+        sorted.sort_by(|a, b| a.url.cmp(&b.url));
+        assert_eq!(inputs, sorted);
+
+        // a lockfile that cannot be parsed is recorded as a whole
+        std::fs::write(root.join("tools/go.sum"), "invalid").unwrap();
+        let inputs = fetched_inputs(root).unwrap();
+        assert_eq!(inputs.len(), 4);
+        assert_eq!(
+            inputs[0],
+            FetchedInput {
+                url: "file:tools/go.sum".to_string(),
+                hash: format!("sha256:{:x}", Sha256::digest("invalid")),
+                size: Some(7),
+            }
+        );
+    }
+}
@@ -1,8 +1,103 @@
-use crate::EnclaveClientArgs;
+use crate::{AttestationBackend, EnclaveClientArgs};
 use chrono;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use tracing::info;
 
+/// The version of the wire protocol spoken between the host-server and the enclave-client. Bump
+/// this whenever the layout of `Message` (or anything it contains) changes, as bincode cannot
+/// detect such changes on its own, or when the order of the messages changes.
+pub const PROTOCOL_VERSION: u32 = 11;
+
+/// How often the enclave client sends a `Heartbeat` while a job is running.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Optional message types that are only used once both sides have announced support for them
+/// in their `Hello`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// `EnclaveToHostMessage::Log` messages forwarded from the runner.
+    Log,
+    /// `EnclaveToHostMessage::Timestamp` messages used for the evaluation.
+    Timestamp,
+    /// `EnclaveToHostMessage::Heartbeat` messages, which allow the host to detect hung enclaves.
+    Heartbeat,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[
+        Capability::Log,
+        Capability::Timestamp,
+        Capability::Heartbeat,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::Log => "log",
+            Capability::Timestamp => "timestamp",
+            Capability::Heartbeat => "heartbeat",
+        }
+    }
+
+    /// Returns `None` for capabilities that we do not know (e.g. announced by a newer peer).
+    pub fn parse(s: &str) -> Option<Capability> {
+        Capability::ALL.iter().find(|c| c.as_str() == s).copied()
+    }
+}
+
+/// The first frame sent in each direction. It is encoded separately from `Message` and its layout
+/// must never change, so that peers built from different commits can always decode it and report
+/// a mismatch instead of failing on a garbled `Message`. Capabilities are plain strings so that
+/// unknown ones can be skipped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    pub fn new(capabilities: &[Capability]) -> Hello {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: capabilities
+                .iter()
+                .map(|c| c.as_str().to_string())
+                .collect(),
+        }
+    }
+
+    /// The capabilities of this `Hello` that we know about.
+    pub fn known_capabilities(&self) -> Vec<Capability> {
+        self.capabilities
+            .iter()
+            .filter_map(|c| Capability::parse(c))
+            .collect()
+    }
+
+    /// Fails with a descriptive error if the peer speaks a different protocol version.
+    pub fn ensure_compatible(&self, peer: &Hello, peer_name: &str) -> anyhow::Result<()> {
+        if self.protocol_version != peer.protocol_version {
+            anyhow::bail!(
+                "protocol version mismatch: we speak v{} but the {} speaks v{}; \
+                make sure the host-server and the enclave image are built from compatible commits",
+                self.protocol_version,
+                peer_name,
+                peer.protocol_version
+            );
+        }
+        Ok(())
+    }
+
+    /// The capabilities supported by both sides.
+    pub fn negotiate(&self, peer: &Hello) -> Vec<Capability> {
+        let peer_capabilities = peer.known_capabilities();
+        self.known_capabilities()
+            .into_iter()
+            .filter(|c| peer_capabilities.contains(c))
+            .collect()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
     HostToEnclave(HostToEnclaveMessage),
@@ -11,19 +106,43 @@ pub enum Message {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum HostToEnclaveMessage {
+    /// Sent in plaintext right after the `Hello`. The enclave answers with a fresh channel key
+    /// bound into an attestation document that also covers the `nonce`. The backend has to be
+    /// the same as in the `EnclaveClientArgs` that follow.
+    RequestChannelKey {
+        nonce: Vec<u8>,
+        attestation_backend: AttestationBackend,
+    },
+    /// The `attestation_nonce` goes into the NSM request of the build attestation, so that the
+    /// attestation can be tied to this job (`run_id`) and is not a replay of an older one.
     StartRunner {
-        enclave_client_args: EnclaveClientArgs,
+        enclave_client_args: Box<EnclaveClientArgs>,
+        run_id: u32,
+        attestation_nonce: Vec<u8>,
     },
     Ok {
         info: Option<String>,
     },
+    /// Stop the job: the enclave client tears down the runner and answers with `Cancelled`.
+    Cancel {
+        reason: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum EnclaveToHostMessage {
+    /// The static key of the secure channel. The attestation document is empty when the host
+    /// asked for a fake attestation.
+    ChannelKey {
+        public_key: Vec<u8>,
+        attestation_document: Vec<u8>,
+    },
     ReportRepositoryRoot {
         commit_hash: String,
+        source_tree_digest: String,
     },
+    /// Sent once per artifact. All artifacts of a build share the `ReportAttestation` that
+    /// follows them.
     ReportArtifact {
         artifact_hash: String,
         artifact_name: String,
@@ -41,6 +160,53 @@ pub enum EnclaveToHostMessage {
         marker: String,
         datetime: String,
     },
+    /// Sent before the enclave client gives up, so that the host learns why the job failed
+    /// instead of only seeing a closed stream.
+    Error {
+        stage: EnclaveStage,
+        kind: ErrorKind,
+        detail: String,
+    },
+    /// Acknowledges a `HostToEnclaveMessage::Cancel` once the runner has been torn down.
+    Cancelled,
+    /// Sent every `HEARTBEAT_INTERVAL` while the job runs.
+    Heartbeat {
+        stage: EnclaveStage,
+    },
+}
+
+/// The stage of the job in the enclave client (mirrors its internal state machine).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EnclaveStage {
+    /// Waiting for the runner arguments.
+    Initializing,
+    /// Configuring the runner.
+    Configuring,
+    /// The runner is configured and waits for the job to check out the repository.
+    WaitingForInput,
+    /// The commit hash has been measured and the build is running.
+    Building,
+    /// All artifacts have been reported and are being attested.
+    Attesting,
+    /// The state machine received an event that is not valid in its current state.
+    Failed,
+}
+
+/// The reason why the enclave client failed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// The runner (or its container) could not be set up or exited before the build finished.
+    RunnerFailed,
+    /// The sandbox ran into one of its `ResourceLimits`, e.g. the OOM killer hit the build.
+    ResourceLimitExceeded,
+    /// The runner reported events in an unexpected order, e.g. a second commit hash.
+    InvalidStateTransition,
+    /// The host sent a message that is not valid at this point.
+    UnexpectedMessage,
+    /// The attestation document could not be created.
+    AttestationFailed,
+    /// Anything else, e.g. I/O errors.
+    Internal,
 }
 
 pub fn create_new_timestamp_now(marker: &str) -> EnclaveToHostMessage {
@@ -72,4 +238,40 @@ mod tests {
         };
         assert_eq!(info, Some("foobar".to_string()));
     }
+
+    #[test]
+    fn test_hello_layout_is_stable() {
+        let hello = Hello::new(&[Capability::Log]);
+        let bytes = bincode::serialize(&hello).unwrap();
+
+        // the protocol version must always be the first four bytes
+        assert_eq!(bytes[..4], PROTOCOL_VERSION.to_le_bytes());
+        let x: Hello = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(x, hello);
+    }
+
+    #[test]
+    fn test_hello_version_mismatch() {
+        let ours = Hello::new(Capability::ALL);
+        let theirs = Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            capabilities: vec![],
+        };
+        let err = ours
+            .ensure_compatible(&theirs, "enclave-client")
+            .unwrap_err();
+        assert!(err.to_string().contains("protocol version mismatch"));
+        assert!(ours.ensure_compatible(&ours, "enclave-client").is_ok());
+    }
+
+    #[test]
+    fn test_hello_negotiate_skips_unknown_capabilities() {
+        let ours = Hello::new(Capability::ALL);
+        let theirs = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec!["timestamp".to_string(), "from_the_future".to_string()],
+        };
+        assert_eq!(ours.negotiate(&theirs), vec![Capability::Timestamp]);
+        assert_eq!(theirs.negotiate(&ours), vec![Capability::Timestamp]);
+    }
 }
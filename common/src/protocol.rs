@@ -1,36 +1,269 @@
-use crate::messages::Message;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio_vsock::VsockStream;
+use crate::messages::{Hello, Message};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+
+/// Attestation documents and logs are a few KiB at most, so this leaves plenty of headroom.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Default time a peer has to deliver a frame once it started sending it.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Limits applied when reading frames from the peer, which we treat as semi-trusted.
+#[derive(Debug, Clone)]
+pub struct ProtocolConfig {
+    /// Frames announcing a larger length are rejected before any buffer is allocated.
+    pub max_frame_size: usize,
+
+    /// How long reading the body of a frame may take once its length prefix has been received.
+    pub read_timeout: Duration,
+
+    /// How long to wait for the next frame to start. `None` waits forever, which is what we want
+    /// while long builds run without producing any output.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            idle_timeout: None,
+        }
+    }
+}
+
+/// Errors that can occur while reading from or writing to the peer. After any of them the stream
+/// should be considered unusable as we might have stopped in the middle of a frame.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The peer announced a frame larger than `ProtocolConfig::max_frame_size`.
+    FrameTooLarge {
+        len: u64,
+        max: usize,
+    },
+
+    /// The peer did not deliver (the start of) a frame in time.
+    Timeout(Duration),
+
+    /// The stream was closed, possibly in the middle of a frame.
+    UnexpectedEof,
+
+    Io(io::Error),
+
+    Decode(bincode::Error),
+
+    /// A frame of the secure channel could not be decrypted, e.g. because it was tampered with.
+    Crypto(snow::Error),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::FrameTooLarge { len, max } => {
+                write!(f, "frame of {len} bytes exceeds the maximum of {max} bytes")
+            }
+            ProtocolError::Timeout(duration) => {
+                write!(f, "no frame received within {duration:?}")
+            }
+            ProtocolError::UnexpectedEof => write!(f, "the stream was closed unexpectedly"),
+            ProtocolError::Io(e) => write!(f, "I/O error: {e}"),
+            ProtocolError::Decode(e) => write!(f, "failed to decode the message: {e}"),
+            ProtocolError::Crypto(e) => write!(f, "secure channel error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(e) => Some(e),
+            ProtocolError::Decode(e) => Some(e),
+            ProtocolError::Crypto(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            ProtocolError::UnexpectedEof
+        } else {
+            ProtocolError::Io(e)
+        }
+    }
+}
+
+impl From<bincode::Error> for ProtocolError {
+    fn from(e: bincode::Error) -> Self {
+        ProtocolError::Decode(e)
+    }
+}
+
+impl From<snow::Error> for ProtocolError {
+    fn from(e: snow::Error) -> Self {
+        ProtocolError::Crypto(e)
+    }
+}
 
 /// Read the next frame from the stream. Each frame is prefixed with its u64 length (little-endian).
-pub async fn read_next_frame(stream: &mut VsockStream) -> anyhow::Result<Vec<u8>> {
+/// The length is checked against the configured maximum before the buffer is allocated.
+pub async fn read_next_frame<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    config: &ProtocolConfig,
+) -> Result<Vec<u8>, ProtocolError> {
     let mut len_buf = [0u8; size_of::<u64>()];
-    stream.read_exact(&mut len_buf).await?;
+    with_timeout(config.idle_timeout, stream.read_exact(&mut len_buf)).await??;
 
     let len = u64::from_le_bytes(len_buf);
+    if len > config.max_frame_size as u64 {
+        return Err(ProtocolError::FrameTooLarge {
+            len,
+            max: config.max_frame_size,
+        });
+    }
+
     let mut buf = vec![0u8; len as usize];
-    stream.read_exact(&mut buf).await?;
+    with_timeout(Some(config.read_timeout), stream.read_exact(&mut buf)).await??;
 
     Ok(buf)
 }
 
 /// Write the given buffer to the stream, prefixed with its u64 length (little-endian).
 /// This is the counterpart to `read_next_frame`.
-pub async fn write_frame(stream: &mut VsockStream, buf: &[u8]) -> anyhow::Result<()> {
+pub async fn write_frame<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    buf: &[u8],
+) -> Result<(), ProtocolError> {
     let len = buf.len() as u64;
     stream.write_all(&len.to_le_bytes()).await?;
     stream.write_all(buf).await?;
     Ok(())
 }
 
-pub async fn read_next_message(stream: &mut VsockStream) -> anyhow::Result<Message> {
-    let buf = read_next_frame(stream).await?;
+pub async fn read_next_message<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    config: &ProtocolConfig,
+) -> Result<Message, ProtocolError> {
+    let buf = read_next_frame(stream, config).await?;
     let message = bincode::deserialize(&buf)?;
     Ok(message)
 }
 
-pub async fn write_message(stream: &mut VsockStream, message: &Message) -> anyhow::Result<()> {
+pub async fn write_message<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    message: &Message,
+) -> Result<(), ProtocolError> {
     let buf = bincode::serialize(message)?;
     write_frame(stream, &buf).await?;
     Ok(())
 }
+
+/// Read the `Hello` frame that opens each connection. Decoding failures are reported explicitly as
+/// they typically mean that the peer predates the versioned handshake.
+pub async fn read_hello<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    config: &ProtocolConfig,
+) -> anyhow::Result<Hello> {
+    let buf = read_next_frame(stream, config).await?;
+    bincode::deserialize(&buf).map_err(|e| {
+        anyhow::anyhow!("failed to decode the Hello frame (is the peer from an older build?): {e}")
+    })
+}
+
+pub async fn write_hello<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    hello: &Hello,
+) -> anyhow::Result<()> {
+    let buf = bincode::serialize(hello)?;
+    write_frame(stream, &buf).await?;
+    Ok(())
+}
+
+async fn with_timeout<F: Future>(
+    timeout: Option<Duration>,
+    future: F,
+) -> Result<F::Output, ProtocolError> {
+    match timeout {
+        Some(duration) => time::timeout(duration, future)
+            .await
+            .map_err(|_| ProtocolError::Timeout(duration)),
+        None => Ok(future.await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::HostToEnclaveMessage;
+
+    #[tokio::test]
+    async fn test_message_roundtrip() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        let message = Message::HostToEnclave(HostToEnclaveMessage::Ok {
+            info: Some("foobar".to_string()),
+        });
+        write_message(&mut a, &message).await.unwrap();
+
+        let received = read_next_message(&mut b, &ProtocolConfig::default())
+            .await
+            .unwrap();
+        let Message::HostToEnclave(HostToEnclaveMessage::Ok { info }) = received else {
+            panic!("unexpected message received");
+        };
+        assert_eq!(info, Some("foobar".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_frame_too_large() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        a.write_all(&u64::MAX.to_le_bytes()).await.unwrap();
+
+        let result = read_next_frame(&mut b, &ProtocolConfig::default()).await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::FrameTooLarge { len: u64::MAX, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_eof_in_frame() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        a.write_all(&10u64.to_le_bytes()).await.unwrap();
+        a.write_all(b"short").await.unwrap();
+        drop(a);
+
+        let result = read_next_frame(&mut b, &ProtocolConfig::default()).await;
+        assert!(matches!(result, Err(ProtocolError::UnexpectedEof)));
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        a.write_all(&10u64.to_le_bytes()).await.unwrap();
+
+        let config = ProtocolConfig {
+            read_timeout: Duration::from_millis(10),
+            ..ProtocolConfig::default()
+        };
+        let result = read_next_frame(&mut b, &config).await;
+        assert!(matches!(result, Err(ProtocolError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (_a, mut b) = tokio::io::duplex(1024);
+
+        let config = ProtocolConfig {
+            idle_timeout: Some(Duration::from_millis(10)),
+            ..ProtocolConfig::default()
+        };
+        let result = read_next_frame(&mut b, &config).await;
+        assert!(matches!(result, Err(ProtocolError::Timeout(_))));
+    }
+}
@@ -0,0 +1,352 @@
+use crate::claims::{Artifact, BuildEnvironment, FetchedInput};
+use anyhow::{bail, Context};
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+pub const STATEMENT_TYPE: &str = "https://in-toto.io/Statement/v1";
+pub const SLSA_PROVENANCE_PREDICATE_TYPE: &str = "https://slsa.dev/provenance/v1";
+
+/// Describes the parameters below, see https://slsa.dev/spec/v1.0/provenance#buildtype.
+pub const BUILD_TYPE: &str =
+    "https://github.com/lambdapioneer/attestable-builds/buildtypes/enclave-client/v1";
+
+/// The builder ID is this prefix with the PCRs of the enclave as query, so that a policy can pin
+/// the exact enclave image (and not just "some enclave client").
+pub const BUILDER_ID_PREFIX: &str =
+    "https://github.com/lambdapioneer/attestable-builds/enclave-client";
+
+/// An in-toto Statement (v1), see https://github.com/in-toto/attestation/blob/main/spec/v1/statement.md.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Statement {
+    #[serde(rename = "_type")]
+    pub type_: String,
+    pub subject: Vec<ResourceDescriptor>,
+    #[serde(rename = "predicateType")]
+    pub predicate_type: String,
+    pub predicate: Provenance,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ResourceDescriptor {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    /// By algorithm, e.g. `sha256` or `gitCommit`, see
+    /// https://github.com/in-toto/attestation/blob/main/spec/v1/digest_set.md.
+    pub digest: BTreeMap<String, String>,
+}
+
+/// A SLSA Provenance (v1) predicate, see https://slsa.dev/spec/v1.0/provenance.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Provenance {
+    pub build_definition: BuildDefinition,
+    pub run_details: RunDetails,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildDefinition {
+    pub build_type: String,
+    pub external_parameters: ExternalParameters,
+    pub internal_parameters: InternalParameters,
+    /// The source (first) followed by the fetched inputs.
+    pub resolved_dependencies: Vec<ResourceDescriptor>,
+}
+
+/// What the job asked for.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalParameters {
+    /// `owner/name` on GitHub.
+    pub repository: String,
+    /// `GITHUB_WORKFLOW_REF` as reported by the pre hook, e.g.
+    /// `owner/name/.github/workflows/build.yml@refs/heads/main`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workflow_ref: Option<String>,
+}
+
+/// What the enclave client measured on top of the resolved dependencies.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct InternalParameters {
+    /// See `source_tree::source_tree_digest`.
+    pub source_tree_digest: String,
+    /// Only set for hermetic builds (computed like `source_tree_digest`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hermetic_cache_digest: Option<String>,
+    /// The build environment of sandboxed builds, see `rootfs::rootfs_digest`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rootfs_digest: Option<String>,
+    /// The build environment image that the rootfs was unpacked from, if any (`sha256:<hex>`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_digest: Option<String>,
+    /// The SHA-256 of the allowlist that permitted the image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_allowlist_digest: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RunDetails {
+    pub builder: Builder,
+    pub metadata: BuildMetadata,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Builder {
+    pub id: String,
+    pub version: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildMetadata {
+    /// The GitHub run ID.
+    pub invocation_id: String,
+}
+
+/// Everything the enclave client knows about a build when it attests it.
+#[derive(Debug, Clone)]
+pub struct BuildDescription<'a> {
+    pub repository: &'a str,
+    pub workflow_ref: Option<&'a str>,
+    /// The version of the action runner (`simulated` for the simulated one).
+    pub runner_version: &'a str,
+    pub run_id: u32,
+    pub commit_hash: &'a str,
+    pub source_tree_digest: &'a str,
+    pub build_environment: &'a BuildEnvironment,
+    pub fetched_inputs: &'a [FetchedInput],
+    pub artifacts: &'a [Artifact],
+    /// PCR0-2 as reported by the attestation backend.
+    pub pcrs: [&'a [u8]; 3],
+}
+
+/// The provenance of a build as an in-toto Statement, with the artifacts as subjects.
+pub fn provenance_statement(build: &BuildDescription) -> anyhow::Result<Statement> {
+    let subject = build
+        .artifacts
+        .iter()
+        .map(|artifact| ResourceDescriptor {
+            name: Some(artifact.name.clone()),
+            uri: None,
+            digest: BTreeMap::from([("sha256".to_string(), artifact.hash.clone())]),
+        })
+        .collect();
+
+    let mut resolved_dependencies = vec![ResourceDescriptor {
+        name: None,
+        uri: Some(format!("git+https://github.com/{}", build.repository)),
+        digest: BTreeMap::from([("gitCommit".to_string(), build.commit_hash.to_string())]),
+    }];
+    for fetched_input in build.fetched_inputs {
+        resolved_dependencies.push(ResourceDescriptor {
+            name: None,
+            uri: Some(fetched_input.url.clone()),
+            digest: digest_set(&fetched_input.hash)
+                .with_context(|| format!("Unsupported hash of {}", fetched_input.url))?,
+        });
+    }
+
+    let [pcr0, pcr1, pcr2] = build.pcrs.map(hex::encode);
+    Ok(Statement {
+        type_: STATEMENT_TYPE.to_string(),
+        subject,
+        predicate_type: SLSA_PROVENANCE_PREDICATE_TYPE.to_string(),
+        predicate: Provenance {
+            build_definition: BuildDefinition {
+                build_type: BUILD_TYPE.to_string(),
+                external_parameters: ExternalParameters {
+                    repository: build.repository.to_string(),
+                    workflow_ref: build.workflow_ref.map(str::to_string),
+                },
+                internal_parameters: InternalParameters {
+                    source_tree_digest: build.source_tree_digest.to_string(),
+                    hermetic_cache_digest: build.build_environment.hermetic_cache_digest.clone(),
+                    rootfs_digest: build.build_environment.rootfs_digest.clone(),
+                    image_digest: build.build_environment.image_digest.clone(),
+                    image_allowlist_digest: build.build_environment.image_allowlist_digest.clone(),
+                },
+                resolved_dependencies,
+            },
+            run_details: RunDetails {
+                builder: Builder {
+                    id: format!(
+                        "{}?pcr0={}&pcr1={}&pcr2={}",
+                        BUILDER_ID_PREFIX, pcr0, pcr1, pcr2
+                    ),
+                    version: BTreeMap::from([(
+                        "actions-runner".to_string(),
+                        build.runner_version.to_string(),
+                    )]),
+                },
+                metadata: BuildMetadata {
+                    invocation_id: build.run_id.to_string(),
+                },
+            },
+        },
+    })
+}
+
+/// The SHA-256 (hex) over the statement exactly as it is published, so that it can be checked
+/// with `sha256sum` against the attested claims.
+pub fn provenance_digest(statement_json: &str) -> String {
+    format!("{:x}", Sha256::digest(statement_json))
+}
+
+/// Converts the hash notation of `FetchedInput` into an in-toto digest set.
+fn digest_set(hash: &str) -> anyhow::Result<BTreeMap<String, String>> {
+    if let Some(hex) = hash.strip_prefix("sha256:") {
+        return Ok(BTreeMap::from([("sha256".to_string(), hex.to_string())]));
+    }
+    if let Some(commit) = hash.strip_prefix("git:") {
+        return Ok(BTreeMap::from([(
+            "gitCommit".to_string(),
+            commit.to_string(),
+        )]));
+    }
+    if hash.starts_with("h1:") {
+        // the Go module hash, which in-toto knows as `dirHash`
+        return Ok(BTreeMap::from([("dirHash".to_string(), hash.to_string())]));
+    }
+
+    // subresource integrity (npm): `<algorithm>-<base64>`, possibly several separated by spaces
+    let mut digests = BTreeMap::new();
+    for integrity in hash.split_whitespace() {
+        let Some((algorithm @ ("sha1" | "sha256" | "sha384" | "sha512"), value)) =
+            integrity.split_once('-')
+        else {
+            bail!("{:?}", hash);
+        };
+        let value = BASE64_STANDARD.decode(value).context(hash.to_string())?;
+        digests.insert(algorithm.to_string(), hex::encode(value));
+    }
+    if digests.is_empty() {
+        bail!("{:?}", hash);
+    }
+    Ok(digests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_build<'a>(
+        build_environment: &'a BuildEnvironment,
+        fetched_inputs: &'a [FetchedInput],
+        artifacts: &'a [Artifact],
+    ) -> BuildDescription<'a> {
+        BuildDescription {
+            repository: "owner/repo",
+            workflow_ref: Some("owner/repo/.github/workflows/build.yml@refs/heads/main"),
+            runner_version: "2.328.0",
+            run_id: 42,
+            commit_hash: "0123456789abcdef0123456789abcdef01234567",
+            source_tree_digest: "tree",
+            build_environment,
+            fetched_inputs,
+            artifacts,
+            pcrs: [&[0x00; 2], &[0xab; 2], &[0xff; 2]],
+        }
+    }
+
+    #[test]
+    fn test_provenance_statement() {
+        let fetched_inputs = [
+            FetchedInput {
+                url: "https://static.crates.io/crates/app/app-1.0.0.crate".to_string(),
+                hash: "sha256:aaaa".to_string(),
+                size: None,
+            },
+            FetchedInput {
+                url: "https://registry.npmjs.org/a/-/a-1.0.0.tgz".to_string(),
+                hash: "sha512-q83v sha1-3q2+7w==".to_string(),
+                size: None,
+            },
+            FetchedInput {
+                url: "https://proxy.golang.org/golang.org/x/text/@v/v0.3.0.zip".to_string(),
+                hash: "h1:abc=".to_string(),
+                size: None,
+            },
+        ];
+        let artifacts = [Artifact {
+            name: "app".to_string(),
+            hash: "bbbb".to_string(),
+        }];
+        let build_environment = BuildEnvironment {
+            rootfs_digest: Some("rootfs".to_string()),
+            image_digest: Some(format!("sha256:{}", "c".repeat(64))),
+            ..Default::default()
+        };
+        let statement = provenance_statement(&sample_build(
+            &build_environment,
+            &fetched_inputs,
+            &artifacts,
+        ))
+        .unwrap();
+
+        let json: serde_json::Value = serde_json::to_value(&statement).unwrap();
+        assert_eq!(json["_type"], STATEMENT_TYPE);
+        assert_eq!(json["predicateType"], SLSA_PROVENANCE_PREDICATE_TYPE);
+        assert_eq!(json["subject"][0]["name"], "app");
+        assert_eq!(json["subject"][0]["digest"]["sha256"], "bbbb");
+
+        let predicate = &json["predicate"];
+        assert_eq!(
+            predicate["runDetails"]["builder"]["id"],
+            format!("{}?pcr0=0000&pcr1=abab&pcr2=ffff", BUILDER_ID_PREFIX)
+        );
+        assert_eq!(predicate["runDetails"]["metadata"]["invocationId"], "42");
+        let build_definition = &predicate["buildDefinition"];
+        assert_eq!(
+            build_definition["externalParameters"]["workflowRef"],
+            "owner/repo/.github/workflows/build.yml@refs/heads/main"
+        );
+        assert!(build_definition["internalParameters"]
+            .get("hermeticCacheDigest")
+            .is_none());
+        let internal_parameters = &build_definition["internalParameters"];
+        assert_eq!(internal_parameters["rootfsDigest"], "rootfs");
+        assert_eq!(
+            internal_parameters["imageDigest"],
+            format!("sha256:{}", "c".repeat(64))
+        );
+        assert!(internal_parameters.get("imageAllowlistDigest").is_none());
+        let dependencies = &build_definition["resolvedDependencies"];
+        assert_eq!(dependencies[0]["uri"], "git+https://github.com/owner/repo");
+        assert_eq!(
+            dependencies[0]["digest"]["gitCommit"],
+            "0123456789abcdef0123456789abcdef01234567"
+        );
+        assert_eq!(dependencies[1]["digest"]["sha256"], "aaaa");
+        assert_eq!(dependencies[2]["digest"]["sha512"], "abcdef");
+        assert_eq!(dependencies[2]["digest"]["sha1"], "deadbeef");
+        assert_eq!(dependencies[3]["digest"]["dirHash"], "h1:abc=");
+
+        // what gets published can be read back
+        let statement_json = serde_json::to_string(&statement).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Statement>(&statement_json).unwrap(),
+            statement
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_hashes() {
+        for hash in ["md5-q83v", "sha512-not base64!", "", "abc"] {
+            let fetched_inputs = [FetchedInput {
+                url: "https://example.com/a.tgz".to_string(),
+                hash: hash.to_string(),
+                size: None,
+            }];
+            let build_environment = BuildEnvironment::default();
+            let build = sample_build(&build_environment, &fetched_inputs, &[]);
+            assert!(provenance_statement(&build).is_err(), "accepted {:?}", hash);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{bail, Context};
+use sha2::{Digest, Sha256};
+use std::fs::FileType;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+/// The permission bits (including setuid, setgid and sticky) that are part of the digest.
+const PERMISSION_BITS: u32 = 0o7777;
+
+/// A deterministic digest of a root filesystem, e.g. the (read-only) lower layer of the sandbox.
+/// Unlike `source_tree::source_tree_digest` it covers everything that makes up a build
+/// environment. Every entry is encoded as `<type><mode> <uid>:<gid> <hash> <path>\0`, in the byte
+/// order of the (`/` separated, relative) paths, and the digest is the SHA-256 (hex) over all
+/// entries:
+///
+/// - the type is one of `d` (directory), `f` (file), `l` (symlink), `c`/`b` (character and block
+///   devices), `p` (FIFO) and `s` (socket), the mode the permission bits in octal,
+/// - files hash to the SHA-256 of their content and symlinks to the SHA-256 of their target (they
+///   are not followed), devices to their device number and everything else to `-`.
+///
+/// Timestamps are left out. Extracting the same `rootfs.tar` as root yields the same digest.
+pub fn rootfs_digest(root: &Path) -> anyhow::Result<String> {
+    let mut entries = vec![];
+    collect_entries(root, &[], &mut entries)
+        .with_context(|| format!("Failed to measure the rootfs in {:?}", root))?;
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut hasher = Sha256::new();
+    for (_, entry) in entries {
+        hasher.update(entry);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The entries by their relative path.
+type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn collect_entries(dir: &Path, prefix: &[u8], entries: &mut Entries) -> anyhow::Result<()> {
+    for dir_entry in std::fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let mut relative_path = prefix.to_vec();
+        if !relative_path.is_empty() {
+            relative_path.push(b'/');
+        }
+        relative_path.extend_from_slice(dir_entry.file_name().as_bytes());
+
+        let path = dir_entry.path();
+        let metadata = std::fs::symlink_metadata(&path)?;
+        let file_type = metadata.file_type();
+        let hash = hash_entry(&path, file_type, metadata.rdev())?;
+        let mut entry = format!(
+            "{}{:04o} {}:{} {} ",
+            type_char(file_type)?,
+            metadata.mode() & PERMISSION_BITS,
+            metadata.uid(),
+            metadata.gid(),
+            hash
+        )
+        .into_bytes();
+        entry.extend_from_slice(&relative_path);
+        entry.push(0);
+        entries.push((relative_path.clone(), entry));
+
+        if file_type.is_dir() {
+            collect_entries(&path, &relative_path, entries)?;
+        }
+    }
+    Ok(())
+}
+
+fn type_char(file_type: FileType) -> anyhow::Result<char> {
+    Ok(if file_type.is_dir() {
+        'd'
+    } else if file_type.is_file() {
+        'f'
+    } else if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_block_device() {
+        'b'
+    } else if file_type.is_fifo() {
+        'p'
+    } else if file_type.is_socket() {
+        's'
+    } else {
+        bail!("Unknown file type {:?}", file_type)
+    })
+}
+
+fn hash_entry(path: &Path, file_type: FileType, rdev: u64) -> anyhow::Result<String> {
+    if file_type.is_symlink() {
+        let target = std::fs::read_link(path)?;
+        return Ok(format!(
+            "{:x}",
+            Sha256::digest(target.as_os_str().as_bytes())
+        ));
+    }
+    if file_type.is_char_device() || file_type.is_block_device() {
+        return Ok(rdev.to_string());
+    }
+    if !file_type.is_file() {
+        return Ok("-".to_string());
+    }
+
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    fn temp_rootfs() -> tempfile::TempDir {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::create_dir_all(dir.join("usr/bin")).unwrap();
+        std::fs::create_dir_all(dir.join("tmp")).unwrap();
+        std::fs::write(dir.join("usr/bin/cc"), "compiler").unwrap();
+        symlink("usr/bin", dir.join("bin")).unwrap();
+        temp
+    }
+
+    #[test]
+    fn test_digest_covers_the_build_environment() {
+        let temp = temp_rootfs();
+        let root = temp.path();
+        let mut digests = vec![rootfs_digest(root).unwrap()];
+        assert_eq!(rootfs_digest(root).unwrap(), digests[0]);
+
+        // unlike in source trees, empty directories and all permission bits matter
+        std::fs::create_dir_all(root.join("opt")).unwrap();
+        digests.push(rootfs_digest(root).unwrap());
+
+        std::fs::set_permissions(root.join("tmp"), std::fs::Permissions::from_mode(0o1777))
+            .unwrap();
+        digests.push(rootfs_digest(root).unwrap());
+
+        std::fs::set_permissions(
+            root.join("usr/bin/cc"),
+            std::fs::Permissions::from_mode(0o4755),
+        )
+        .unwrap();
+        digests.push(rootfs_digest(root).unwrap());
+
+        std::fs::write(root.join("usr/bin/cc"), "evil compiler").unwrap();
+        digests.push(rootfs_digest(root).unwrap());
+
+        std::fs::remove_file(root.join("bin")).unwrap();
+        symlink("opt", root.join("bin")).unwrap();
+        digests.push(rootfs_digest(root).unwrap());
+
+        // .git directories are nothing special here
+        std::fs::create_dir_all(root.join("srv/.git")).unwrap();
+        digests.push(rootfs_digest(root).unwrap());
+
+        let mut unique = digests.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), digests.len());
+    }
+}
@@ -0,0 +1,364 @@
+use crate::messages::Message;
+use crate::protocol::{read_next_frame, write_frame, ProtocolConfig, ProtocolError};
+use snow::{Builder, Keypair, StatelessTransportState};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+
+/// The enclave's static key is known to the host in advance (it is bound into the attestation
+/// document), while the host stays anonymous. Hence, we use the NK pattern with the host as the
+/// initiator and the enclave as the responder.
+pub const NOISE_PARAMS: &str = "Noise_NK_25519_ChaChaPoly_SHA256";
+
+const MAX_NOISE_MESSAGE_LEN: usize = 65535;
+const NOISE_TAG_LEN: usize = 16;
+const MAX_CHUNK_LEN: usize = MAX_NOISE_MESSAGE_LEN - NOISE_TAG_LEN;
+const MESSAGE_RECEIVER_BUFFER_SIZE: usize = 32;
+
+/// Generate a fresh static key pair for the enclave side of the channel.
+pub fn generate_keypair() -> anyhow::Result<Keypair> {
+    Ok(Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?)
+}
+
+/// Run the handshake as the host. The `prologue` must be the same on both sides and binds the
+/// session to the attestation exchange that preceded it (e.g. the attestation nonce).
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    enclave_public_key: &[u8],
+    prologue: &[u8],
+    config: &ProtocolConfig,
+) -> anyhow::Result<SecureChannel<S>> {
+    let mut handshake = Builder::new(NOISE_PARAMS.parse()?)
+        .remote_public_key(enclave_public_key)
+        .prologue(prologue)
+        .build_initiator()?;
+    let mut buf = vec![0u8; MAX_NOISE_MESSAGE_LEN];
+
+    // -> e, es
+    let len = handshake.write_message(&[], &mut buf)?;
+    write_frame(&mut stream, &buf[..len]).await?;
+
+    // <- e, ee
+    let frame = read_next_frame(&mut stream, config).await?;
+    handshake.read_message(&frame, &mut buf)?;
+
+    let transport = handshake.into_stateless_transport_mode()?;
+    Ok(SecureChannel::new(stream, transport, config.clone()))
+}
+
+/// Run the handshake as the enclave using the key pair whose public key has been attested.
+pub async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    keypair: &Keypair,
+    prologue: &[u8],
+    config: &ProtocolConfig,
+) -> anyhow::Result<SecureChannel<S>> {
+    let mut handshake = Builder::new(NOISE_PARAMS.parse()?)
+        .local_private_key(&keypair.private)
+        .prologue(prologue)
+        .build_responder()?;
+    let mut buf = vec![0u8; MAX_NOISE_MESSAGE_LEN];
+
+    // -> e, es
+    let frame = read_next_frame(&mut stream, config).await?;
+    handshake.read_message(&frame, &mut buf)?;
+
+    // <- e, ee
+    let len = handshake.write_message(&[], &mut buf)?;
+    write_frame(&mut stream, &buf[..len]).await?;
+
+    let transport = handshake.into_stateless_transport_mode()?;
+    Ok(SecureChannel::new(stream, transport, config.clone()))
+}
+
+/// An established channel that encrypts every `Message` before it is framed. Messages larger
+/// than a single Noise message are split into chunks of `MAX_NOISE_MESSAGE_LEN` ciphertext bytes
+/// (the last one may be shorter), which are sent together as one frame.
+pub struct SecureChannel<S> {
+    stream: S,
+    transport: Arc<StatelessTransportState>,
+    send_nonce: u64,
+    receive_nonce: u64,
+    config: ProtocolConfig,
+}
+
+impl<S> SecureChannel<S> {
+    fn new(stream: S, transport: StatelessTransportState, config: ProtocolConfig) -> Self {
+        Self {
+            stream,
+            transport: Arc::new(transport),
+            send_nonce: 0,
+            receive_nonce: 0,
+            config,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> SecureChannel<S> {
+    pub async fn write_message(&mut self, message: &Message) -> Result<(), ProtocolError> {
+        write_encrypted(
+            &mut self.stream,
+            &self.transport,
+            &mut self.send_nonce,
+            message,
+        )
+        .await
+    }
+}
+
+impl<S: AsyncRead + Unpin> SecureChannel<S> {
+    pub async fn read_next_message(&mut self) -> Result<Message, ProtocolError> {
+        read_encrypted(
+            &mut self.stream,
+            &self.transport,
+            &mut self.receive_nonce,
+            &self.config,
+        )
+        .await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> SecureChannel<S> {
+    /// Split the channel so that messages can be read and written from different tasks.
+    pub fn split(self) -> (SecureReadHalf<ReadHalf<S>>, SecureWriteHalf<WriteHalf<S>>) {
+        let (read_half, write_half) = tokio::io::split(self.stream);
+        let reader = SecureReadHalf {
+            stream: read_half,
+            transport: self.transport.clone(),
+            nonce: self.receive_nonce,
+            config: self.config,
+        };
+        let writer = SecureWriteHalf {
+            stream: write_half,
+            transport: self.transport,
+            nonce: self.send_nonce,
+        };
+        (reader, writer)
+    }
+}
+
+pub struct SecureReadHalf<S> {
+    stream: S,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+    config: ProtocolConfig,
+}
+
+impl<S: AsyncRead + Unpin> SecureReadHalf<S> {
+    pub async fn read_next_message(&mut self) -> Result<Message, ProtocolError> {
+        read_encrypted(
+            &mut self.stream,
+            &self.transport,
+            &mut self.nonce,
+            &self.config,
+        )
+        .await
+    }
+}
+
+impl<S: AsyncRead + Unpin + Send + 'static> SecureReadHalf<S> {
+    /// Read messages in a background task. Reading a frame is not cancellation safe, so this is
+    /// what allows waiting for the next message alongside other events in `tokio::select!`. The
+    /// task forwards the first error and then stops.
+    pub fn into_message_receiver(mut self) -> mpsc::Receiver<Result<Message, ProtocolError>> {
+        let (tx, rx) = mpsc::channel(MESSAGE_RECEIVER_BUFFER_SIZE);
+        tokio::spawn(async move {
+            loop {
+                let result = self.read_next_message().await;
+                let failed = result.is_err();
+                if tx.send(result).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        rx
+    }
+}
+
+pub struct SecureWriteHalf<S> {
+    stream: S,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl<S: AsyncWrite + Unpin> SecureWriteHalf<S> {
+    pub async fn write_message(&mut self, message: &Message) -> Result<(), ProtocolError> {
+        write_encrypted(&mut self.stream, &self.transport, &mut self.nonce, message).await
+    }
+}
+
+async fn write_encrypted<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    transport: &StatelessTransportState,
+    nonce: &mut u64,
+    message: &Message,
+) -> Result<(), ProtocolError> {
+    let plaintext = bincode::serialize(message)?;
+    let ciphertext = encrypt(transport, nonce, &plaintext)?;
+    write_frame(stream, &ciphertext).await
+}
+
+async fn read_encrypted<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    transport: &StatelessTransportState,
+    nonce: &mut u64,
+    config: &ProtocolConfig,
+) -> Result<Message, ProtocolError> {
+    let ciphertext = read_next_frame(stream, config).await?;
+    let plaintext = decrypt(transport, nonce, &ciphertext)?;
+    Ok(bincode::deserialize(&plaintext)?)
+}
+
+fn encrypt(
+    transport: &StatelessTransportState,
+    nonce: &mut u64,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, ProtocolError> {
+    let mut ciphertext = Vec::with_capacity(plaintext.len() + NOISE_TAG_LEN);
+    let mut buf = vec![0u8; MAX_NOISE_MESSAGE_LEN];
+
+    // an empty message still yields one (empty) chunk
+    let mut chunks: Vec<&[u8]> = plaintext.chunks(MAX_CHUNK_LEN).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+
+    for chunk in chunks {
+        let len = transport.write_message(*nonce, chunk, &mut buf)?;
+        *nonce += 1;
+        ciphertext.extend_from_slice(&buf[..len]);
+    }
+    Ok(ciphertext)
+}
+
+fn decrypt(
+    transport: &StatelessTransportState,
+    nonce: &mut u64,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, ProtocolError> {
+    let mut plaintext = Vec::with_capacity(ciphertext.len());
+    let mut buf = vec![0u8; MAX_NOISE_MESSAGE_LEN];
+
+    for chunk in ciphertext.chunks(MAX_NOISE_MESSAGE_LEN) {
+        let len = transport.read_message(*nonce, chunk, &mut buf)?;
+        *nonce += 1;
+        plaintext.extend_from_slice(&buf[..len]);
+    }
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{EnclaveToHostMessage, HostToEnclaveMessage};
+
+    async fn connected_pair() -> (
+        SecureChannel<tokio::io::DuplexStream>,
+        SecureChannel<tokio::io::DuplexStream>,
+    ) {
+        let (host_stream, enclave_stream) = tokio::io::duplex(1 << 20);
+        let keypair = generate_keypair().unwrap();
+        let public_key = keypair.public.clone();
+        let config = ProtocolConfig::default();
+
+        let enclave_config = config.clone();
+        let enclave = tokio::spawn(async move {
+            respond(enclave_stream, &keypair, b"prologue", &enclave_config)
+                .await
+                .unwrap()
+        });
+        let host = initiate(host_stream, &public_key, b"prologue", &config)
+            .await
+            .unwrap();
+        (host, enclave.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_small_and_large_messages() {
+        let (mut host, mut enclave) = connected_pair().await;
+
+        let message = Message::HostToEnclave(HostToEnclaveMessage::Ok {
+            info: Some("secret".to_string()),
+        });
+        host.write_message(&message).await.unwrap();
+        let Message::HostToEnclave(HostToEnclaveMessage::Ok { info }) =
+            enclave.read_next_message().await.unwrap()
+        else {
+            panic!("unexpected message received");
+        };
+        assert_eq!(info, Some("secret".to_string()));
+
+        // spans several noise messages
+        let large = "x".repeat(3 * MAX_NOISE_MESSAGE_LEN);
+        let message = Message::EnclaveToHost(EnclaveToHostMessage::Log {
+            message: large.clone(),
+        });
+        enclave.write_message(&message).await.unwrap();
+        let Message::EnclaveToHost(EnclaveToHostMessage::Log { message }) =
+            host.read_next_message().await.unwrap()
+        else {
+            panic!("unexpected message received");
+        };
+        assert_eq!(message, large);
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_is_not_on_the_wire() {
+        let (mut host, _enclave) = connected_pair().await;
+        let message = Message::HostToEnclave(HostToEnclaveMessage::Ok {
+            info: Some("github_pat_secret".to_string()),
+        });
+        let plaintext = bincode::serialize(&message).unwrap();
+        let ciphertext = encrypt(&host.transport, &mut host.send_nonce, &plaintext).unwrap();
+
+        let needle = b"github_pat_secret";
+        assert!(!ciphertext.windows(needle.len()).any(|w| w == needle));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_fails_with_wrong_key() {
+        let (host_stream, enclave_stream) = tokio::io::duplex(1 << 20);
+        let keypair = generate_keypair().unwrap();
+        let other_keypair = generate_keypair().unwrap();
+        let config = ProtocolConfig::default();
+
+        let enclave_config = config.clone();
+        let enclave = tokio::spawn(async move {
+            respond(enclave_stream, &keypair, b"prologue", &enclave_config).await
+        });
+        let host = initiate(host_stream, &other_keypair.public, b"prologue", &config).await;
+
+        // the enclave cannot decrypt the first handshake message and drops the stream
+        assert!(enclave.await.unwrap().is_err());
+        assert!(host.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_split_channel_keeps_nonces_in_sync() {
+        let (mut host, enclave) = connected_pair().await;
+        let ok = || Message::EnclaveToHost(EnclaveToHostMessage::Ok { info: None });
+
+        // use the channel before splitting it, so that the nonces are no longer zero
+        host.write_message(&Message::HostToEnclave(HostToEnclaveMessage::Ok {
+            info: None,
+        }))
+        .await
+        .unwrap();
+        let (reader, mut writer) = enclave.split();
+        let mut messages = reader.into_message_receiver();
+        assert!(messages.recv().await.unwrap().is_ok());
+
+        writer.write_message(&ok()).await.unwrap();
+        writer.write_message(&ok()).await.unwrap();
+        assert!(host.read_next_message().await.is_ok());
+        assert!(host.read_next_message().await.is_ok());
+
+        // the receiver forwards the error once the host is gone
+        drop(host);
+        assert!(matches!(
+            messages.recv().await.unwrap(),
+            Err(ProtocolError::UnexpectedEof)
+        ));
+        assert!(messages.recv().await.is_none());
+    }
+}
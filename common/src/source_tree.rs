@@ -0,0 +1,189 @@
+use crate::git;
+use anyhow::{bail, Context};
+use sha2::{Digest, Sha256};
+use std::fs::FileType;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// The modes as git records them. Other permission bits depend on the umask of whoever checked
+/// out the repository, so they are not part of the digest.
+const MODE_FILE: &str = "100644";
+const MODE_EXECUTABLE: &str = "100755";
+const MODE_SYMLINK: &str = "120000";
+const MODE_SUBMODULE: &str = "160000";
+
+/// A deterministic digest of a checked out source tree, including uncommitted modifications and
+/// untracked files. Every entry is encoded as `<mode> <hash> <path>\0`, in the byte order of the
+/// (`/` separated, relative) paths, and the digest is the SHA-256 (hex) over all entries:
+///
+/// - files: `100644` or `100755` and the SHA-256 of their content,
+/// - symlinks: `120000` and the SHA-256 of their target (they are not followed),
+/// - submodules (directories with a `.git`): `160000` and their checked out commit, followed by
+///   their files like any others.
+///
+/// `.git` itself is skipped, as are empty directories. A fresh `git clone --recurse-submodules`
+/// of the same commit therefore yields the same digest as the pristine checkout.
+pub fn source_tree_digest(root: &Path) -> anyhow::Result<String> {
+    let mut entries = vec![];
+    collect_entries(root, &[], &mut entries)
+        .with_context(|| format!("Failed to measure the source tree in {:?}", root))?;
+    // a submodule's own entry sorts before its files
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut hasher = Sha256::new();
+    for (_, entry) in entries {
+        hasher.update(entry);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The entries by their relative path.
+type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn collect_entries(dir: &Path, prefix: &[u8], entries: &mut Entries) -> anyhow::Result<()> {
+    for dir_entry in std::fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name();
+        if name == ".git" {
+            continue;
+        }
+        let mut relative_path = prefix.to_vec();
+        if !relative_path.is_empty() {
+            relative_path.push(b'/');
+        }
+        relative_path.extend_from_slice(name.as_bytes());
+
+        let path = dir_entry.path();
+        let file_type = dir_entry.file_type()?;
+        if file_type.is_dir() {
+            if std::fs::symlink_metadata(path.join(".git")).is_ok() {
+                let commit_hash = git::read_head_commit(&path)?;
+                let submodule_entry = entry(MODE_SUBMODULE, &commit_hash, &relative_path);
+                entries.push((relative_path.clone(), submodule_entry));
+            }
+            collect_entries(&path, &relative_path, entries)?;
+        } else {
+            let (mode, hash) = hash_file(&path, file_type)?;
+            let file_entry = entry(mode, &hash, &relative_path);
+            entries.push((relative_path, file_entry));
+        }
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path, file_type: FileType) -> anyhow::Result<(&'static str, String)> {
+    if file_type.is_symlink() {
+        let target = std::fs::read_link(path)?;
+        let hash = format!("{:x}", Sha256::digest(target.as_os_str().as_bytes()));
+        return Ok((MODE_SYMLINK, hash));
+    }
+    if !file_type.is_file() {
+        bail!("{:?} is neither a file, a directory nor a symlink", path);
+    }
+
+    let mut file = std::fs::File::open(path)?;
+    let mode = if file.metadata()?.permissions().mode() & 0o111 != 0 {
+        MODE_EXECUTABLE
+    } else {
+        MODE_FILE
+    };
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok((mode, format!("{:x}", hasher.finalize())))
+}
+
+fn entry(mode: &str, hash: &str, relative_path: &[u8]) -> Vec<u8> {
+    let mut entry = format!("{} {} ", mode, hash).into_bytes();
+    entry.extend_from_slice(relative_path);
+    entry.push(0);
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn temp_tree() -> tempfile::TempDir {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::write(dir.join(".git/HEAD"), COMMIT).unwrap();
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}").unwrap();
+        std::fs::write(dir.join("README.md"), "readme").unwrap();
+        temp
+    }
+
+    #[test]
+    fn test_digest_is_deterministic_and_ignores_git_metadata() {
+        let (temp_a, temp_b) = (temp_tree(), temp_tree());
+        let (a, b) = (temp_a.path(), temp_b.path());
+        // created in a different order, with unrelated permissions and git metadata
+        std::fs::write(b.join(".git/index"), "index").unwrap();
+        std::fs::create_dir_all(b.join("empty")).unwrap();
+        std::fs::set_permissions(b.join("README.md"), std::fs::Permissions::from_mode(0o600))
+            .unwrap();
+        assert_eq!(
+            source_tree_digest(a).unwrap(),
+            source_tree_digest(b).unwrap()
+        );
+
+        let expected = {
+            let mut hasher = Sha256::new();
+            hasher.update(entry(
+                MODE_FILE,
+                &format!("{:x}", Sha256::digest("readme")),
+                b"README.md",
+            ));
+            hasher.update(entry(
+                MODE_FILE,
+                &format!("{:x}", Sha256::digest("fn main() {}")),
+                b"src/main.rs",
+            ));
+            format!("{:x}", hasher.finalize())
+        };
+        assert_eq!(source_tree_digest(a).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_digest_covers_modifications_modes_symlinks_and_submodules() {
+        let temp = temp_tree();
+        let root = temp.path();
+        let mut digests = vec![source_tree_digest(root).unwrap()];
+
+        std::fs::write(root.join("src/main.rs"), "fn main() { evil() }").unwrap();
+        digests.push(source_tree_digest(root).unwrap());
+
+        std::fs::set_permissions(
+            root.join("README.md"),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        digests.push(source_tree_digest(root).unwrap());
+
+        symlink("README.md", root.join("link")).unwrap();
+        digests.push(source_tree_digest(root).unwrap());
+
+        std::fs::create_dir_all(root.join("vendor/lib/.git")).unwrap();
+        std::fs::write(root.join("vendor/lib/.git/HEAD"), COMMIT).unwrap();
+        std::fs::write(root.join("vendor/lib/lib.rs"), "").unwrap();
+        digests.push(source_tree_digest(root).unwrap());
+        std::fs::write(
+            root.join("vendor/lib/.git/HEAD"),
+            "fedcba9876543210fedcba9876543210fedcba98",
+        )
+        .unwrap();
+        digests.push(source_tree_digest(root).unwrap());
+        std::fs::write(root.join("vendor/lib/lib.rs"), "modified").unwrap();
+        digests.push(source_tree_digest(root).unwrap());
+
+        let mut unique = digests.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), digests.len());
+    }
+}
@@ -0,0 +1,141 @@
+use crate::parse_vsock_addr;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fmt::{Debug, Display, Formatter};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_vsock::{VsockAddr, VsockListener, VsockStream};
+
+/// The transport used between the host-server and the enclave-client. Nitro enclaves are only
+/// reachable via vsock, but for local runs we can also use Unix sockets or TCP, e.g. on machines
+/// without the `vsock_loopback` kernel module.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[clap(rename_all = "snake_case")]
+pub enum Transport {
+    Vsock,
+    Unix,
+    Tcp,
+}
+
+/// An address for one of the supported transports.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransportAddr {
+    Vsock(VsockAddr),
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl TransportAddr {
+    /// Parse an address for the given transport. The formats are `CID:PORT` for vsock, a file
+    /// path for Unix sockets, and `IP:PORT` for TCP. This is the inverse of `Display`.
+    pub fn parse(transport: Transport, s: &str) -> anyhow::Result<TransportAddr> {
+        Ok(match transport {
+            Transport::Vsock => TransportAddr::Vsock(parse_vsock_addr(s.to_string())?),
+            Transport::Unix => TransportAddr::Unix(PathBuf::from(s)),
+            Transport::Tcp => TransportAddr::Tcp(s.parse()?),
+        })
+    }
+
+    pub fn transport(&self) -> Transport {
+        match self {
+            TransportAddr::Vsock(_) => Transport::Vsock,
+            TransportAddr::Unix(_) => Transport::Unix,
+            TransportAddr::Tcp(_) => Transport::Tcp,
+        }
+    }
+}
+
+impl Display for TransportAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportAddr::Vsock(addr) => write!(f, "{}:{}", addr.cid(), addr.port()),
+            TransportAddr::Unix(path) => write!(f, "{}", path.display()),
+            TransportAddr::Tcp(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// Any bidirectional byte stream that the protocol can run over.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Debug> Stream for T {}
+
+pub type BoxedStream = Box<dyn Stream>;
+
+/// Connect to a listening peer.
+pub async fn connect(addr: &TransportAddr) -> std::io::Result<BoxedStream> {
+    Ok(match addr {
+        TransportAddr::Vsock(addr) => Box::new(VsockStream::connect(*addr).await?),
+        TransportAddr::Unix(path) => Box::new(UnixStream::connect(path).await?),
+        TransportAddr::Tcp(addr) => Box::new(TcpStream::connect(addr).await?),
+    })
+}
+
+/// Listen on the given address and return the first connection that comes in.
+pub async fn accept_one(addr: &TransportAddr) -> anyhow::Result<BoxedStream> {
+    Ok(match addr {
+        TransportAddr::Vsock(addr) => {
+            let mut listener = VsockListener::bind(*addr)?;
+            Box::new(listener.accept().await?.0)
+        }
+        TransportAddr::Unix(path) => {
+            // a stale socket file from a previous run would make the bind fail
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            Box::new(listener.accept().await?.0)
+        }
+        TransportAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            Box::new(listener.accept().await?.0)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_transport_addr_roundtrip() {
+        for (transport, s) in [
+            (Transport::Vsock, "1:10042"),
+            (Transport::Unix, "/tmp/enclave-client.sock"),
+            (Transport::Tcp, "127.0.0.1:10042"),
+        ] {
+            let addr = TransportAddr::parse(transport, s).unwrap();
+            assert_eq!(addr.transport(), transport);
+            assert_eq!(addr.to_string(), s);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_and_accept_unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = TransportAddr::Unix(dir.path().join("transport.sock"));
+
+        let listener_addr = addr.clone();
+        let accept_task = tokio::spawn(async move {
+            let mut stream = accept_one(&listener_addr).await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+
+        // the listener might not be up yet
+        let mut stream = loop {
+            if let Ok(stream) = connect(&addr).await {
+                break stream;
+            }
+            crate::short_wait().await;
+        };
+        stream.write_all(b"hello").await.unwrap();
+
+        assert_eq!(&accept_task.await.unwrap(), b"hello");
+    }
+}
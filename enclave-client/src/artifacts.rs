@@ -0,0 +1,104 @@
+use anyhow::{bail, Context};
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Hashes an artifact that the attestation hook placed in the artifacts directory. The hook only
+/// names the file, so the attested hash covers the bytes that we read here and not whatever a
+/// build step claims. Paths that lead outside the directory (e.g. through symlinks) are rejected.
+pub(crate) fn hash_artifact(artifacts_dir: &Path, path: &str) -> anyhow::Result<String> {
+    let artifacts_dir = artifacts_dir.canonicalize().with_context(|| {
+        format!(
+            "Failed to resolve the artifacts directory {:?}",
+            artifacts_dir
+        )
+    })?;
+    let artifact_path = artifacts_dir
+        .join(path)
+        .canonicalize()
+        .with_context(|| format!("Artifact {:?} does not exist", path))?;
+    if !artifact_path.starts_with(&artifacts_dir) {
+        bail!("Artifact {:?} is outside of the artifacts directory", path);
+    }
+
+    // a FIFO would block us forever, and the path could have been replaced since we resolved it
+    let mut file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(&artifact_path)
+        .with_context(|| format!("Failed to open artifact {:?}", path))?;
+    let opened_path = std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))?;
+    if !opened_path.starts_with(&artifacts_dir) {
+        bail!(
+            "Artifact {:?} was moved outside of the artifacts directory",
+            path
+        );
+    }
+    if !file.metadata()?.is_file() {
+        bail!("Artifact {:?} is not a regular file", path);
+    }
+
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read artifact {:?}", path))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn temp_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("artifacts/nested")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_hash_artifact() {
+        let temp = temp_dir();
+        let dir = temp.path();
+        let artifacts_dir = dir.join("artifacts");
+        std::fs::write(artifacts_dir.join("app"), "test").unwrap();
+        std::fs::write(artifacts_dir.join("nested/app"), "").unwrap();
+
+        assert_eq!(
+            hash_artifact(&artifacts_dir, "app").unwrap(),
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+        );
+        assert_eq!(
+            hash_artifact(&artifacts_dir, "nested/app").unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        // symlinks within the directory are fine
+        symlink(artifacts_dir.join("app"), artifacts_dir.join("link")).unwrap();
+        assert_eq!(
+            hash_artifact(&artifacts_dir, "link").unwrap(),
+            hash_artifact(&artifacts_dir, "app").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rejects_artifacts_outside_of_the_directory() {
+        let temp = temp_dir();
+        let dir = temp.path();
+        let artifacts_dir = dir.join("artifacts");
+        std::fs::write(dir.join("secret"), "secret").unwrap();
+        symlink(dir.join("secret"), artifacts_dir.join("file-link")).unwrap();
+        symlink(dir, artifacts_dir.join("dir-link")).unwrap();
+
+        for path in ["../secret", "file-link", "dir-link/secret", "/etc/hostname"] {
+            assert!(
+                hash_artifact(&artifacts_dir, path).is_err(),
+                "accepted {}",
+                path
+            );
+        }
+        // neither missing files nor directories are artifacts
+        assert!(hash_artifact(&artifacts_dir, "missing").is_err());
+        assert!(hash_artifact(&artifacts_dir, "nested").is_err());
+    }
+}
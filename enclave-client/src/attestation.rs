@@ -1,92 +1,452 @@
-use anyhow::Ok;
+use crate::nsm_emulator::NsmEmulator;
+use anyhow::Context;
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
+use common::attestation_document::{AttestationDocument, ATTESTATION_DOCUMENT_SCHEMA_VERSION};
+use common::claims::{Artifact, AttestationClaims, BuildEnvironment, FetchedInput};
+use common::provenance::{self, BuildDescription};
+use common::AttestationBackend;
 use nsm_io::{Request, Response};
 use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
 use tracing::{debug, warn};
 
-pub async fn perform_attestation(
-    use_fake_attestation: bool,
-    commit_hash: String,
-    artifact_name: String,
-    artifact_hash: String,
-) -> anyhow::Result<String> {
-    if use_fake_attestation {
-        perform_fake_attestation(commit_hash, artifact_name, artifact_hash).await
-    } else {
-        nitro_attestation(commit_hash, artifact_name, artifact_hash).await
+/// The PCRs that every backend has to report, as they are listed in the attestation document.
+const REPORTED_PCRS: [usize; 3] = [0, 1, 2];
+
+/// The length of the placeholder PCRs without attestation (the same as SHA-384 PCRs).
+const PLACEHOLDER_PCR_LEN: usize = 48;
+
+/// The measurements of the code running in the TEE by PCR index. Backends without numbered
+/// registers map their measurements onto PCR0-2.
+pub type Measurements = BTreeMap<usize, Vec<u8>>;
+
+/// What the enclave client measured right after the checkout, before any build step ran.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeasuredCheckout {
+    pub commit_hash: String,
+    pub source_tree_digest: String,
+    /// The sandbox's rootfs (measured before the container started) and, for hermetic builds,
+    /// the dependency cache, see `common::claims::BuildEnvironment`.
+    pub build_environment: BuildEnvironment,
+    pub fetched_inputs: Vec<FetchedInput>,
+    /// As reported by the pre hook together with the checkout, see `HookEvent::CheckoutComplete`.
+    pub workflow_ref: Option<String>,
+}
+
+/// The job as requested by the host, which goes into the provenance of the build.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildJob {
+    pub repository: String,
+    /// The version of the action runner (`simulated` for the simulated one).
+    pub runner_version: String,
+    pub run_id: u32,
+}
+
+/// A source of evidence for the attestations of the enclave client. The rest of the client only
+/// deals with the measurements and the raw (signed) evidence, so that other TEE types can be
+/// added next to Nitro without touching the build pipeline.
+pub trait AttestationProvider: Send + Sync {
+    fn backend(&self) -> AttestationBackend;
+
+    /// The current measurements, including at least PCR0-2.
+    fn measurements(&self) -> anyhow::Result<Measurements>;
+
+    /// Signed evidence that covers the given data and the measurements.
+    fn attest(
+        &self,
+        user_data: Option<&[u8]>,
+        nonce: Option<&[u8]>,
+        public_key: Option<&[u8]>,
+    ) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Creates the provider for the backend the host asked for. The emulator is only set up when the
+/// enclave client was started with `--nsm-emulator-dir`.
+pub fn new_provider(
+    backend: AttestationBackend,
+    emulator: Option<NsmEmulator>,
+) -> anyhow::Result<Box<dyn AttestationProvider>> {
+    match backend {
+        AttestationBackend::Nitro => Ok(Box::new(NitroAttestation)),
+        AttestationBackend::Emulated => {
+            let emulator = emulator.context(
+                "The host asked for emulated attestations, but the NSM emulator is not set up",
+            )?;
+            Ok(Box::new(EmulatedAttestation { emulator }))
+        }
+        AttestationBackend::None => Ok(Box::new(NoAttestation)),
     }
 }
 
-async fn nitro_attestation(
-    commit_hash: String,
-    artifact_name: String,
-    artifact_hash: String,
+/// Attest all artifacts of a build at once. The resulting document lists the artifacts, so that
+/// it can be shared by the log entries of all of them, and carries the SLSA provenance of the
+/// build. The host's nonce ties the attestation to this job (an empty nonce is left out).
+pub async fn perform_attestation(
+    provider: &dyn AttestationProvider,
+    checkout: &MeasuredCheckout,
+    artifacts: &[Artifact],
+    job: &BuildJob,
+    nonce: &[u8],
 ) -> anyhow::Result<String> {
-    let nsm_fd = nsm_driver::nsm_init();
-
-    let user_data = ByteBuf::from(format!(
-        "commit_hash={},artifact_name={},artifact_hash={}",
-        commit_hash, artifact_name, artifact_hash
-    ));
+    if provider.backend() == AttestationBackend::None {
+        warn!("Creating a fake attestation document");
+    }
 
-    // get pcr0-2 (also included in the attestation itself)
-    let pcrs = vec![0, 1, 2];
+    // the PCRs are also included in the attestation itself, and identify the builder
+    let measurements = provider.measurements()?;
     let mut pcr_values = vec![];
-    for pcr in pcrs {
-        let pcr_response = {
-            let request = Request::DescribePCR { index: pcr };
-            nsm_driver::nsm_process_request(nsm_fd, request)
-        };
-        let pcr_value = match pcr_response {
-            Response::DescribePCR { lock: _, data } => data,
-            _ => anyhow::bail!("Failed to get pcr{}", pcr),
-        };
-        debug!("pcr{}={}", pcr, BASE64_STANDARD.encode(&pcr_value));
-        pcr_values.push(pcr_value);
+    for index in REPORTED_PCRS {
+        let value = measurements.get(&index).with_context(|| {
+            format!(
+                "The {:?} attestation backend reports no PCR{}",
+                provider.backend(),
+                index
+            )
+        })?;
+        debug!("pcr{}={}", index, BASE64_STANDARD.encode(value));
+        pcr_values.push(value.as_slice());
     }
+    let pcrs: Vec<String> = pcr_values
+        .iter()
+        .map(|v| BASE64_STANDARD.encode(v))
+        .collect();
 
-    // get attestation
-    let attestation_response = {
-        let request = Request::Attestation {
-            user_data: Some(user_data),
-            public_key: None,
-            nonce: None,
-        };
-        nsm_driver::nsm_process_request(nsm_fd, request)
-    };
-    nsm_driver::nsm_exit(nsm_fd);
-
-    let attestation_b64 = match attestation_response {
-        Response::Attestation { document } => BASE64_STANDARD.encode(&document),
-        _ => anyhow::bail!("Failed to get attestation document"),
-    };
-
-    let attestation_document = format!(
-        r#"{{"commit_hash": "{}", "artifact_name": "{}", "artifact_hash": "{}", "pcr0": "{}", "pcr1": "{}", "pcr2": "{}", "attestation": "{}"}}"#,
-        commit_hash,
-        artifact_name,
-        artifact_hash,
-        BASE64_STANDARD.encode(&pcr_values[0]),
-        BASE64_STANDARD.encode(&pcr_values[1]),
-        BASE64_STANDARD.encode(&pcr_values[2]),
-        attestation_b64
+    let statement = provenance::provenance_statement(&BuildDescription {
+        repository: &job.repository,
+        workflow_ref: checkout.workflow_ref.as_deref(),
+        runner_version: &job.runner_version,
+        run_id: job.run_id,
+        commit_hash: &checkout.commit_hash,
+        source_tree_digest: &checkout.source_tree_digest,
+        build_environment: &checkout.build_environment,
+        fetched_inputs: &checkout.fetched_inputs,
+        artifacts,
+        pcrs: [pcr_values[0], pcr_values[1], pcr_values[2]],
+    })?;
+    let statement_json = serde_json::to_string(&statement)?;
+
+    let claims = AttestationClaims::new(
+        checkout.commit_hash.clone(),
+        checkout.source_tree_digest.clone(),
+        &checkout.build_environment,
+        &checkout.fetched_inputs,
+        artifacts,
+        provenance::provenance_digest(&statement_json),
     );
+    let user_data = claims.to_user_data()?;
+
+    let evidence = provider.attest(Some(&user_data), (!nonce.is_empty()).then_some(nonce), None)?;
+
+    let attestation_document = AttestationDocument {
+        schema_version: ATTESTATION_DOCUMENT_SCHEMA_VERSION,
+        commit_hash: checkout.commit_hash.clone(),
+        source_tree_digest: checkout.source_tree_digest.clone(),
+        build_environment: checkout.build_environment.clone(),
+        build_environment_digest: claims.build_environment_digest,
+        fetched_inputs: checkout.fetched_inputs.clone(),
+        fetched_inputs_digest: claims.fetched_inputs_digest,
+        artifacts: artifacts.to_vec(),
+        artifacts_digest: claims.artifacts_digest,
+        run_id: job.run_id,
+        provenance: statement_json,
+        nonce: hex::encode(nonce),
+        pcr0: pcrs[0].clone(),
+        pcr1: pcrs[1].clone(),
+        pcr2: pcrs[2].clone(),
+        attestation: BASE64_STANDARD.encode(evidence),
+    }
+    .to_json()?;
     debug!("Attestation document: {}", attestation_document);
     Ok(attestation_document)
 }
 
-async fn perform_fake_attestation(
-    commit_hash: String,
-    artifact_name: String,
-    artifact_hash: String,
-) -> anyhow::Result<String> {
-    warn!("Creating a fake attestation document");
-    let attestation_document = format!(
-        r#"{{"commit_hash": "{}", "artifact_name": "{}", "artifact_hash": "{}", "pcr0": "fake0", "pcr1": "fake1", "pcr2": "fake2", "attestation": "fake signature"}}"#,
-        commit_hash, artifact_name, artifact_hash
-    );
-    debug!("Fake attestation document: {}", attestation_document);
+/// Bind the public key of the secure channel and the host's nonce into an attestation document,
+/// which the host verifies before sending any secrets. Returns the raw evidence, which is empty
+/// without attestation.
+pub async fn attest_channel_key(
+    provider: &dyn AttestationProvider,
+    public_key: &[u8],
+    nonce: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    if provider.backend() == AttestationBackend::None {
+        warn!("Not attesting the channel key (no attestation backend)");
+    }
+    provider
+        .attest(None, Some(nonce), Some(public_key))
+        .context("Failed to attest the channel key")
+}
 
-    Ok(attestation_document)
+/// The Nitro Secure Module of the enclave.
+pub struct NitroAttestation;
+
+impl NitroAttestation {
+    fn process_request(request: Request) -> Response {
+        let nsm_fd = nsm_driver::nsm_init();
+        let response = nsm_driver::nsm_process_request(nsm_fd, request);
+        nsm_driver::nsm_exit(nsm_fd);
+        response
+    }
+}
+
+impl AttestationProvider for NitroAttestation {
+    fn backend(&self) -> AttestationBackend {
+        AttestationBackend::Nitro
+    }
+
+    fn measurements(&self) -> anyhow::Result<Measurements> {
+        nsm_measurements(NitroAttestation::process_request)
+    }
+
+    fn attest(
+        &self,
+        user_data: Option<&[u8]>,
+        nonce: Option<&[u8]>,
+        public_key: Option<&[u8]>,
+    ) -> anyhow::Result<Vec<u8>> {
+        nsm_attest(
+            NitroAttestation::process_request,
+            user_data,
+            nonce,
+            public_key,
+        )
+    }
+}
+
+/// The NSM emulator, which produces documents in the Nitro format that chain up to its own root.
+pub struct EmulatedAttestation {
+    emulator: NsmEmulator,
+}
+
+impl AttestationProvider for EmulatedAttestation {
+    fn backend(&self) -> AttestationBackend {
+        AttestationBackend::Emulated
+    }
+
+    fn measurements(&self) -> anyhow::Result<Measurements> {
+        nsm_measurements(|request| self.emulator.process_request(request))
+    }
+
+    fn attest(
+        &self,
+        user_data: Option<&[u8]>,
+        nonce: Option<&[u8]>,
+        public_key: Option<&[u8]>,
+    ) -> anyhow::Result<Vec<u8>> {
+        nsm_attest(
+            |request| self.emulator.process_request(request),
+            user_data,
+            nonce,
+            public_key,
+        )
+    }
+}
+
+/// No attestation at all (e.g. for running locally): all-zero PCRs and empty evidence.
+pub struct NoAttestation;
+
+impl AttestationProvider for NoAttestation {
+    fn backend(&self) -> AttestationBackend {
+        AttestationBackend::None
+    }
+
+    fn measurements(&self) -> anyhow::Result<Measurements> {
+        Ok(REPORTED_PCRS
+            .iter()
+            .map(|&index| (index, vec![0; PLACEHOLDER_PCR_LEN]))
+            .collect())
+    }
+
+    fn attest(
+        &self,
+        _user_data: Option<&[u8]>,
+        _nonce: Option<&[u8]>,
+        _public_key: Option<&[u8]>,
+    ) -> anyhow::Result<Vec<u8>> {
+        Ok(vec![])
+    }
+}
+
+fn nsm_measurements(process_request: impl Fn(Request) -> Response) -> anyhow::Result<Measurements> {
+    let mut measurements = Measurements::new();
+    for index in REPORTED_PCRS {
+        match process_request(Request::DescribePCR {
+            index: index as u16,
+        }) {
+            Response::DescribePCR { lock: _, data } => measurements.insert(index, data),
+            response => anyhow::bail!("Failed to get pcr{}: {:?}", index, response),
+        };
+    }
+    Ok(measurements)
+}
+
+fn nsm_attest(
+    process_request: impl Fn(Request) -> Response,
+    user_data: Option<&[u8]>,
+    nonce: Option<&[u8]>,
+    public_key: Option<&[u8]>,
+) -> anyhow::Result<Vec<u8>> {
+    let response = process_request(Request::Attestation {
+        user_data: user_data.map(ByteBuf::from),
+        nonce: nonce.map(ByteBuf::from),
+        public_key: public_key.map(ByteBuf::from),
+    });
+    match response {
+        Response::Attestation { document } => Ok(document),
+        response => anyhow::bail!("Failed to get attestation document: {:?}", response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_nitro_enclaves_cose::crypto::Openssl;
+    use aws_nitro_enclaves_cose::CoseSign1;
+    use common::provenance::Statement;
+    use nsm_io::AttestationDoc;
+
+    fn artifact(name: &str, hash: &str) -> Artifact {
+        Artifact {
+            name: name.to_string(),
+            hash: hash.to_string(),
+        }
+    }
+
+    fn sample_checkout() -> MeasuredCheckout {
+        MeasuredCheckout {
+            commit_hash: "commit".to_string(),
+            source_tree_digest: "tree".to_string(),
+            build_environment: BuildEnvironment {
+                rootfs_digest: Some("rootfs".to_string()),
+                hermetic_cache_digest: Some("cache".to_string()),
+                image_digest: Some("sha256:image".to_string()),
+                image_allowlist_digest: Some("allowlist".to_string()),
+            },
+            fetched_inputs: vec![FetchedInput {
+                url: "https://static.crates.io/crates/app/app-1.0.0.crate".to_string(),
+                hash: "sha256:aaaa".to_string(),
+                size: None,
+            }],
+            workflow_ref: Some(
+                "owner/repo/.github/workflows/build.yml@refs/heads/main".to_string(),
+            ),
+        }
+    }
+
+    fn sample_job() -> BuildJob {
+        BuildJob {
+            repository: "owner/repo".to_string(),
+            runner_version: "2.328.0".to_string(),
+            run_id: 42,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fake_attestation_lists_all_artifacts() {
+        let artifacts = [artifact("app", "aaaa"), artifact("app.sha256", "bbbb")];
+        let provider = new_provider(AttestationBackend::None, None).unwrap();
+        let document = perform_attestation(
+            provider.as_ref(),
+            &sample_checkout(),
+            &artifacts,
+            &sample_job(),
+            b"nonce",
+        )
+        .await
+        .unwrap();
+
+        let document = AttestationDocument::from_json(&document).unwrap();
+        assert_eq!(document.commit_hash, "commit");
+        assert_eq!(document.source_tree_digest, "tree");
+        assert_eq!(
+            document.build_environment,
+            sample_checkout().build_environment
+        );
+        assert_eq!(
+            document.build_environment_digest,
+            common::claims::build_environment_digest(&document.build_environment)
+        );
+        assert_eq!(document.fetched_inputs, sample_checkout().fetched_inputs);
+        assert_eq!(
+            document.fetched_inputs_digest,
+            common::claims::fetched_inputs_digest(&sample_checkout().fetched_inputs)
+        );
+        assert_eq!(document.run_id, 42);
+        assert_eq!(document.nonce, hex::encode(b"nonce"));
+        assert_eq!(document.artifacts, artifacts);
+        assert_eq!(
+            document.artifacts_digest,
+            common::claims::artifacts_digest(&artifacts)
+        );
+        assert_eq!(document.attestation, "");
+
+        let statement: Statement = serde_json::from_str(&document.provenance).unwrap();
+        assert_eq!(statement.subject.len(), 2);
+        assert_eq!(statement.subject[1].name.as_deref(), Some("app.sha256"));
+        assert_eq!(
+            statement.predicate.run_details.builder.id,
+            format!(
+                "{}?pcr0={}&pcr1={}&pcr2={}",
+                provenance::BUILDER_ID_PREFIX,
+                "00".repeat(PLACEHOLDER_PCR_LEN),
+                "00".repeat(PLACEHOLDER_PCR_LEN),
+                "00".repeat(PLACEHOLDER_PCR_LEN)
+            )
+        );
+        assert_eq!(
+            statement
+                .predicate
+                .build_definition
+                .external_parameters
+                .workflow_ref,
+            sample_checkout().workflow_ref
+        );
+    }
+
+    #[tokio::test]
+    async fn test_emulated_attestation_reports_its_measurements() {
+        let dir = tempfile::tempdir().unwrap();
+        let pcrs = BTreeMap::from([(1, vec![0xab; 48])]);
+        let emulator = NsmEmulator::new(dir.path(), pcrs).unwrap();
+        let provider = new_provider(AttestationBackend::Emulated, Some(emulator)).unwrap();
+
+        let artifacts = [artifact("app", "aaaa")];
+        let document = perform_attestation(
+            provider.as_ref(),
+            &sample_checkout(),
+            &artifacts,
+            &sample_job(),
+            b"",
+        )
+        .await
+        .unwrap();
+        let document = AttestationDocument::from_json(&document).unwrap();
+        assert_eq!(document.pcr1, BASE64_STANDARD.encode([0xab; 48]));
+
+        // the signed evidence covers the same measurements and the claims
+        let evidence = BASE64_STANDARD.decode(&document.attestation).unwrap();
+        let cose_sign_1 = CoseSign1::from_bytes(&evidence).unwrap();
+        let payload = cose_sign_1.get_payload::<Openssl>(None).unwrap();
+        let attestation_doc = AttestationDoc::from_binary(&payload).unwrap();
+        assert_eq!(attestation_doc.pcrs[&1].to_vec(), vec![0xab; 48]);
+        assert!(attestation_doc.nonce.is_none());
+        let claims =
+            AttestationClaims::from_user_data(&attestation_doc.user_data.unwrap()).unwrap();
+        assert_eq!(
+            claims,
+            AttestationClaims::new(
+                "commit".to_string(),
+                "tree".to_string(),
+                &document.build_environment,
+                &sample_checkout().fetched_inputs,
+                &artifacts,
+                provenance::provenance_digest(&document.provenance)
+            )
+        );
+    }
+
+    #[test]
+    fn test_emulated_backend_requires_the_emulator() {
+        assert!(new_provider(AttestationBackend::Emulated, None).is_err());
+    }
 }
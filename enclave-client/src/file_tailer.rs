@@ -0,0 +1,330 @@
+use crate::hook_protocol::MAX_HOOK_LINE_LEN;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, warn};
+
+/// How often the file is checked for new content. The runner only writes a few lines per step,
+/// so this is plenty and avoids depending on inotify (which does not work on all mounts).
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// How much of the file is read at once.
+const READ_CHUNK_LEN: usize = 64 * 1024;
+
+/// Follows a log file like `tail -F`, but in-process: every complete line is sent (without the
+/// line break) to the channel. The file may not exist yet, and it may be truncated or replaced
+/// (rotated) while it is followed; the tailer then starts over from the beginning of the file.
+/// The file holds the output of the build, so lines longer than `MAX_HOOK_LINE_LEN` are dropped
+/// (with a warning) while they are read instead of being buffered.
+pub(crate) struct FileTailer {
+    stop_tx: oneshot::Sender<()>,
+    handle: JoinHandle<anyhow::Result<()>>,
+}
+
+impl FileTailer {
+    pub(crate) fn spawn(path: &Path, line_output: Sender<String>) -> FileTailer {
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let path = path.to_owned();
+        let handle = tokio::task::spawn(follow(path, line_output, stop_rx));
+        FileTailer { stop_tx, handle }
+    }
+
+    /// Reads everything that has been written so far (including a final line without a line
+    /// break) and stops. Call this once the writer has exited, so that its last lines are not
+    /// lost. The channel is closed afterwards.
+    pub(crate) async fn stop(self) -> anyhow::Result<()> {
+        // the task might already be gone because the receiver was dropped
+        let _ = self.stop_tx.send(());
+        self.handle.await?
+    }
+}
+
+/// The file that is currently followed and how far it has been read.
+struct TailState {
+    path: PathBuf,
+    file: Option<File>,
+    inode: u64,
+    position: u64,
+    lines: LineBuffer,
+}
+
+/// Splits what is read into lines.
+#[derive(Default)]
+struct LineBuffer {
+    /// The beginning of a line whose line break has not been written yet.
+    partial_line: Vec<u8>,
+
+    /// Whether the current line is too long, so that the rest of it is dropped.
+    overlong: bool,
+}
+
+async fn follow(
+    path: PathBuf,
+    line_output: Sender<String>,
+    mut stop_rx: oneshot::Receiver<()>,
+) -> anyhow::Result<()> {
+    let mut state = TailState {
+        path,
+        file: None,
+        inode: 0,
+        position: 0,
+        lines: LineBuffer::default(),
+    };
+    loop {
+        let stopping = tokio::select! {
+            _ = &mut stop_rx => true,
+            _ = time::sleep(POLL_INTERVAL) => false,
+        };
+
+        if !state.poll(&line_output).await? {
+            debug!("Nobody listens to {:?} anymore", state.path);
+            return Ok(());
+        }
+        if stopping {
+            state.lines.flush(&line_output).await;
+            debug!("Stopped following {:?}", state.path);
+            return Ok(());
+        }
+    }
+}
+
+impl TailState {
+    /// Sends all complete lines that were written since the last poll. Returns `false` once the
+    /// receiver is gone.
+    async fn poll(&mut self, line_output: &Sender<String>) -> anyhow::Result<bool> {
+        let metadata = match tokio::fs::metadata(&self.path).await {
+            Ok(metadata) => Some(metadata),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        // a different file at the path: read what is left of the old one first
+        let replaced = metadata
+            .as_ref()
+            .is_some_and(|metadata| self.file.is_some() && metadata.ino() != self.inode);
+        if replaced {
+            debug!("{:?} was replaced, following the new file", self.path);
+            if !self.read_to_end(line_output).await? {
+                return Ok(false);
+            }
+            if !self.lines.flush(line_output).await {
+                return Ok(false);
+            }
+            self.file = None;
+        }
+
+        let Some(metadata) = metadata else {
+            // not created yet (or removed), keep what we have opened
+            return self.read_to_end(line_output).await;
+        };
+        if self.file.is_none() {
+            self.file = Some(File::open(&self.path).await?);
+            self.inode = metadata.ino();
+            self.position = 0;
+        }
+        if metadata.len() < self.position {
+            warn!("{:?} was truncated, reading it from the start", self.path);
+            self.position = 0;
+            self.lines = LineBuffer::default();
+        }
+        self.read_to_end(line_output).await
+    }
+
+    async fn read_to_end(&mut self, line_output: &Sender<String>) -> anyhow::Result<bool> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(true);
+        };
+        file.seek(SeekFrom::Start(self.position)).await?;
+        let mut buf = vec![0; READ_CHUNK_LEN];
+        loop {
+            let read = file.read(&mut buf).await?;
+            if read == 0 {
+                return Ok(true);
+            }
+            self.position += read as u64;
+            if !self.lines.push(&buf[..read], line_output).await {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+impl LineBuffer {
+    /// Sends the lines that `chunk` completes. Returns `false` once the receiver is gone.
+    async fn push(&mut self, chunk: &[u8], line_output: &Sender<String>) -> bool {
+        let mut parts = chunk.split(|&b| b == b'\n').peekable();
+        while let Some(part) = parts.next() {
+            self.extend(part);
+            if parts.peek().is_none() {
+                // the last part has no line break (yet)
+                break;
+            }
+            let line = std::mem::take(&mut self.partial_line);
+            if !std::mem::take(&mut self.overlong) && !send_line(line_output, line).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn extend(&mut self, part: &[u8]) {
+        if self.overlong {
+            return;
+        }
+        if self.partial_line.len() + part.len() > MAX_HOOK_LINE_LEN {
+            warn!(
+                "Dropping a line of the output that is longer than {} bytes",
+                MAX_HOOK_LINE_LEN
+            );
+            self.partial_line = vec![];
+            self.overlong = true;
+            return;
+        }
+        self.partial_line.extend_from_slice(part);
+    }
+
+    async fn flush(&mut self, line_output: &Sender<String>) -> bool {
+        let line = std::mem::take(&mut self.partial_line);
+        if std::mem::take(&mut self.overlong) || line.is_empty() {
+            return true;
+        }
+        send_line(line_output, line).await
+    }
+}
+
+async fn send_line(line_output: &Sender<String>, line: Vec<u8>) -> bool {
+    let line = String::from_utf8_lossy(&line).into_owned();
+    line_output.send(line).await.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hook_protocol::HOOK_PROTOCOL_VERSION;
+    use std::io::Write;
+    use tokio::sync::mpsc;
+
+    fn event(fields: &str) -> String {
+        format!(r#"{{"version":{},{}}}"#, HOOK_PROTOCOL_VERSION, fields)
+    }
+
+    fn append(path: &Path, content: &str) {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+    }
+
+    async fn next_line(line_rx: &mut mpsc::Receiver<String>) -> String {
+        time::timeout(time::Duration::from_secs(5), line_rx.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_follows_a_file_that_is_created_later() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.log");
+        let (line_tx, mut line_rx) = mpsc::channel(8);
+        let tailer = FileTailer::spawn(&path, line_tx);
+
+        let checkout_complete = event(r#""type":"checkout_complete""#);
+        let log = event(r#""type":"log","message":"hello world""#);
+        let (start, end) = log.split_at(10);
+        append(&path, &format!("{}\n{}", checkout_complete, start));
+        assert_eq!(next_line(&mut line_rx).await, checkout_complete);
+        append(&path, &format!("{}\n", end));
+        assert_eq!(next_line(&mut line_rx).await, log);
+
+        tailer.stop().await.unwrap();
+        assert!(line_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_flushes_the_remaining_lines_on_stop() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.log");
+        append(&path, "");
+        let (line_tx, mut line_rx) = mpsc::channel(8);
+        let tailer = FileTailer::spawn(&path, line_tx);
+
+        // written right before the runner exits, without waiting for the next poll
+        let artifact = event(r#""type":"artifact","path":"app""#);
+        let build_complete = event(r#""type":"build_complete""#);
+        append(&path, &format!("{}\n{}", artifact, build_complete));
+        tailer.stop().await.unwrap();
+
+        assert_eq!(line_rx.recv().await.unwrap(), artifact);
+        assert_eq!(line_rx.recv().await.unwrap(), build_complete);
+        assert!(line_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handles_truncation_and_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.log");
+        append(&path, "first\n");
+        let (line_tx, mut line_rx) = mpsc::channel(8);
+        let tailer = FileTailer::spawn(&path, line_tx);
+        assert_eq!(next_line(&mut line_rx).await, "first");
+
+        // truncated (as by `ensure_empty_output_log_file`)
+        std::fs::write(&path, "").unwrap();
+        time::sleep(2 * POLL_INTERVAL).await;
+        append(&path, "second\n");
+        assert_eq!(next_line(&mut line_rx).await, "second");
+
+        // replaced by a new file, the old one is still read to the end
+        let rotated = path.with_extension("log.1");
+        std::fs::rename(&path, &rotated).unwrap();
+        append(&rotated, "third\n");
+        append(&path, "fourth\n");
+        assert_eq!(next_line(&mut line_rx).await, "third");
+        assert_eq!(next_line(&mut line_rx).await, "fourth");
+
+        tailer.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drops_overlong_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.log");
+        let (line_tx, mut line_rx) = mpsc::channel(8);
+        let tailer = FileTailer::spawn(&path, line_tx);
+
+        // longer than a chunk, and written over several polls
+        let overlong = "a".repeat(MAX_HOOK_LINE_LEN + READ_CHUNK_LEN);
+        append(&path, &overlong);
+        time::sleep(2 * POLL_INTERVAL).await;
+        append(&path, &format!("{}\nnext\n", overlong));
+        assert_eq!(next_line(&mut line_rx).await, "next");
+
+        let longest = "b".repeat(MAX_HOOK_LINE_LEN);
+        append(&path, &format!("{}\n{}", longest, overlong));
+        assert_eq!(next_line(&mut line_rx).await, longest);
+        tailer.stop().await.unwrap();
+        assert!(line_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stops_when_the_receiver_is_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.log");
+        append(&path, "line\n");
+        let (line_tx, line_rx) = mpsc::channel(1);
+        drop(line_rx);
+        let tailer = FileTailer::spawn(&path, line_tx);
+        time::timeout(time::Duration::from_secs(5), tailer.stop())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
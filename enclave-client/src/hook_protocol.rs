@@ -0,0 +1,278 @@
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+/// The version of the hook protocol. Bump this whenever events are added or change their meaning,
+/// and update the hooks in `github-runner/hooks` accordingly.
+pub const HOOK_PROTOCOL_VERSION: u32 = 4;
+
+/// Longer lines are rejected without being parsed.
+pub const MAX_HOOK_LINE_LEN: usize = 64 * 1024;
+
+const MAX_ARTIFACT_PATH_LEN: usize = 1024;
+const MAX_WORKFLOW_REF_LEN: usize = 1024;
+const MAX_MARKER_LEN: usize = 64;
+const MAX_DATETIME_LEN: usize = 64;
+
+/// An event that the hooks (and the runner scripts) report to the enclave client. Each event is
+/// one JSON object per line of the output log, e.g.
+/// `{"version":4,"type":"artifact","path":"app.tar.gz"}`. The log is written by
+/// untrusted build code, so every field is validated before it is used.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HookEvent {
+    RunnerConfigurationDone,
+    RunnerFinished,
+    /// The pre hook has checked out the repository. The enclave client then measures the commit
+    /// itself (a reported hash could be forged by any build step) and the pre hook waits for it.
+    /// The workflow (`GITHUB_WORKFLOW_REF`) goes into the provenance; no build step has run yet.
+    CheckoutComplete {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        workflow_ref: Option<String>,
+    },
+    /// An artifact in the artifacts directory, which the enclave client hashes itself. The path
+    /// is relative to that directory and also serves as the name of the artifact.
+    Artifact {
+        path: String,
+    },
+    BuildComplete,
+    Log {
+        message: String,
+    },
+    Timestamp {
+        marker: String,
+        datetime: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct HookLine {
+    version: u32,
+    #[serde(flatten)]
+    event: HookEvent,
+}
+
+impl HookEvent {
+    fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            HookEvent::Artifact { path } => {
+                if path.len() > MAX_ARTIFACT_PATH_LEN
+                    || path.chars().any(|c| c.is_control())
+                    || path
+                        .split('/')
+                        .any(|component| matches!(component, "" | "." | ".."))
+                {
+                    bail!("Invalid artifact path: {:?}", path);
+                }
+            }
+            HookEvent::CheckoutComplete {
+                workflow_ref: Some(workflow_ref),
+            } => {
+                if workflow_ref.is_empty()
+                    || workflow_ref.len() > MAX_WORKFLOW_REF_LEN
+                    || workflow_ref.chars().any(|c| c.is_control())
+                {
+                    bail!("Invalid workflow ref: {:?}", workflow_ref);
+                }
+            }
+            HookEvent::Timestamp { marker, datetime } => {
+                if marker.is_empty()
+                    || marker.len() > MAX_MARKER_LEN
+                    || !marker
+                        .chars()
+                        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+                {
+                    bail!("Invalid timestamp marker: {:?}", marker);
+                }
+                if datetime.is_empty()
+                    || datetime.len() > MAX_DATETIME_LEN
+                    || datetime
+                        .chars()
+                        .any(|c| c.is_whitespace() || c.is_control())
+                {
+                    bail!("Invalid timestamp for {}: {:?}", marker, datetime);
+                }
+            }
+            HookEvent::RunnerConfigurationDone
+            | HookEvent::RunnerFinished
+            | HookEvent::CheckoutComplete { workflow_ref: None }
+            | HookEvent::BuildComplete
+            | HookEvent::Log { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Parses a line of the output log. Lines that are not JSON objects are free text (e.g. from
+/// scripts that echo into the log) and yield `None`; malformed events are errors.
+pub fn parse_hook_line(line: &str) -> anyhow::Result<Option<HookEvent>> {
+    let line = line.trim();
+    if !line.starts_with('{') {
+        return Ok(None);
+    }
+    if line.len() > MAX_HOOK_LINE_LEN {
+        bail!(
+            "The hook event takes {} bytes, but at most {} bytes are allowed",
+            line.len(),
+            MAX_HOOK_LINE_LEN
+        );
+    }
+
+    #[derive(Deserialize)]
+    struct Versioned {
+        version: u32,
+    }
+
+    // check the version first, as the events may differ between versions
+    let versioned: Versioned =
+        serde_json::from_str(line).context("Failed to parse the hook event")?;
+    if versioned.version != HOOK_PROTOCOL_VERSION {
+        bail!(
+            "Unsupported hook protocol version {} (expected {})",
+            versioned.version,
+            HOOK_PROTOCOL_VERSION
+        );
+    }
+    let hook_line: HookLine =
+        serde_json::from_str(line).context("Failed to parse the hook event")?;
+    hook_line.event.validate()?;
+    Ok(Some(hook_line.event))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn to_line(event: &HookEvent) -> String {
+        serde_json::to_string(&HookLine {
+            version: HOOK_PROTOCOL_VERSION,
+            event: event.clone(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_events() {
+        assert_eq!(
+            parse_hook_line(r#"{"version":4,"type":"artifact","path":"dist/app.tar.gz"}"#).unwrap(),
+            Some(HookEvent::Artifact {
+                path: "dist/app.tar.gz".to_string()
+            })
+        );
+        assert_eq!(
+            parse_hook_line(r#"{"version":4,"type":"log","message":"hello  big world"}"#).unwrap(),
+            Some(HookEvent::Log {
+                message: "hello  big world".to_string()
+            })
+        );
+        assert_eq!(
+            parse_hook_line(
+                r#"{"version":4,"type":"timestamp","marker":"PRE_CHECKOUT","datetime":"2024-01-01T12:00:00,123+00:00"}"#
+            )
+            .unwrap(),
+            Some(HookEvent::Timestamp {
+                marker: "PRE_CHECKOUT".to_string(),
+                datetime: "2024-01-01T12:00:00,123+00:00".to_string()
+            })
+        );
+        assert_eq!(
+            parse_hook_line(r#"{"version":4,"type":"build_complete"}"#).unwrap(),
+            Some(HookEvent::BuildComplete)
+        );
+        assert_eq!(
+            parse_hook_line(r#"{"version":4,"type":"checkout_complete"}"#).unwrap(),
+            Some(HookEvent::CheckoutComplete { workflow_ref: None })
+        );
+        assert_eq!(
+            parse_hook_line(
+                r#"{"version":4,"type":"checkout_complete","workflow_ref":"o/r/.github/workflows/b.yml@refs/heads/main"}"#
+            )
+            .unwrap(),
+            Some(HookEvent::CheckoutComplete {
+                workflow_ref: Some("o/r/.github/workflows/b.yml@refs/heads/main".to_string())
+            })
+        );
+    }
+
+    #[test]
+    fn test_free_text_is_ignored() {
+        assert_eq!(parse_hook_line("").unwrap(), None);
+        assert_eq!(
+            parse_hook_line("GITHUB_REF_NAME not set, defaulting to main").unwrap(),
+            None
+        );
+        // the old format is not accepted anymore
+        assert_eq!(parse_hook_line("GIT_HASH=abc").unwrap(), None);
+    }
+
+    #[test]
+    fn test_rejects_malformed_events() {
+        let invalid = [
+            r#"{"version":4"#,
+            r#"{"type":"build_complete"}"#,
+            r#"{"version":1,"type":"build_complete"}"#,
+            r#"{"version":4,"type":"unknown"}"#,
+            r#"{"version":4,"type":"log"}"#,
+            // commit hashes are measured by the enclave client, not reported
+            r#"{"version":4,"type":"commit_hash","commit_hash":"abc"}"#,
+            // hashes are computed by the enclave client, not reported
+            r#"{"version":2,"type":"artifact","name":"app","hash":"9f86d081"}"#,
+            r#"{"version":4,"type":"artifact","name":"app","hash":"9f86d081"}"#,
+            r#"{"version":4,"type":"artifact","path":""}"#,
+            r#"{"version":4,"type":"artifact","path":"/etc/passwd"}"#,
+            r#"{"version":4,"type":"artifact","path":"../app"}"#,
+            r#"{"version":4,"type":"artifact","path":"dist/./app"}"#,
+            r#"{"version":4,"type":"artifact","path":"dist/"}"#,
+            r#"{"version":4,"type":"artifact","path":"app\n"}"#,
+            r#"{"version":4,"type":"timestamp","marker":"a b","datetime":"now"}"#,
+            r#"{"version":4,"type":"checkout_complete","workflow_ref":""}"#,
+            r#"{"version":4,"type":"checkout_complete","workflow_ref":"a\nb"}"#,
+            // the workflow ref was added in version 4
+            r#"{"version":3,"type":"checkout_complete"}"#,
+        ];
+        for line in invalid {
+            assert!(parse_hook_line(line).is_err(), "accepted {}", line);
+        }
+    }
+
+    #[test]
+    fn test_rejects_oversized_lines() {
+        let message = "a".repeat(MAX_HOOK_LINE_LEN);
+        let line = to_line(&HookEvent::Log { message });
+        assert!(parse_hook_line(&line).is_err());
+    }
+
+    fn hook_event() -> impl Strategy<Value = HookEvent> {
+        prop_oneof![
+            Just(HookEvent::RunnerConfigurationDone),
+            Just(HookEvent::RunnerFinished),
+            Just(HookEvent::BuildComplete),
+            proptest::option::of("[a-zA-Z0-9/._@-]{1,64}")
+                .prop_map(|workflow_ref| HookEvent::CheckoutComplete { workflow_ref }),
+            "[a-zA-Z0-9_-][a-zA-Z0-9._-]{0,31}(/[a-zA-Z0-9_-][a-zA-Z0-9._-]{0,31}){0,3}"
+                .prop_map(|path| HookEvent::Artifact { path }),
+            any::<String>().prop_map(|message| HookEvent::Log { message }),
+            ("[A-Z_]{1,32}", "[0-9T:,.+-]{1,40}")
+                .prop_map(|(marker, datetime)| HookEvent::Timestamp { marker, datetime }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn fuzz_parse_never_panics(line in any::<String>()) {
+            let _ = parse_hook_line(&line);
+        }
+
+        #[test]
+        fn fuzz_parse_json_like_lines_never_panics(
+            line in r#"\{("[a-z_]{1,12}":("[^"]{0,20}"|[0-9]{1,3}|null|\{\}|\[\]),?){0,5}\}"#
+        ) {
+            let _ = parse_hook_line(&line);
+        }
+
+        #[test]
+        fn fuzz_events_roundtrip(event in hook_event()) {
+            prop_assert_eq!(parse_hook_line(&to_line(&event)).unwrap(), Some(event));
+        }
+    }
+}
@@ -1,26 +1,61 @@
 extern crate alloc;
 
+mod artifacts;
 mod attestation;
+mod file_tailer;
+mod hook_protocol;
+mod network;
+mod nsm_emulator;
+mod oci_image;
+mod overlay;
 mod runc;
 mod runner_manager;
 
+use std::fmt;
+use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
 
+use crate::attestation::{AttestationProvider, BuildJob, MeasuredCheckout};
+use crate::nsm_emulator::NsmEmulator;
 use crate::runner_manager::RunnerMessage;
+use anyhow::Context;
 use clap::Parser;
-use common::messages::{EnclaveToHostMessage, HostToEnclaveMessage, Message};
-use common::{init_tracing, protocol, short_wait, RunnerStartMode};
-use futures::StreamExt as _;
+use common::claims::Artifact;
+use common::messages::{
+    Capability, EnclaveStage, EnclaveToHostMessage, ErrorKind, Hello, HostToEnclaveMessage,
+    Message, HEARTBEAT_INTERVAL,
+};
+use common::protocol::{ProtocolConfig, ProtocolError};
+use common::secure_channel::SecureWriteHalf;
+use common::transport::{Transport, TransportAddr};
+use common::{init_tracing, protocol, secure_channel, short_wait, transport, RunnerStartMode};
 use runner_manager::DirectRunnerManager;
-use tokio::task;
-use tokio_vsock::VsockListener;
-use tracing::{debug, error, info};
+use tokio::io::AsyncWrite;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::MissedTickBehavior;
+use tokio::{task, time};
+use tracing::{debug, error, info, warn};
 
 #[derive(Parser, Debug)]
 #[clap(version)]
 struct Args {
-    /// The vsock address to listen on.
-    pub vsock: String,
+    /// The address to listen on: `CID:PORT` for vsock, a socket path for unix, `IP:PORT` for tcp.
+    pub address: String,
+
+    /// The transport to listen on. Nitro enclaves only support vsock.
+    #[clap(long, default_value = "vsock")]
+    pub transport: Transport,
+
+    /// Emulate the NSM with a root certificate kept in this directory (created if needed), so that
+    /// real attestations can be produced outside of a Nitro enclave. Verifiers have to trust the
+    /// `root.pem` in there instead of the AWS Nitro root certificate.
+    #[clap(long)]
+    pub nsm_emulator_dir: Option<PathBuf>,
+
+    /// A PCR value reported by the emulated NSM as `INDEX=HEX` (may be repeated). PCRs that are
+    /// not set are all zeros, as in a debug enclave.
+    #[clap(long = "nsm-emulator-pcr", requires = "nsm_emulator_dir")]
+    pub nsm_emulator_pcrs: Vec<String>,
 }
 
 /// We model the client state as a typed state machine. The state transitions are driven by the
@@ -31,12 +66,12 @@ enum EnclaveState {
     ReceivedStartMessage,
     Configured,
     WithMeasuredInput {
-        commit_hash: String,
+        checkout: MeasuredCheckout,
+        artifacts: Vec<Artifact>,
     },
     BuildFinished {
-        commit_hash: String,
-        artifact_name: String,
-        artifact_hash: String,
+        checkout: MeasuredCheckout,
+        artifacts: Vec<Artifact>,
         local_input_log_path: PathBuf,
     },
     Error,
@@ -47,6 +82,17 @@ impl EnclaveState {
         EnclaveState::Initializing
     }
 
+    fn stage(&self) -> EnclaveStage {
+        match self {
+            EnclaveState::Initializing => EnclaveStage::Initializing,
+            EnclaveState::ReceivedStartMessage => EnclaveStage::Configuring,
+            EnclaveState::Configured => EnclaveStage::WaitingForInput,
+            EnclaveState::WithMeasuredInput { .. } => EnclaveStage::Building,
+            EnclaveState::BuildFinished { .. } => EnclaveStage::Attesting,
+            EnclaveState::Error => EnclaveStage::Failed,
+        }
+    }
+
     fn on_start_message(self) -> EnclaveState {
         match self {
             EnclaveState::Initializing => EnclaveState::ReceivedStartMessage,
@@ -61,24 +107,42 @@ impl EnclaveState {
         }
     }
 
-    fn on_received_commit_hash(self, commit_hash: String) -> EnclaveState {
+    fn on_measured_checkout(self, checkout: MeasuredCheckout) -> EnclaveState {
         match self {
-            EnclaveState::Configured => EnclaveState::WithMeasuredInput { commit_hash },
+            EnclaveState::Configured => EnclaveState::WithMeasuredInput {
+                checkout,
+                artifacts: vec![],
+            },
             _ => EnclaveState::Error,
         }
     }
 
-    fn on_received_artifact(
-        self,
-        artifact_name: String,
-        artifact_hash: String,
-        local_input_log_path: PathBuf,
-    ) -> EnclaveState {
+    /// Artifacts are collected until the build is complete. Reporting the same name twice is an
+    /// error, as the log entries of a build are identified by the artifact name.
+    fn on_received_artifact(self, artifact: Artifact) -> EnclaveState {
         match self {
-            EnclaveState::WithMeasuredInput { commit_hash } => EnclaveState::BuildFinished {
-                commit_hash,
-                artifact_name,
-                artifact_hash,
+            EnclaveState::WithMeasuredInput {
+                checkout,
+                mut artifacts,
+            } if !artifacts.iter().any(|a| a.name == artifact.name) => {
+                artifacts.push(artifact);
+                EnclaveState::WithMeasuredInput {
+                    checkout,
+                    artifacts,
+                }
+            }
+            _ => EnclaveState::Error,
+        }
+    }
+
+    fn on_build_complete(self, local_input_log_path: PathBuf) -> EnclaveState {
+        match self {
+            EnclaveState::WithMeasuredInput {
+                checkout,
+                artifacts,
+            } if !artifacts.is_empty() => EnclaveState::BuildFinished {
+                checkout,
+                artifacts,
                 local_input_log_path,
             },
             _ => EnclaveState::Error,
@@ -86,52 +150,207 @@ impl EnclaveState {
     }
 }
 
+/// A failure with a specific `ErrorKind` for the host. Any other error is reported to the host as
+/// `ErrorKind::Internal`.
+#[derive(Debug)]
+struct JobFailure {
+    kind: ErrorKind,
+    detail: String,
+}
+
+impl JobFailure {
+    fn new(kind: ErrorKind, detail: String) -> JobFailure {
+        JobFailure { kind, detail }
+    }
+}
+
+impl Display for JobFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.detail)
+    }
+}
+
+impl std::error::Error for JobFailure {}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     info!("Enclave client started");
-    let mut enclave_state = EnclaveState::new();
 
     // init
     let args = Args::parse();
     init_tracing();
     debug!("{:?}", args);
 
-    // listen to the vsock
-    let addr = common::parse_vsock_addr(args.vsock)?;
-    let listener = VsockListener::bind(addr)?;
-    debug!("VsockListener bound: {}", addr);
+    // set up the emulator before we accept, so that its root certificate exists once the host
+    // connects
+    let nsm_emulator = match &args.nsm_emulator_dir {
+        Some(dir) => {
+            let pcrs = args
+                .nsm_emulator_pcrs
+                .iter()
+                .map(|pcr| nsm_emulator::parse_pcr(pcr))
+                .collect::<anyhow::Result<_>>()?;
+            Some(NsmEmulator::new(dir, pcrs)?)
+        }
+        None => None,
+    };
 
     // we only accept one connection and then terminate
-    let mut incoming = listener.incoming();
-    let mut stream = incoming.next().await.expect("no incoming connection")?;
+    let addr = TransportAddr::parse(args.transport, &args.address)?;
+    debug!("Listening on {:?}", addr);
+    let mut stream = transport::accept_one(&addr).await?;
     info!("Accepted connection: {:?}", stream);
+    let protocol_config = ProtocolConfig::default();
+
+    // answer the host's hello with our own before bailing on a mismatch, so that the host can
+    // report the mismatch as well
+    let host_hello = protocol::read_hello(&mut stream, &protocol_config).await?;
+    let enclave_hello = Hello::new(Capability::ALL);
+    protocol::write_hello(&mut stream, &enclave_hello).await?;
+    enclave_hello.ensure_compatible(&host_hello, "host-server")?;
+    let capabilities = enclave_hello.negotiate(&host_hello);
+    debug!("Negotiated capabilities with the host: {:?}", capabilities);
+
+    // bind a fresh channel key into an attestation, so that the host can be sure that it talks to
+    // us before it sends any secrets (all later messages are encrypted)
+    let message = protocol::read_next_message(&mut stream, &protocol_config).await?;
+    let Message::HostToEnclave(HostToEnclaveMessage::RequestChannelKey {
+        nonce,
+        attestation_backend,
+    }) = message
+    else {
+        anyhow::bail!("unexpected message: {:?}", message);
+    };
+    let attestation_provider = attestation::new_provider(attestation_backend, nsm_emulator)?;
+    let keypair = secure_channel::generate_keypair()?;
+    let attestation_document =
+        attestation::attest_channel_key(attestation_provider.as_ref(), &keypair.public, &nonce)
+            .await?;
+    let message = Message::EnclaveToHost(EnclaveToHostMessage::ChannelKey {
+        public_key: keypair.public.clone(),
+        attestation_document,
+    });
+    protocol::write_message(&mut stream, &message).await?;
+    let channel = secure_channel::respond(stream, &keypair, &nonce, &protocol_config).await?;
+    debug!("Established the secure channel with the host");
+
+    // from here on we read in the background, so that the host can cancel the job at any time
+    let (reader, mut writer) = channel.split();
+    let mut host_messages = reader.into_message_receiver();
+
+    let mut stage = EnclaveStage::Initializing;
+    if let Err(e) = run_job(
+        attestation_provider.as_ref(),
+        &mut host_messages,
+        &mut writer,
+        &capabilities,
+        &mut stage,
+    )
+    .await
+    {
+        error!("The job failed in stage {:?}: {:?}", stage, e);
+        let kind = e
+            .downcast_ref::<JobFailure>()
+            .map_or(ErrorKind::Internal, |failure| failure.kind);
+        let message = Message::EnclaveToHost(EnclaveToHostMessage::Error {
+            stage,
+            kind,
+            detail: format!("{:#}", e),
+        });
+        // best effort, as the connection to the host might be what failed
+        if let Err(write_error) = writer.write_message(&message).await {
+            warn!("Failed to report the error to the host: {}", write_error);
+        }
+        short_wait().await;
+        return Err(e);
+    }
+    short_wait().await; // some time to take down the vsock connection
+
+    info!("Enclave client finished");
+    Ok(())
+}
+
+/// Runs the job from receiving the runner arguments to reporting the attestation. The `stage`
+/// is kept up to date, so that failures can be reported to the host with some context.
+async fn run_job<W: AsyncWrite + Unpin>(
+    attestation_provider: &dyn AttestationProvider,
+    host_messages: &mut mpsc::Receiver<Result<Message, ProtocolError>>,
+    writer: &mut SecureWriteHalf<W>,
+    capabilities: &[Capability],
+    stage: &mut EnclaveStage,
+) -> anyhow::Result<()> {
+    let mut enclave_state = EnclaveState::new();
 
     // parse the initial message with the runner arguments
-    let message = protocol::read_next_message(&mut stream).await?;
+    let message = next_host_message(host_messages).await?;
     let Message::HostToEnclave(HostToEnclaveMessage::StartRunner {
         enclave_client_args,
+        run_id,
+        attestation_nonce,
     }) = message
     else {
-        anyhow::bail!("unexpected message: {:?}", message);
+        return Err(JobFailure::new(
+            ErrorKind::UnexpectedMessage,
+            format!("expected the runner arguments, got: {:?}", message),
+        )
+        .into());
     };
+    let enclave_client_args = *enclave_client_args;
     enclave_state = enclave_state.on_start_message();
-    debug!("Received the enclave client args: {}", enclave_client_args);
+    debug!(
+        "Received the enclave client args for run {}: {}",
+        run_id, enclave_client_args
+    );
+    if enclave_client_args.attestation_backend != attestation_provider.backend() {
+        return Err(JobFailure::new(
+            ErrorKind::UnexpectedMessage,
+            format!(
+                "the runner arguments ask for {:?} attestations, but the channel key was attested with {:?}",
+                enclave_client_args.attestation_backend,
+                attestation_provider.backend()
+            ),
+        )
+        .into());
+    }
+
+    if let Err(e) = enclave_client_args.validate() {
+        return Err(JobFailure::new(ErrorKind::UnexpectedMessage, format!("{:#}", e)).into());
+    }
+
+    let job = BuildJob {
+        repository: enclave_client_args.runner_args.github_repository.clone(),
+        runner_version: if enclave_client_args.fake_runner_args.is_some() {
+            "simulated".to_string()
+        } else {
+            enclave_client_args.runner_args.runner_version.clone()
+        },
+        run_id,
+    };
 
     // Create and start the runner manager which babysits the GitHub Action Runner either as
     // a direct sub process or in a sandbox (using runc).
-    let (runner_message_tx, mut runner_message_rx) = tokio::sync::mpsc::channel(32);
+    let (runner_message_tx, mut runner_message_rx) = mpsc::channel(32);
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    let failure_tx = runner_message_tx.clone();
     let runner_manager_join_handle = match enclave_client_args.runner_start_mode {
         RunnerStartMode::Direct => {
             let runner_manager = DirectRunnerManager::new(
                 enclave_client_args.fake_runner_args,
                 enclave_client_args.runner_args.runner_version.clone(),
-            )?;
+            )
+            .map_err(|e| JobFailure::new(ErrorKind::RunnerFailed, format!("{:#}", e)))?;
             task::spawn(async move {
                 if let Err(e) = runner_manager
-                    .run(enclave_client_args.runner_args, runner_message_tx)
+                    .run(
+                        enclave_client_args.runner_args,
+                        runner_message_tx,
+                        cancel_rx,
+                    )
                     .await
                 {
                     error!("Error running the runner: {:?}", e);
+                    let detail = format!("{:#}", e);
+                    let _ = failure_tx.send(RunnerMessage::Failed { detail }).await;
                 }
             })
         }
@@ -139,88 +358,155 @@ async fn main() -> anyhow::Result<()> {
             let runner_manager = runner_manager::SandboxRunnerManager::new(
                 enclave_client_args.fake_runner_args,
                 enclave_client_args.runner_args.runner_version.clone(),
-            )?;
+                enclave_client_args.hermetic_cache_path,
+                enclave_client_args.resource_limits,
+                enclave_client_args.build_environment_image,
+                run_id,
+            )
+            .map_err(|e| JobFailure::new(ErrorKind::RunnerFailed, format!("{:#}", e)))?;
             task::spawn(async move {
                 if let Err(e) = runner_manager
                     .run(
                         enclave_client_args.runner_args,
                         runner_message_tx,
                         enclave_client_args.runner_start_mode,
+                        cancel_rx,
                     )
                     .await
                 {
                     error!("Error running the runner: {:?}", e);
+                    let detail = format!("{:#}", e);
+                    let _ = failure_tx.send(RunnerMessage::Failed { detail }).await;
                 }
             })
         }
     };
 
-    while let Some(runner_message) = runner_message_rx.recv().await {
+    // let the host know that we are alive, as builds can run for a long time without any output
+    let send_heartbeats = capabilities.contains(&Capability::Heartbeat);
+    let mut heartbeat = time::interval(HEARTBEAT_INTERVAL);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        *stage = enclave_state.stage();
+        let runner_message = tokio::select! {
+            _ = heartbeat.tick(), if send_heartbeats => {
+                let message = Message::EnclaveToHost(EnclaveToHostMessage::Heartbeat { stage: *stage });
+                writer.write_message(&message).await?;
+                continue;
+            }
+            runner_message = runner_message_rx.recv() => {
+                runner_message.ok_or_else(|| JobFailure::new(
+                    ErrorKind::RunnerFailed,
+                    "the runner exited before the build finished".to_string(),
+                ))?
+            }
+            message = next_host_message(host_messages) => {
+                let Message::HostToEnclave(HostToEnclaveMessage::Cancel { reason }) = message? else {
+                    return Err(JobFailure::new(
+                        ErrorKind::UnexpectedMessage,
+                        "only a cancellation is expected while the job runs".to_string(),
+                    )
+                    .into());
+                };
+                info!("The host cancelled the job: {}", reason);
+                let _ = cancel_tx.send(());
+
+                // stop listening so that the runner manager cannot block on a full channel
+                drop(runner_message_rx);
+                runner_manager_join_handle.await?;
+
+                let message = Message::EnclaveToHost(EnclaveToHostMessage::Cancelled);
+                writer.write_message(&message).await?;
+                return Ok(());
+            }
+        };
+
         match runner_message {
             RunnerMessage::ConfigurationComplete => {
                 enclave_state = enclave_state.on_configured();
 
                 let message = Message::EnclaveToHost(EnclaveToHostMessage::Ok { info: None });
-                protocol::write_message(&mut stream, &message).await?;
+                writer.write_message(&message).await?;
             }
 
-            RunnerMessage::CommitHash { commit_hash } => {
-                enclave_state = enclave_state.on_received_commit_hash(commit_hash.clone());
-
+            RunnerMessage::CheckoutMeasured { checkout } => {
                 let message = Message::EnclaveToHost(EnclaveToHostMessage::ReportRepositoryRoot {
-                    commit_hash,
+                    commit_hash: checkout.commit_hash.clone(),
+                    source_tree_digest: checkout.source_tree_digest.clone(),
                 });
-                protocol::write_message(&mut stream, &message).await?;
+                enclave_state = enclave_state.on_measured_checkout(checkout);
+                writer.write_message(&message).await?;
             }
 
             RunnerMessage::ArtifactNameAndHash {
                 artifact_name,
                 artifact_hash,
-                local_input_log_path,
             } => {
-                enclave_state = enclave_state.on_received_artifact(
-                    artifact_name.clone(),
-                    artifact_hash.clone(),
-                    local_input_log_path.clone(),
-                );
+                enclave_state = enclave_state.on_received_artifact(Artifact {
+                    name: artifact_name.clone(),
+                    hash: artifact_hash.clone(),
+                });
 
                 let message = Message::EnclaveToHost(EnclaveToHostMessage::ReportArtifact {
                     artifact_name,
                     artifact_hash,
                 });
-                protocol::write_message(&mut stream, &message).await?;
+                writer.write_message(&message).await?;
+            }
+
+            RunnerMessage::BuildComplete {
+                local_input_log_path,
+            } => {
+                enclave_state = enclave_state.on_build_complete(local_input_log_path);
             }
 
             RunnerMessage::LogMessage { message } => {
+                if !capabilities.contains(&Capability::Log) {
+                    continue;
+                }
                 let message = Message::EnclaveToHost(EnclaveToHostMessage::Log { message });
-                protocol::write_message(&mut stream, &message).await?;
+                writer.write_message(&message).await?;
             }
 
             RunnerMessage::TimestampMessage { marker, datetime } => {
+                if !capabilities.contains(&Capability::Timestamp) {
+                    continue;
+                }
                 let message =
                     Message::EnclaveToHost(EnclaveToHostMessage::Timestamp { marker, datetime });
-                protocol::write_message(&mut stream, &message).await?;
+                writer.write_message(&message).await?;
+            }
+
+            RunnerMessage::Failed { detail } => {
+                return Err(JobFailure::new(ErrorKind::RunnerFailed, detail).into());
+            }
+
+            RunnerMessage::ResourceLimitExceeded { detail } => {
+                return Err(JobFailure::new(ErrorKind::ResourceLimitExceeded, detail).into());
             }
         }
 
         match enclave_state {
             EnclaveState::BuildFinished {
-                commit_hash,
-                artifact_name,
-                artifact_hash,
+                checkout,
+                artifacts,
                 local_input_log_path,
             } => {
+                info!("Attesting {} artifact(s)", artifacts.len());
                 let attestation_document = attestation::perform_attestation(
-                    enclave_client_args.use_fake_attestation,
-                    commit_hash.clone(),
-                    artifact_name.clone(),
-                    artifact_hash.clone(),
+                    attestation_provider,
+                    &checkout,
+                    &artifacts,
+                    &job,
+                    &attestation_nonce,
                 )
-                .await?;
+                .await
+                .map_err(|e| JobFailure::new(ErrorKind::AttestationFailed, format!("{:#}", e)))?;
                 let message = Message::EnclaveToHost(EnclaveToHostMessage::ReportAttestation {
                     attestation_document: attestation_document.clone(),
                 });
-                protocol::write_message(&mut stream, &message).await?;
+                writer.write_message(&message).await?;
 
                 // TODO: handle the writing back a bit more elegantly..
                 debug!(
@@ -232,15 +518,91 @@ async fn main() -> anyhow::Result<()> {
                 break;
             }
             EnclaveState::Error => {
-                anyhow::bail!("Enclave client state machine yields EnclaveState::Error");
+                return Err(JobFailure::new(
+                    ErrorKind::InvalidStateTransition,
+                    "Enclave client state machine yields EnclaveState::Error".to_string(),
+                )
+                .into());
             }
             _ => {}
         }
     }
 
     runner_manager_join_handle.await?;
-    short_wait().await; // some time to take down the vsock connection
-
-    info!("Enclave client finished");
     Ok(())
 }
+
+async fn next_host_message(
+    host_messages: &mut mpsc::Receiver<Result<Message, ProtocolError>>,
+) -> anyhow::Result<Message> {
+    let message = host_messages
+        .recv()
+        .await
+        .context("The connection to the host is closed")?
+        .context("Failed to read the next message from the host")?;
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifact(name: &str) -> Artifact {
+        Artifact {
+            name: name.to_string(),
+            hash: "hash".to_string(),
+        }
+    }
+
+    fn building() -> EnclaveState {
+        EnclaveState::new()
+            .on_start_message()
+            .on_configured()
+            .on_measured_checkout(MeasuredCheckout {
+                commit_hash: "commit".to_string(),
+                source_tree_digest: "tree".to_string(),
+                build_environment: Default::default(),
+                fetched_inputs: vec![],
+                workflow_ref: None,
+            })
+    }
+
+    #[test]
+    fn test_collects_artifacts_until_build_complete() {
+        let state = building()
+            .on_received_artifact(artifact("app"))
+            .on_received_artifact(artifact("app.sha256"));
+        assert_eq!(state.stage(), EnclaveStage::Building);
+
+        let EnclaveState::BuildFinished {
+            checkout,
+            artifacts,
+            ..
+        } = state.on_build_complete(PathBuf::from("input.log"))
+        else {
+            panic!("expected the build to be finished");
+        };
+        assert_eq!(checkout.commit_hash, "commit");
+        assert_eq!(artifacts, vec![artifact("app"), artifact("app.sha256")]);
+    }
+
+    #[test]
+    fn test_rejects_invalid_artifact_reports() {
+        // the build must report at least one artifact
+        let state = building().on_build_complete(PathBuf::from("input.log"));
+        assert_eq!(state.stage(), EnclaveStage::Failed);
+
+        // names must be unique
+        let state = building()
+            .on_received_artifact(artifact("app"))
+            .on_received_artifact(artifact("app"));
+        assert_eq!(state.stage(), EnclaveStage::Failed);
+
+        // and nothing can be added after the build is complete
+        let state = building()
+            .on_received_artifact(artifact("app"))
+            .on_build_complete(PathBuf::from("input.log"))
+            .on_received_artifact(artifact("late"));
+        assert_eq!(state.stage(), EnclaveStage::Failed);
+    }
+}
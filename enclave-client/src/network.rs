@@ -0,0 +1,183 @@
+use anyhow::Context;
+use std::net::Ipv4Addr;
+use std::process::Command;
+use tracing::{debug, warn};
+
+/// The network namespace that connects the sandbox to the enclave (see
+/// `enclave-container/content/setup.sh`, or `scripts/setup-local-net-ns.sh` for local runs).
+/// Jobs get a namespace of their own behind it, for which it routes (and masquerades) the traffic.
+const SANDBOX_NAMESPACE: &str = "netns-sandbox";
+
+/// The addresses of the jobs: a `/30` each, with the link in `SANDBOX_NAMESPACE` on the first and
+/// the job on the second address.
+const JOBS_NETWORK: Ipv4Addr = Ipv4Addr::new(10, 200, 0, 0);
+const JOBS_PREFIX_LEN: u32 = 16;
+
+/// The links of the jobs in `SANDBOX_NAMESPACE` are named `<prefix><slot>`. Creating a link
+/// fails if its name is taken, which is what hands out the slots (and addresses) to the jobs.
+const LINK_PREFIX: &str = "sbx";
+
+/// The network of a sandboxed job: a network namespace named after its container, connected to
+/// `SANDBOX_NAMESPACE` by a veth pair of its own. Removes the namespace once dropped.
+#[derive(Debug)]
+pub(crate) struct JobNetwork {
+    namespace: String,
+    link: String,
+}
+
+impl JobNetwork {
+    pub(crate) fn create(namespace: &str) -> anyhow::Result<Self> {
+        ensure_routing().context("Failed to route the traffic of the jobs")?;
+        ip(&["netns", "add", namespace])?;
+        // from here on, dropping the network removes the namespace again
+        let mut network = JobNetwork {
+            namespace: namespace.to_string(),
+            link: String::new(),
+        };
+
+        let mut slots = 0..job_slots();
+        let slot = loop {
+            let slot = slots
+                .next()
+                .context("No free slot for the network of the job")?;
+            let link = format!("{}{}", LINK_PREFIX, slot);
+            let args = [
+                "-n",
+                SANDBOX_NAMESPACE,
+                "link",
+                "add",
+                &link,
+                "type",
+                "veth",
+                "peer",
+                "name",
+                "eth0",
+                "netns",
+                namespace,
+            ];
+            match ip(&args) {
+                Ok(()) => break slot,
+                // taken by another job
+                Err(e) if e.to_string().contains("File exists") => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        network.link = format!("{}{}", LINK_PREFIX, slot);
+
+        let (gateway, address) = slot_addresses(slot);
+        let gateway_cidr = format!("{}/30", gateway);
+        let address_cidr = format!("{}/30", address);
+        let gateway = gateway.to_string();
+        let link = network.link.as_str();
+        ip(&[
+            "-n",
+            SANDBOX_NAMESPACE,
+            "addr",
+            "add",
+            &gateway_cidr,
+            "dev",
+            link,
+        ])?;
+        ip(&["-n", SANDBOX_NAMESPACE, "link", "set", link, "up"])?;
+        ip(&["-n", namespace, "addr", "add", &address_cidr, "dev", "eth0"])?;
+        ip(&["-n", namespace, "link", "set", "lo", "up"])?;
+        ip(&["-n", namespace, "link", "set", "eth0", "up"])?;
+        ip(&["-n", namespace, "route", "add", "default", "via", &gateway])?;
+        debug!(
+            "Set up the network namespace {} at {} (via {})",
+            namespace, address_cidr, network.link
+        );
+        Ok(network)
+    }
+
+    /// The path of the namespace, for the `config.json` of the container.
+    pub(crate) fn namespace_path(&self) -> String {
+        format!("/var/run/netns/{}", self.namespace)
+    }
+
+    /// The link whose removal cuts off the job, see `cut_off`.
+    pub(crate) fn link(&self) -> &str {
+        &self.link
+    }
+}
+
+impl Drop for JobNetwork {
+    fn drop(&mut self) {
+        // the veth pair goes with the namespace, but a cut off job has none left
+        if let Err(e) = ip(&["netns", "delete", &self.namespace]) {
+            warn!(
+                "Failed to remove the network namespace {}: {:?}",
+                self.namespace, e
+            );
+        }
+    }
+}
+
+/// Cuts the job with the given link off the network, e.g. for the build steps of a hermetic build:
+/// the veth pair is removed, so that only the loopback device is left in its namespace. Nothing
+/// brings it back, and other jobs have links of their own.
+pub(crate) fn cut_off(link: &str) -> anyhow::Result<()> {
+    ip(&["-n", SANDBOX_NAMESPACE, "link", "delete", link])
+        .with_context(|| format!("Failed to cut off the network of the job at {}", link))?;
+    debug!("Cut off the network access of the job at {}", link);
+    Ok(())
+}
+
+/// Lets `SANDBOX_NAMESPACE` forward the traffic of the jobs under its own address, so that the
+/// rest of the network setup (NAT or the proxies of the enclave) stays as it is.
+fn ensure_routing() -> anyhow::Result<()> {
+    netns_exec(&["sysctl", "-qw", "net.ipv4.ip_forward=1"])?;
+    let source = format!("{}/{}", JOBS_NETWORK, JOBS_PREFIX_LEN);
+    let rule = ["POSTROUTING", "-s", &source, "-j", "MASQUERADE"];
+    let check = [&["iptables", "-t", "nat", "-C"], &rule[..]].concat();
+    if netns_exec(&check).is_err() {
+        netns_exec(&[&["iptables", "-t", "nat", "-A"], &rule[..]].concat())?;
+    }
+    Ok(())
+}
+
+fn job_slots() -> u32 {
+    1 << (32 - JOBS_PREFIX_LEN - 2)
+}
+
+/// The address of the link in `SANDBOX_NAMESPACE` (the gateway of the job) and of the job.
+fn slot_addresses(slot: u32) -> (Ipv4Addr, Ipv4Addr) {
+    let base = u32::from(JOBS_NETWORK) + slot * 4;
+    (Ipv4Addr::from(base + 1), Ipv4Addr::from(base + 2))
+}
+
+fn netns_exec(args: &[&str]) -> anyhow::Result<()> {
+    ip(&[&["netns", "exec", SANDBOX_NAMESPACE], args].concat())
+}
+
+fn ip(args: &[&str]) -> anyhow::Result<()> {
+    let output = Command::new("ip").args(args).output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "ip {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slot_addresses() {
+        assert_eq!(
+            slot_addresses(0),
+            (Ipv4Addr::new(10, 200, 0, 1), Ipv4Addr::new(10, 200, 0, 2))
+        );
+        assert_eq!(
+            slot_addresses(65),
+            (Ipv4Addr::new(10, 200, 1, 5), Ipv4Addr::new(10, 200, 1, 6))
+        );
+        // the last slot stays in the network of the jobs
+        let (_, last) = slot_addresses(job_slots() - 1);
+        assert_eq!(last, Ipv4Addr::new(10, 200, 255, 254));
+    }
+}
@@ -0,0 +1,326 @@
+use anyhow::Context;
+use aws_nitro_enclaves_cose::crypto::Openssl;
+use aws_nitro_enclaves_cose::header_map::HeaderMap;
+use aws_nitro_enclaves_cose::CoseSign1;
+use nsm_io::{AttestationDoc, Digest, ErrorCode, Request, Response};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::{BasicConstraints, KeyUsage};
+use openssl::x509::{X509NameBuilder, X509};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+/// Nitro enclaves have 16 SHA-384 PCRs (32 in newer versions, but we only report the first 16).
+const PCR_COUNT: usize = 16;
+const PCR_LEN: usize = 48;
+
+/// The limits of the NSM for `user_data` and `nonce`, and for `public_key`.
+const MAX_USER_DATA_LEN: usize = 512;
+const MAX_PUBLIC_KEY_LEN: usize = 1024;
+
+const ROOT_CERT_FILE: &str = "root.pem";
+const ROOT_KEY_FILE: &str = "root.key";
+const ROOT_VALIDITY_DAYS: u32 = 3650;
+const LEAF_VALIDITY_DAYS: u32 = 365;
+const MODULE_ID: &str = "i-emulated-enc0000000000000000";
+
+/// A software stand-in for the Nitro Secure Module, so that the real attestation path (and the
+/// verifier) can be exercised outside of Nitro enclaves. The documents have the same COSE/CBOR
+/// layout as the ones from the NSM, but they are signed by a local CA whose root certificate is
+/// stored next to its key in the emulator directory. Of course, they prove nothing about the
+/// code that runs, as anyone with access to that key can sign whatever they like.
+pub struct NsmEmulator {
+    pcrs: BTreeMap<usize, Vec<u8>>,
+    root_cert: X509,
+    certificate: X509,
+    signing_key: PKey<Private>,
+}
+
+impl NsmEmulator {
+    /// Loads the CA from `dir`, or creates it if there is none yet, and issues a fresh signing
+    /// certificate. PCRs that are not given are all zeros.
+    pub fn new(dir: &Path, pcrs: BTreeMap<usize, Vec<u8>>) -> anyhow::Result<NsmEmulator> {
+        for (index, value) in &pcrs {
+            if *index >= PCR_COUNT || value.len() != PCR_LEN {
+                anyhow::bail!(
+                    "PCR{} must be one of {} PCRs with {} bytes",
+                    index,
+                    PCR_COUNT,
+                    PCR_LEN
+                );
+            }
+        }
+        let pcrs = (0..PCR_COUNT)
+            .map(|index| {
+                let value = pcrs.get(&index).cloned().unwrap_or(vec![0; PCR_LEN]);
+                (index, value)
+            })
+            .collect();
+
+        let (root_cert, root_key) = load_or_create_root(dir)?;
+        let signing_key = new_key()?;
+        let certificate = new_cert(
+            "emulated-nsm",
+            &signing_key,
+            Some((&root_cert, &root_key)),
+            LEAF_VALIDITY_DAYS,
+        )?;
+        Ok(NsmEmulator {
+            pcrs,
+            root_cert,
+            certificate,
+            signing_key,
+        })
+    }
+
+    /// Handles the subset of requests that we use, like `nsm_driver::nsm_process_request`.
+    pub fn process_request(&self, request: Request) -> Response {
+        match request {
+            Request::DescribePCR { index } => match self.pcrs.get(&(index as usize)) {
+                Some(data) => Response::DescribePCR {
+                    lock: true,
+                    data: data.clone(),
+                },
+                None => Response::Error(ErrorCode::InvalidIndex),
+            },
+            Request::Attestation {
+                user_data,
+                nonce,
+                public_key,
+            } => {
+                let len = |field: &Option<ByteBuf>| field.as_ref().map_or(0, |f| f.len());
+                if len(&user_data) > MAX_USER_DATA_LEN
+                    || len(&nonce) > MAX_USER_DATA_LEN
+                    || len(&public_key) > MAX_PUBLIC_KEY_LEN
+                {
+                    return Response::Error(ErrorCode::InputTooLarge);
+                }
+                match self.attest(
+                    user_data.map(|b| b.into_vec()),
+                    nonce.map(|b| b.into_vec()),
+                    public_key.map(|b| b.into_vec()),
+                ) {
+                    Ok(document) => Response::Attestation { document },
+                    Err(e) => {
+                        debug!("The emulated attestation failed: {:?}", e);
+                        Response::Error(ErrorCode::InternalError)
+                    }
+                }
+            }
+            _ => Response::Error(ErrorCode::InvalidOperation),
+        }
+    }
+
+    fn attest(
+        &self,
+        user_data: Option<Vec<u8>>,
+        nonce: Option<Vec<u8>>,
+        public_key: Option<Vec<u8>>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let attestation_doc = AttestationDoc::new(
+            MODULE_ID.to_string(),
+            Digest::SHA384,
+            timestamp,
+            self.pcrs.clone(),
+            self.certificate.to_der()?,
+            vec![self.root_cert.to_der()?],
+            user_data,
+            nonce,
+            public_key,
+        );
+        let cose_sign_1 = CoseSign1::new::<Openssl>(
+            &attestation_doc.to_binary(),
+            &HeaderMap::new(),
+            &self.signing_key,
+        )
+        .map_err(|e| anyhow::anyhow!("Failed to sign the attestation document: {:?}", e))?;
+        cose_sign_1
+            .as_bytes(false)
+            .map_err(|e| anyhow::anyhow!("Failed to encode the attestation document: {:?}", e))
+    }
+}
+
+/// Parses a PCR given as `INDEX=HEX`.
+pub fn parse_pcr(s: &str) -> anyhow::Result<(usize, Vec<u8>)> {
+    let (index, value) = s
+        .split_once('=')
+        .with_context(|| format!("Expected INDEX=HEX but got: {}", s))?;
+    Ok((index.parse()?, hex::decode(value)?))
+}
+
+fn load_or_create_root(dir: &Path) -> anyhow::Result<(X509, PKey<Private>)> {
+    let cert_path = dir.join(ROOT_CERT_FILE);
+    let key_path = dir.join(ROOT_KEY_FILE);
+    if cert_path.exists() && key_path.exists() {
+        debug!("Loading the emulated NSM root from {:?}", dir);
+        let root_cert = X509::from_pem(&std::fs::read(&cert_path)?)
+            .context("Failed to parse the emulated NSM root certificate")?;
+        let root_key = PKey::private_key_from_pem(&std::fs::read(&key_path)?)
+            .context("Failed to parse the emulated NSM root key")?;
+        return Ok((root_cert, root_key));
+    }
+
+    let root_key = new_key()?;
+    let root_cert = new_cert("emulated-nsm-root", &root_key, None, ROOT_VALIDITY_DAYS)?;
+    std::fs::create_dir_all(dir)?;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&key_path)?
+        .write_all(&root_key.private_key_to_pem_pkcs8()?)?;
+    std::fs::write(&cert_path, root_cert.to_pem()?)?;
+    info!("Created a new emulated NSM root in {:?}", cert_path);
+    Ok((root_cert, root_key))
+}
+
+fn new_key() -> anyhow::Result<PKey<Private>> {
+    let group = EcGroup::from_curve_name(Nid::SECP384R1)?;
+    Ok(PKey::from_ec_key(EcKey::generate(&group)?)?)
+}
+
+/// Creates a certificate for `key`. Without an issuer, this is a self-signed CA.
+fn new_cert(
+    name: &str,
+    key: &PKey<Private>,
+    issuer: Option<(&X509, &PKey<Private>)>,
+    validity_days: u32,
+) -> anyhow::Result<X509> {
+    let mut subject = X509NameBuilder::new()?;
+    subject.append_entry_by_text("CN", name)?;
+    let subject = subject.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(validity_days)?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&subject)?;
+    builder.set_pubkey(key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    let (issuer_name, signing_key) = match issuer {
+        Some((issuer_cert, issuer_key)) => {
+            builder.append_extension(KeyUsage::new().critical().digital_signature().build()?)?;
+            (issuer_cert.subject_name(), issuer_key)
+        }
+        None => {
+            builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+            builder.append_extension(KeyUsage::new().critical().key_cert_sign().build()?)?;
+            (subject.as_ref(), key)
+        }
+    };
+    builder.set_issuer_name(issuer_name)?;
+    builder.sign(signing_key, MessageDigest::sha384())?;
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::stack::Stack;
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::X509StoreContext;
+
+    #[test]
+    fn test_attestation_chains_up_to_the_root() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let pcrs = BTreeMap::from([(0, vec![0xab; PCR_LEN])]);
+        let emulator = NsmEmulator::new(dir, pcrs).unwrap();
+
+        let response = emulator.process_request(Request::Attestation {
+            user_data: Some(ByteBuf::from(b"user data".to_vec())),
+            nonce: Some(ByteBuf::from(b"nonce".to_vec())),
+            public_key: None,
+        });
+        let Response::Attestation { document } = response else {
+            panic!("unexpected response: {:?}", response);
+        };
+
+        // the same checks as for documents from the NSM
+        let cose_sign_1 = CoseSign1::from_bytes(&document).unwrap();
+        let payload = cose_sign_1.get_payload::<Openssl>(None).unwrap();
+        let attestation_doc = AttestationDoc::from_binary(&payload).unwrap();
+        let certificate = X509::from_der(&attestation_doc.certificate).unwrap();
+        assert!(cose_sign_1
+            .verify_signature::<Openssl>(certificate.public_key().unwrap().as_ref())
+            .unwrap());
+        assert_eq!(attestation_doc.pcrs.len(), PCR_COUNT);
+        assert_eq!(attestation_doc.pcrs[&0].to_vec(), vec![0xab; PCR_LEN]);
+        assert_eq!(attestation_doc.pcrs[&1].to_vec(), vec![0; PCR_LEN]);
+        assert_eq!(
+            attestation_doc.user_data.unwrap().to_vec(),
+            b"user data".to_vec()
+        );
+        assert_eq!(attestation_doc.nonce.unwrap().to_vec(), b"nonce".to_vec());
+
+        let root_cert = X509::from_pem(&std::fs::read(dir.join(ROOT_CERT_FILE)).unwrap()).unwrap();
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(root_cert).unwrap();
+        let store = store.build();
+        let mut intermediates = Stack::new().unwrap();
+        for cert in &attestation_doc.cabundle {
+            intermediates.push(X509::from_der(cert).unwrap()).unwrap();
+        }
+        let mut context = X509StoreContext::new().unwrap();
+        let valid = context
+            .init(&store, &certificate, &intermediates, |c| c.verify_cert())
+            .unwrap();
+        assert!(valid);
+    }
+
+    #[test]
+    fn test_reuses_the_root() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let first = NsmEmulator::new(dir, BTreeMap::new()).unwrap();
+        let second = NsmEmulator::new(dir, BTreeMap::new()).unwrap();
+        assert_eq!(
+            first.root_cert.to_der().unwrap(),
+            second.root_cert.to_der().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_requests() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        assert!(NsmEmulator::new(dir, BTreeMap::from([(0, vec![0; 32])])).is_err());
+
+        let emulator = NsmEmulator::new(dir, BTreeMap::new()).unwrap();
+        let response = emulator.process_request(Request::DescribePCR { index: 99 });
+        assert!(matches!(response, Response::Error(ErrorCode::InvalidIndex)));
+        let response = emulator.process_request(Request::Attestation {
+            user_data: Some(ByteBuf::from(vec![0; MAX_USER_DATA_LEN + 1])),
+            nonce: None,
+            public_key: None,
+        });
+        assert!(matches!(
+            response,
+            Response::Error(ErrorCode::InputTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_parse_pcr() {
+        assert_eq!(parse_pcr("3=abcd").unwrap(), (3, vec![0xab, 0xcd]));
+        assert!(parse_pcr("abcd").is_err());
+        assert!(parse_pcr("x=abcd").is_err());
+    }
+}
@@ -0,0 +1,699 @@
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use tracing::debug;
+
+/// The prefix of the whiteout files, which delete a file of the layers below.
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// The whiteout file that hides everything in its directory from the layers below.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// The image manifests that we can unpack (OCI and the Docker ones that `skopeo` passes on).
+const MANIFEST_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+/// The build environment images that the enclave client unpacks, by the digest of their
+/// manifest. The file is part of the enclave image and its digest goes into the attestation, so
+/// that the host can only pick among images that the verifier can look up.
+#[derive(Debug)]
+pub(crate) struct ImageAllowlist {
+    digests: BTreeSet<String>,
+    /// The SHA-256 (hex) of the file.
+    pub(crate) digest: String,
+}
+
+impl ImageAllowlist {
+    pub(crate) fn read(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read(path)
+            .with_context(|| format!("Failed to read the image allowlist {:?}", path))?;
+        Self::parse(&content)
+    }
+
+    /// One `sha256:<hex>` digest per line, `#` starts a comment.
+    fn parse(content: &[u8]) -> anyhow::Result<Self> {
+        let mut digests = BTreeSet::new();
+        for line in std::str::from_utf8(content)?.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            common::validate_image_digest(line)?;
+            digests.insert(line.to_string());
+        }
+        Ok(Self {
+            digests,
+            digest: format!("{:x}", Sha256::digest(content)),
+        })
+    }
+
+    pub(crate) fn ensure_allowed(&self, image_digest: &str) -> anyhow::Result<()> {
+        if !self.digests.contains(image_digest) {
+            bail!(
+                "The build environment image {} is not on the allowlist",
+                image_digest
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: String,
+    digest: String,
+}
+
+/// A member of a layer, as listed by `tar`.
+#[derive(Debug, PartialEq)]
+struct Member {
+    /// The type, as `tar` lists it in front of the mode (`l` for symlinks, `h` for hard links).
+    kind: char,
+    /// The path in the layer, without `.` components (empty for the layer's root).
+    path: PathBuf,
+    /// The target of a symlink (as is) or hard link (a path in the layer).
+    target: Option<PathBuf>,
+}
+
+/// Unpacks the image with the manifest `digest` from an OCI image layout tarball into `rootfs`:
+/// the layout is extracted into `layout_dir`, every blob is checked against its digest and the
+/// layers are applied in order, whiteouts included. Only allowlisted images get here, which is
+/// why `tar` may extract them as root.
+pub(crate) fn unpack_image(
+    tarball: &Path,
+    digest: &str,
+    layout_dir: &Path,
+    rootfs: &Path,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(layout_dir)?;
+    std::fs::create_dir_all(rootfs)?;
+    tar(&[
+        "-x".as_ref(),
+        "-f".as_ref(),
+        tarball.as_os_str(),
+        "-C".as_ref(),
+        layout_dir.as_os_str(),
+        "--no-same-owner".as_ref(),
+    ])
+    .with_context(|| format!("Failed to extract the image layout {:?}", tarball))?;
+
+    let index: Index = serde_json::from_slice(&std::fs::read(layout_dir.join("index.json"))?)
+        .context("Invalid index.json")?;
+    let descriptor = index
+        .manifests
+        .iter()
+        .find(|descriptor| descriptor.digest == digest)
+        .with_context(|| format!("The image layout has no manifest {}", digest))?;
+    if !MANIFEST_MEDIA_TYPES.contains(&descriptor.media_type.as_str()) {
+        bail!(
+            "Unsupported manifest media type {:?}",
+            descriptor.media_type
+        );
+    }
+
+    let manifest_path = blob_path(layout_dir, digest)?;
+    verify_blob(&manifest_path, digest)?;
+    let manifest: Manifest =
+        serde_json::from_slice(&std::fs::read(&manifest_path)?).context("Invalid manifest")?;
+    for layer in &manifest.layers {
+        let layer_path = blob_path(layout_dir, &layer.digest)?;
+        verify_blob(&layer_path, &layer.digest)?;
+        apply_layer(&layer_path, &layer.media_type, rootfs)
+            .with_context(|| format!("Failed to apply the layer {}", layer.digest))?;
+        debug!("Applied the layer {} to {:?}", layer.digest, rootfs);
+    }
+    Ok(())
+}
+
+/// `blobs/<algorithm>/<hex>`, for (valid) SHA-256 digests only.
+fn blob_path(layout_dir: &Path, digest: &str) -> anyhow::Result<PathBuf> {
+    common::validate_image_digest(digest)?;
+    Ok(layout_dir
+        .join("blobs/sha256")
+        .join(&digest["sha256:".len()..]))
+}
+
+fn verify_blob(path: &Path, digest: &str) -> anyhow::Result<()> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("Missing blob {}", digest))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    let actual = format!("sha256:{:x}", hasher.finalize());
+    if actual != digest {
+        bail!("The blob {} has the digest {}", digest, actual);
+    }
+    Ok(())
+}
+
+/// Applies a layer: its whiteouts delete what the layers below put into `rootfs`, then
+/// everything else is extracted on top (as is, with the numeric owners of the image). `tar`
+/// follows the symlinks that are already there, so members that would be written through one
+/// leading out of `rootfs` (or through a symlink of the same layer) reject the layer up front.
+fn apply_layer(layer: &Path, media_type: &str, rootfs: &Path) -> anyhow::Result<()> {
+    let compression = match media_type {
+        "application/vnd.oci.image.layer.v1.tar" => None,
+        "application/vnd.oci.image.layer.v1.tar+gzip"
+        | "application/vnd.docker.image.rootfs.diff.tar.gzip" => Some("--gzip"),
+        "application/vnd.oci.image.layer.v1.tar+zstd" => Some("--zstd"),
+        _ => bail!("Unsupported layer media type {:?}", media_type),
+    };
+
+    let mut list: Vec<&OsStr> = vec!["-t".as_ref(), "-v".as_ref(), "-f".as_ref()];
+    list.push(layer.as_os_str());
+    list.extend(compression.map(OsStr::new));
+    list.extend(["--numeric-owner", "--quoting-style=c"].map(OsStr::new));
+    let members = tar(&list)?
+        .lines()
+        .map(parse_member)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // the whiteouts may remove symlinks that the members are checked against below
+    for member in &members {
+        let name = member.path.file_name().unwrap_or_default();
+        if name.as_bytes().starts_with(WHITEOUT_PREFIX.as_bytes()) {
+            apply_whiteout(&member.path, rootfs)?;
+        }
+    }
+    let mut symlinks = HashSet::new();
+    for member in &members {
+        let mut paths = vec![&member.path];
+        // hard links are created through the symlinks in the path of their target as well
+        if member.kind == 'h' {
+            paths.extend(&member.target);
+        }
+        for path in paths {
+            let Some(parent) = path.parent() else {
+                continue;
+            };
+            if parent.ancestors().any(|dir| symlinks.contains(dir)) {
+                bail!("The member {:?} is below a symlink of the same layer", path);
+            }
+            ensure_in_rootfs(&rootfs.join(parent), rootfs)
+                .with_context(|| format!("Refusing to extract the member {:?}", path))?;
+        }
+        if member.kind == 'l' {
+            symlinks.insert(member.path.as_path());
+        }
+    }
+
+    let mut extract: Vec<&OsStr> = vec!["-x".as_ref(), "-f".as_ref(), layer.as_os_str()];
+    extract.extend(compression.map(OsStr::new));
+    extract.extend([
+        "-C".as_ref(),
+        rootfs.as_os_str(),
+        "--numeric-owner".as_ref(),
+        "--exclude=.wh.*".as_ref(),
+    ]);
+    tar(&extract)?;
+    Ok(())
+}
+
+/// Deletes what the whiteout `entry` (a path in the layer) hides, refusing to leave `rootfs`.
+fn apply_whiteout(entry: &Path, rootfs: &Path) -> anyhow::Result<()> {
+    let (Some(parent), Some(name)) = (entry.parent(), entry.file_name()) else {
+        bail!("Invalid whiteout {:?}", entry);
+    };
+    if !parent
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("Invalid whiteout {:?}", entry);
+    }
+    let local_parent = rootfs.join(parent);
+    if !local_parent.is_dir() {
+        return Ok(());
+    }
+    // symlinks in the image must not take us out of it
+    ensure_in_rootfs(&local_parent, rootfs)
+        .with_context(|| format!("Refusing to apply the whiteout {:?}", entry))?;
+
+    let name = name.to_string_lossy();
+    if name == OPAQUE_WHITEOUT {
+        for dir_entry in std::fs::read_dir(&local_parent)? {
+            remove_path(&dir_entry?.path())?;
+        }
+    } else {
+        let hidden = &name[WHITEOUT_PREFIX.len()..];
+        if hidden.is_empty() || hidden == "." || hidden == ".." {
+            bail!("Invalid whiteout {:?}", entry);
+        }
+        remove_path(&local_parent.join(hidden))?;
+    }
+    Ok(())
+}
+
+/// Fails if `path` (in `rootfs`) leads out of `rootfs` once the symlinks in the part of it that
+/// exists are resolved. Dangling symlinks fail as well.
+fn ensure_in_rootfs(path: &Path, rootfs: &Path) -> anyhow::Result<()> {
+    let mut existing = path;
+    loop {
+        match std::fs::symlink_metadata(existing) {
+            Ok(_) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                existing = existing.parent().context("The rootfs is missing")?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let resolved = existing
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {:?}", existing))?;
+    if !resolved.starts_with(rootfs.canonicalize()?) {
+        bail!("{:?} points outside of the rootfs", path);
+    }
+    Ok(())
+}
+
+/// Parses a line of `tar -t -v --numeric-owner --quoting-style=c`, e.g.
+/// `hrw-r--r-- 0/0 0 2024-01-01 12:00 "./b" link to "./a"`.
+fn parse_member(line: &str) -> anyhow::Result<Member> {
+    let invalid = || anyhow!("Unexpected member in the layer: {:?}", line);
+    let kind = line.chars().next().ok_or_else(invalid)?;
+    // the mode, the owner, the size (or device numbers), the date and the time
+    let mut rest = line;
+    for _ in 0..5 {
+        rest = rest.trim_start();
+        rest = &rest[rest.find(' ').ok_or_else(invalid)?..];
+    }
+    let (path, rest) = unquote(rest.trim_start()).ok_or_else(invalid)?;
+    let link = match kind {
+        'l' => Some(" -> "),
+        'h' => Some(" link to "),
+        _ => None,
+    };
+    let (target, rest) = match link.and_then(|link| rest.strip_prefix(link)) {
+        Some(rest) => {
+            let (target, rest) = unquote(rest).ok_or_else(invalid)?;
+            (Some(target), rest)
+        }
+        None => (None, rest),
+    };
+    if !rest.is_empty() {
+        return Err(invalid());
+    }
+    let path = member_path(&path)?;
+    if path.as_os_str().is_empty() && kind != 'd' {
+        return Err(invalid());
+    }
+    Ok(Member {
+        kind,
+        path,
+        // unlike the ones of hard links, the targets of symlinks may be absolute or contain `..`
+        target: match kind {
+            'h' => target.as_deref().map(member_path).transpose()?,
+            _ => target.map(|target| PathBuf::from(OsStr::from_bytes(&target))),
+        },
+    })
+}
+
+/// Reads a string in the C quoting style of `tar` from the start of `s` and returns its bytes and
+/// what follows it.
+fn unquote(s: &str) -> Option<(Vec<u8>, &str)> {
+    let s = s.strip_prefix('"')?;
+    let mut bytes = s.bytes().enumerate().peekable();
+    let mut unquoted = Vec::new();
+    while let Some((i, byte)) = bytes.next() {
+        match byte {
+            b'"' => return Some((unquoted, &s[i + 1..])),
+            b'\\' => {
+                let (_, escaped) = bytes.next()?;
+                unquoted.push(match escaped {
+                    b'a' => 0x07,
+                    b'b' => 0x08,
+                    b'f' => 0x0c,
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    b'v' => 0x0b,
+                    // up to three octal digits
+                    b'0'..=b'7' => {
+                        let mut value = u32::from(escaped - b'0');
+                        for _ in 0..2 {
+                            let Some((_, digit @ b'0'..=b'7')) = bytes.peek().copied() else {
+                                break;
+                            };
+                            value = value * 8 + u32::from(digit - b'0');
+                            bytes.next();
+                        }
+                        u8::try_from(value).ok()?
+                    }
+                    other => other,
+                });
+            }
+            byte => unquoted.push(byte),
+        }
+    }
+    None
+}
+
+/// The path of a member (or link target) relative to the root of the layer. `tar` refuses to
+/// extract absolute paths and `..` anyway, so layers with such members are rejected.
+fn member_path(name: &[u8]) -> anyhow::Result<PathBuf> {
+    let name = Path::new(OsStr::from_bytes(name));
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(component) => path.push(component),
+            Component::CurDir => {}
+            _ => bail!("Invalid member {:?} in the layer", name),
+        }
+    }
+    Ok(path)
+}
+
+fn remove_path(path: &Path) -> anyhow::Result<()> {
+    let result = match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e),
+    };
+    result.with_context(|| format!("Failed to remove {:?}", path))
+}
+
+fn tar(args: &[&OsStr]) -> anyhow::Result<String> {
+    let output = Command::new("tar").args(args).output()?;
+    if !output.status.success() {
+        bail!(
+            "tar {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a blob into the layout and returns its descriptor (as JSON) and digest.
+    fn add_blob(layout: &Path, media_type: &str, content: &[u8]) -> (serde_json::Value, String) {
+        let hex = format!("{:x}", Sha256::digest(content));
+        std::fs::create_dir_all(layout.join("blobs/sha256")).unwrap();
+        std::fs::write(layout.join("blobs/sha256").join(&hex), content).unwrap();
+        let digest = format!("sha256:{}", hex);
+        let descriptor = serde_json::json!({
+            "mediaType": media_type,
+            "digest": digest,
+            "size": content.len(),
+        });
+        (descriptor, digest)
+    }
+
+    enum Entry<'a> {
+        File(&'a str),
+        Dir,
+        Symlink(&'a Path),
+    }
+
+    /// Packs the entries of a layer with `tar`.
+    fn layer(dir: &Path, entries: &[(&str, Entry)]) -> Vec<u8> {
+        let content = dir.join("layer-content");
+        let _ = std::fs::remove_dir_all(&content);
+        std::fs::create_dir_all(&content).unwrap();
+        for (path, entry) in entries {
+            let path = content.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            match entry {
+                Entry::File(data) => std::fs::write(path, data).unwrap(),
+                Entry::Dir => std::fs::create_dir_all(path).unwrap(),
+                Entry::Symlink(target) => std::os::unix::fs::symlink(target, path).unwrap(),
+            }
+        }
+        let archive = dir.join("layer.tar.gz");
+        let status = Command::new("tar")
+            .arg("-czf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&content)
+            .arg(".")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        std::fs::read(archive).unwrap()
+    }
+
+    /// Writes an image layout with the layers (gzipped or not) and returns it as a tarball, with
+    /// the digest of its manifest.
+    fn image(dir: &Path, layers: &[Vec<u8>]) -> (PathBuf, String) {
+        let layout = dir.join("build-layout");
+        let layers: Vec<_> = layers
+            .iter()
+            .map(|layer| {
+                let media_type = match layer.starts_with(&[0x1f, 0x8b]) {
+                    true => "application/vnd.oci.image.layer.v1.tar+gzip",
+                    false => "application/vnd.oci.image.layer.v1.tar",
+                };
+                add_blob(&layout, media_type, layer).0
+            })
+            .collect();
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": MANIFEST_MEDIA_TYPES[0],
+            "config": layers[0],
+            "layers": layers,
+        });
+        let (manifest, digest) = add_blob(
+            &layout,
+            MANIFEST_MEDIA_TYPES[0],
+            manifest.to_string().as_bytes(),
+        );
+        let index = serde_json::json!({ "schemaVersion": 2, "manifests": [manifest] });
+        std::fs::write(layout.join("index.json"), index.to_string()).unwrap();
+
+        let tarball = dir.join("image.tar");
+        let status = Command::new("tar")
+            .arg("-cf")
+            .arg(&tarball)
+            .arg("-C")
+            .arg(&layout)
+            .arg(".")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        (tarball, digest)
+    }
+
+    /// An image with a base layer and one that deletes and replaces some of its files.
+    fn sample_image(dir: &Path) -> (PathBuf, String) {
+        let base = layer(
+            dir,
+            &[
+                ("app/entry.sh", Entry::File("run")),
+                ("usr/bin/cc", Entry::File("gcc")),
+                ("etc/old.conf", Entry::File("old")),
+                ("opt/tool/a", Entry::File("a")),
+            ],
+        );
+        let update = layer(
+            dir,
+            &[
+                ("usr/bin/cc", Entry::File("clang")),
+                ("etc/.wh.old.conf", Entry::File("")),
+                ("opt/tool/.wh..wh..opq", Entry::File("")),
+                ("opt/tool/b", Entry::File("b")),
+            ],
+        );
+        image(dir, &[base, update])
+    }
+
+    #[test]
+    fn test_unpack_image_applies_the_layers() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let (tarball, digest) = sample_image(dir);
+        let rootfs = dir.join("rootfs");
+        unpack_image(&tarball, &digest, &dir.join("layout"), &rootfs).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(rootfs.join("app/entry.sh")).unwrap(),
+            "run"
+        );
+        assert_eq!(
+            std::fs::read_to_string(rootfs.join("usr/bin/cc")).unwrap(),
+            "clang"
+        );
+        assert!(!rootfs.join("etc/old.conf").exists());
+        assert!(rootfs.join("etc").is_dir());
+        assert!(!rootfs.join("opt/tool/a").exists());
+        assert!(rootfs.join("opt/tool/b").exists());
+        assert!(!rootfs.join("opt/tool/.wh..wh..opq").exists());
+    }
+
+    #[test]
+    fn test_unpack_image_rejects_unknown_or_tampered_images() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let (tarball, digest) = sample_image(dir);
+        let other = format!("sha256:{}", "0".repeat(64));
+        assert!(unpack_image(&tarball, &other, &dir.join("a"), &dir.join("a-rootfs")).is_err());
+
+        // a layer that does not match the digest in the manifest
+        let layout = dir.join("build-layout");
+        for blob in std::fs::read_dir(layout.join("blobs/sha256")).unwrap() {
+            let blob = blob.unwrap().path();
+            if std::fs::read(&blob).unwrap().starts_with(&[0x1f, 0x8b]) {
+                std::fs::write(&blob, "tampered").unwrap();
+            }
+        }
+        let tarball = dir.join("tampered.tar");
+        let status = Command::new("tar")
+            .arg("-cf")
+            .arg(&tarball)
+            .arg("-C")
+            .arg(&layout)
+            .arg(".")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let error =
+            unpack_image(&tarball, &digest, &dir.join("b"), &dir.join("b-rootfs")).unwrap_err();
+        assert!(format!("{:#}", error).contains("has the digest"));
+    }
+
+    #[test]
+    fn test_whiteouts_stay_in_the_rootfs() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let rootfs = dir.join("rootfs");
+        std::fs::create_dir_all(rootfs.join("etc")).unwrap();
+        std::fs::write(dir.join("outside"), "").unwrap();
+        std::os::unix::fs::symlink(dir, rootfs.join("escape")).unwrap();
+
+        for whiteout in ["../.wh.outside", "escape/.wh.outside", "etc/.wh..."] {
+            assert!(apply_whiteout(Path::new(whiteout), &rootfs).is_err());
+        }
+        assert!(dir.join("outside").exists());
+        // nothing to hide
+        apply_whiteout(Path::new("./missing/.wh.file"), &rootfs).unwrap();
+    }
+
+    #[test]
+    fn test_layers_cannot_write_through_symlinks() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let outside = dir.join("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+
+        // a symlink out of the rootfs in one layer, and a file written through it in the next
+        let symlink = layer(dir, &[("escape", Entry::Symlink(&outside))]);
+        let write = layer(dir, &[("escape/pwned", Entry::File("pwned"))]);
+        let (tarball, digest) = image(dir, &[symlink, write]);
+        let error =
+            unpack_image(&tarball, &digest, &dir.join("a"), &dir.join("a-rootfs")).unwrap_err();
+        assert!(format!("{:#}", error).contains("outside of the rootfs"));
+        assert!(!outside.join("pwned").exists());
+
+        // both in the same layer
+        let content = dir.join("same-layer");
+        std::fs::create_dir_all(content.join("first")).unwrap();
+        std::os::unix::fs::symlink(&outside, content.join("first/escape")).unwrap();
+        std::fs::create_dir_all(content.join("second/escape")).unwrap();
+        std::fs::write(content.join("second/escape/pwned"), "pwned").unwrap();
+        let archive = dir.join("same-layer.tar");
+        for (mode, part) in [("-cf", "first"), ("-rf", "second")] {
+            let status = Command::new("tar")
+                .arg(mode)
+                .arg(&archive)
+                .arg("-C")
+                .arg(content.join(part))
+                .arg(".")
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        let (tarball, digest) = image(dir, &[std::fs::read(archive).unwrap()]);
+        let error =
+            unpack_image(&tarball, &digest, &dir.join("b"), &dir.join("b-rootfs")).unwrap_err();
+        assert!(format!("{:#}", error).contains("below a symlink"));
+        assert!(!outside.join("pwned").exists());
+
+        // symlinks within the rootfs still work
+        let base = layer(
+            dir,
+            &[
+                ("usr/lib", Entry::Dir),
+                ("lib", Entry::Symlink(Path::new("usr/lib"))),
+            ],
+        );
+        let update = layer(dir, &[("lib/libc.so", Entry::File("libc"))]);
+        let (tarball, digest) = image(dir, &[base, update]);
+        let rootfs = dir.join("c-rootfs");
+        unpack_image(&tarball, &digest, &dir.join("c"), &rootfs).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(rootfs.join("lib/libc.so")).unwrap(),
+            "libc"
+        );
+    }
+
+    #[test]
+    fn test_parse_member() {
+        assert_eq!(
+            parse_member(
+                r#"hrw-r--r-- 0/0    0 2024-01-01 12:00 "./d/a \"b\".txt" link to "./caf\303\251""#
+            )
+            .unwrap(),
+            Member {
+                kind: 'h',
+                path: PathBuf::from("d/a \"b\".txt"),
+                target: Some(PathBuf::from("café")),
+            }
+        );
+        assert_eq!(
+            parse_member(r#"lrwxrwxrwx 0/0 0 2024-01-01 12:00 "./l -> x" -> "/etc""#).unwrap(),
+            Member {
+                kind: 'l',
+                path: PathBuf::from("l -> x"),
+                target: Some(PathBuf::from("/etc")),
+            }
+        );
+        assert_eq!(
+            parse_member(r#"crw-r--r-- 0/0  1,3 2024-01-01 12:00 "./dev/null""#)
+                .unwrap()
+                .path,
+            PathBuf::from("dev/null")
+        );
+        assert!(parse_member(r#"-rw-r--r-- 0/0 1 2024-01-01 12:00 "../outside""#).is_err());
+        assert!(parse_member(r#"-rw-r--r-- 0/0 1 2024-01-01 12:00 "./a" trailing"#).is_err());
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let content = format!(
+            "# rust toolchain\nsha256:{}\n\nsha256:{} # node\n",
+            "a".repeat(64),
+            "b".repeat(64)
+        );
+        let allowlist = ImageAllowlist::parse(content.as_bytes()).unwrap();
+        allowlist
+            .ensure_allowed(&format!("sha256:{}", "b".repeat(64)))
+            .unwrap();
+        assert!(allowlist
+            .ensure_allowed(&format!("sha256:{}", "c".repeat(64)))
+            .is_err());
+        assert_eq!(
+            allowlist.digest,
+            format!("{:x}", Sha256::digest(content.as_bytes()))
+        );
+
+        assert!(ImageAllowlist::parse(b"ubuntu:24.04\n").is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::debug;
+
+/// The merged rootfs of the sandbox in its bundle directory.
+const MERGED_DIR: &str = "rootfs";
+
+/// The tmpfs with the upper (and work) directory of the overlay in the bundle directory.
+const OVERLAY_DIR: &str = "overlay";
+
+/// Mounts a fresh copy-on-write view of `lower` at `<bundle>/rootfs`: an overlayfs whose upper
+/// layer lives in a tmpfs, so that whatever the build writes is gone with the job and the shared
+/// lower rootfs is never modified. Returns the path of the merged rootfs.
+pub(crate) fn mount_rootfs_overlay(lower: &Path, bundle: &Path) -> anyhow::Result<PathBuf> {
+    let overlay = bundle.join(OVERLAY_DIR);
+    let merged = bundle.join(MERGED_DIR);
+    std::fs::create_dir_all(&overlay)?;
+    std::fs::create_dir_all(&merged)?;
+
+    mount(&["-t", "tmpfs", "tmpfs"], &overlay).context("Failed to mount the upper layer")?;
+    let upper = overlay.join("upper");
+    let work = overlay.join("work");
+    std::fs::create_dir_all(&upper)?;
+    std::fs::create_dir_all(&work)?;
+
+    let options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower.display(),
+        upper.display(),
+        work.display()
+    );
+    mount(&["-t", "overlay", "overlay", "-o", &options], &merged)
+        .context("Failed to mount the rootfs overlay")?;
+    debug!("Mounted the rootfs overlay of {:?} at {:?}", lower, merged);
+    Ok(merged)
+}
+
+/// Unmounts what `mount_rootfs_overlay` mounted in the bundle, ignoring what is not mounted.
+pub(crate) fn unmount_rootfs_overlay(bundle: &Path) -> anyhow::Result<()> {
+    for dir in [MERGED_DIR, OVERLAY_DIR] {
+        let path = bundle.join(dir);
+        if !is_mount_point(&path)? {
+            continue;
+        }
+        let output = Command::new("umount").arg(&path).output()?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "umount {:?} failed: {}",
+                path,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn mount(args: &[&str], target: &Path) -> anyhow::Result<()> {
+    let output = Command::new("mount").args(args).arg(target).output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "mount {:?} {:?} failed: {}",
+            args,
+            target,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Whether something is mounted at `path` according to `/proc/self/mountinfo`.
+fn is_mount_point(path: &Path) -> anyhow::Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let path = path.canonicalize()?;
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .any(|mount_point| Path::new(&unescape_mount_point(mount_point)) == path))
+}
+
+/// Mount points in `mountinfo` escape spaces, tabs, newlines and backslashes in octal.
+fn unescape_mount_point(s: &str) -> String {
+    s.replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
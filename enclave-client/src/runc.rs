@@ -1,4 +1,74 @@
+use common::ResourceLimits;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// The CFS period for the CPU quota of the sandbox.
+const CPU_PERIOD_US: u64 = 100_000;
+
+const EPERM: u32 = 1;
+const ENOSYS: u32 = 38;
+
+/// `CLONE_NEWUSER`, the only namespace flag that does not require `CAP_SYS_ADMIN`.
+const CLONE_NEWUSER: u64 = 0x1000_0000;
+
+/// The syscalls that the hardened seccomp profile denies (with `EPERM`): they change the kernel,
+/// the clock, mounts or namespaces, inspect other processes, or have been a frequent source of
+/// kernel exploits (`bpf`, `userfaultfd`, `keyctl`, ...). A build has no business calling them.
+const DENIED_SYSCALLS: [&str; 53] = [
+    "acct",
+    "add_key",
+    "bpf",
+    "clock_adjtime",
+    "clock_settime",
+    "create_module",
+    "delete_module",
+    "finit_module",
+    "fsconfig",
+    "fsmount",
+    "fsopen",
+    "fspick",
+    "get_kernel_syms",
+    "init_module",
+    "ioperm",
+    "iopl",
+    "kcmp",
+    "kexec_file_load",
+    "kexec_load",
+    "keyctl",
+    "lookup_dcookie",
+    "mount",
+    "mount_setattr",
+    "move_mount",
+    "name_to_handle_at",
+    "nfsservctl",
+    "open_by_handle_at",
+    "open_tree",
+    "perf_event_open",
+    "pivot_root",
+    "process_vm_readv",
+    "process_vm_writev",
+    "ptrace",
+    "query_module",
+    "quotactl",
+    "quotactl_fd",
+    "reboot",
+    "request_key",
+    "setns",
+    "settimeofday",
+    "stime",
+    "swapoff",
+    "swapon",
+    "_sysctl",
+    "syslog",
+    "umount",
+    "umount2",
+    "unshare",
+    "uselib",
+    "userfaultfd",
+    "ustat",
+    "vm86",
+    "vm86old",
+];
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct ConfigJson {
@@ -14,6 +84,9 @@ pub struct ConfigJson {
     mounts: Vec<Mount>,
     #[serde(rename = "linux")]
     linux: Linux,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -28,14 +101,66 @@ pub struct Process {
     env: Vec<String>,
     #[serde(rename = "cwd")]
     cwd: String,
+    #[serde(
+        rename = "capabilities",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    capabilities: Option<Capabilities>,
+    #[serde(rename = "rlimits", default, skip_serializing_if = "Option::is_none")]
+    rlimits: Option<Vec<Rlimit>>,
+    #[serde(
+        rename = "noNewPrivileges",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    no_new_privileges: Option<bool>,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
 }
 
+/// The capability sets of the process, e.g. `CAP_CHOWN`.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    #[serde(rename = "bounding", default, skip_serializing_if = "Option::is_none")]
+    bounding: Option<Vec<String>>,
+    #[serde(rename = "effective", default, skip_serializing_if = "Option::is_none")]
+    effective: Option<Vec<String>>,
+    #[serde(
+        rename = "inheritable",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    inheritable: Option<Vec<String>>,
+    #[serde(rename = "permitted", default, skip_serializing_if = "Option::is_none")]
+    permitted: Option<Vec<String>>,
+    #[serde(rename = "ambient", default, skip_serializing_if = "Option::is_none")]
+    ambient: Option<Vec<String>>,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
+}
+/// A resource limit of the process, e.g. `RLIMIT_NOFILE`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Rlimit {
+    #[serde(rename = "type")]
+    type_: String,
+    #[serde(rename = "hard")]
+    hard: u64,
+    #[serde(rename = "soft")]
+    soft: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 pub struct User {
     #[serde(rename = "uid")]
     pub(crate) uid: u32,
     #[serde(rename = "gid")]
     pub(crate) gid: u32,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -44,9 +169,12 @@ pub struct Root {
     path: String,
     #[serde(rename = "readonly")]
     readonly: bool,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 pub struct Mount {
     #[serde(rename = "destination")]
     pub destination: String,
@@ -54,8 +182,11 @@ pub struct Mount {
     pub type_: String,
     #[serde(rename = "source")]
     pub source: String,
-    #[serde(rename = "options")]
+    #[serde(rename = "options", default, skip_serializing_if = "Option::is_none")]
     pub options: Option<Vec<String>>,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -68,28 +199,187 @@ pub struct Linux {
     masked_paths: Vec<String>,
     #[serde(rename = "readonlyPaths")]
     readonly_paths: Vec<String>,
+    #[serde(rename = "seccomp", default, skip_serializing_if = "Option::is_none")]
+    seccomp: Option<Seccomp>,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
 }
 
+/// The seccomp filter of the container: the `syscalls` rules take precedence over the
+/// `default_action`. Without `architectures` only the native one is allowed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Seccomp {
+    #[serde(rename = "defaultAction")]
+    default_action: String,
+    #[serde(
+        rename = "defaultErrnoRet",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    default_errno_ret: Option<u32>,
+    #[serde(
+        rename = "architectures",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    architectures: Option<Vec<String>>,
+    #[serde(rename = "flags", default, skip_serializing_if = "Option::is_none")]
+    flags: Option<Vec<String>>,
+    #[serde(rename = "syscalls", default, skip_serializing_if = "Option::is_none")]
+    syscalls: Option<Vec<SeccompSyscall>>,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SeccompSyscall {
+    #[serde(rename = "names")]
+    names: Vec<String>,
+    #[serde(rename = "action")]
+    action: String,
+    #[serde(rename = "errnoRet", default, skip_serializing_if = "Option::is_none")]
+    errno_ret: Option<u32>,
+    #[serde(rename = "args", default, skip_serializing_if = "Option::is_none")]
+    args: Option<Vec<SeccompArg>>,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
+}
+/// Compares the syscall argument at `index` with `value`. For `SCMP_CMP_MASKED_EQ`, `value` is
+/// the mask and `value_two` the expected result.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SeccompArg {
+    #[serde(rename = "index")]
+    index: u32,
+    #[serde(rename = "value")]
+    value: u64,
+    #[serde(rename = "valueTwo", default, skip_serializing_if = "Option::is_none")]
+    value_two: Option<u64>,
+    #[serde(rename = "op")]
+    op: String,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
+}
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Resources {
     #[serde(rename = "devices")]
     devices: Vec<Device>,
+    #[serde(rename = "memory", default, skip_serializing_if = "Option::is_none")]
+    memory: Option<Memory>,
+    #[serde(rename = "cpu", default, skip_serializing_if = "Option::is_none")]
+    cpu: Option<Cpu>,
+    #[serde(rename = "pids", default, skip_serializing_if = "Option::is_none")]
+    pids: Option<Pids>,
+    #[serde(rename = "blockIO", default, skip_serializing_if = "Option::is_none")]
+    block_io: Option<BlockIo>,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
 }
 
+/// Limits in bytes. `swap` is the limit of memory and swap together.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Memory {
+    #[serde(rename = "limit", default, skip_serializing_if = "Option::is_none")]
+    limit: Option<i64>,
+    #[serde(
+        rename = "reservation",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    reservation: Option<i64>,
+    #[serde(rename = "swap", default, skip_serializing_if = "Option::is_none")]
+    swap: Option<i64>,
+    #[serde(
+        rename = "disableOOMKiller",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    disable_oom_killer: Option<bool>,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
+}
+/// The CFS `quota` per `period` (in microseconds), the relative `shares` and the allowed `cpus`
+/// (e.g. `0-3`).
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Cpu {
+    #[serde(rename = "shares", default, skip_serializing_if = "Option::is_none")]
+    shares: Option<u64>,
+    #[serde(rename = "quota", default, skip_serializing_if = "Option::is_none")]
+    quota: Option<i64>,
+    #[serde(rename = "period", default, skip_serializing_if = "Option::is_none")]
+    period: Option<u64>,
+    #[serde(rename = "cpus", default, skip_serializing_if = "Option::is_none")]
+    cpus: Option<String>,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
+}
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Pids {
+    #[serde(rename = "limit")]
+    limit: i64,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
+}
+/// The relative `weight` (10 to 1000) and per-device throttling in bytes per second.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct BlockIo {
+    #[serde(rename = "weight", default, skip_serializing_if = "Option::is_none")]
+    weight: Option<u16>,
+    #[serde(
+        rename = "throttleReadBpsDevice",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    throttle_read_bps_device: Option<Vec<ThrottleDevice>>,
+    #[serde(
+        rename = "throttleWriteBpsDevice",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    throttle_write_bps_device: Option<Vec<ThrottleDevice>>,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
+}
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ThrottleDevice {
+    #[serde(rename = "major")]
+    major: i64,
+    #[serde(rename = "minor")]
+    minor: i64,
+    #[serde(rename = "rate")]
+    rate: u64,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
+}
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Device {
     #[serde(rename = "allow")]
     allow: bool,
     #[serde(rename = "access")]
     access: String,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Namespace {
     #[serde(rename = "type")]
     type_: String,
-    #[serde(rename = "path")]
+    #[serde(rename = "path", default, skip_serializing_if = "Option::is_none")]
     path: Option<String>,
+    /// Fields that are not modeled here, kept as they are.
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
 }
 
 pub fn patch_config_json(
@@ -99,6 +389,7 @@ pub fn patch_config_json(
     user: Option<User>,
     cwd: Option<String>,
     additional_mounts: Option<Vec<Mount>>,
+    resource_limits: Option<ResourceLimits>,
 ) -> ConfigJson {
     let mut config_json = config_json;
 
@@ -122,12 +413,184 @@ pub fn patch_config_json(
         config_json.mounts.extend(additional_mounts);
     }
 
+    if let Some(resource_limits) = resource_limits {
+        apply_resource_limits(&mut config_json.linux.resources, &resource_limits);
+    }
+
     config_json
 }
 
+impl ConfigJson {
+    /// Runs the container in the given (existing) network namespace.
+    pub fn with_network_namespace(mut self, path: String) -> ConfigJson {
+        let namespaces = &mut self.linux.namespaces;
+        match namespaces.iter_mut().find(|n| n.type_ == "network") {
+            Some(namespace) => namespace.path = Some(path),
+            None => namespaces.push(Namespace {
+                type_: "network".to_string(),
+                path: Some(path),
+                other: Map::new(),
+            }),
+        }
+        self
+    }
+
+    /// Points the bundle at the given rootfs, e.g. one outside of the bundle directory.
+    pub fn with_root_path(mut self, path: String) -> ConfigJson {
+        self.root.path = path;
+        self
+    }
+
+    /// Uses the given seccomp profile, unless the base config brings its own.
+    pub fn with_default_seccomp(mut self, seccomp: Seccomp) -> ConfigJson {
+        self.linux.seccomp.get_or_insert(seccomp);
+        self
+    }
+}
+
+/// The seccomp profile of the `runc` sandbox: everything is allowed except for
+/// `DENIED_SYSCALLS` and new user namespaces. `clone3` reports `ENOSYS`, as its flags cannot be
+/// inspected, so that the libc falls back to `clone`. Only the native architecture is allowed.
+pub fn hardened_seccomp_profile() -> Seccomp {
+    Seccomp {
+        default_action: "SCMP_ACT_ALLOW".to_string(),
+        default_errno_ret: None,
+        architectures: None,
+        flags: None,
+        syscalls: Some(vec![
+            SeccompSyscall {
+                names: DENIED_SYSCALLS.iter().map(|s| s.to_string()).collect(),
+                action: "SCMP_ACT_ERRNO".to_string(),
+                errno_ret: Some(EPERM),
+                args: None,
+                other: Map::new(),
+            },
+            SeccompSyscall {
+                names: vec!["clone".to_string()],
+                action: "SCMP_ACT_ERRNO".to_string(),
+                errno_ret: Some(EPERM),
+                args: Some(vec![SeccompArg {
+                    index: 0,
+                    value: CLONE_NEWUSER,
+                    value_two: Some(CLONE_NEWUSER),
+                    op: "SCMP_CMP_MASKED_EQ".to_string(),
+                    other: Map::new(),
+                }]),
+                other: Map::new(),
+            },
+            SeccompSyscall {
+                names: vec!["clone3".to_string()],
+                action: "SCMP_ACT_ERRNO".to_string(),
+                errno_ret: Some(ENOSYS),
+                args: None,
+                other: Map::new(),
+            },
+        ]),
+        other: Map::new(),
+    }
+}
+
+/// Sets the limits that are configured and leaves the others as in the base config.
+fn apply_resource_limits(resources: &mut Resources, resource_limits: &ResourceLimits) {
+    if let Some(memory_bytes) = resource_limits.memory_bytes {
+        let limit = i64::try_from(memory_bytes).unwrap_or(i64::MAX);
+        resources.memory = Some(Memory {
+            limit: Some(limit),
+            // no swapping beyond the limit
+            swap: Some(limit),
+            ..resources.memory.take().unwrap_or_default()
+        });
+    }
+    if let Some(milli_cpus) = resource_limits.milli_cpus {
+        let quota = i64::try_from(milli_cpus * CPU_PERIOD_US / 1000).unwrap_or(i64::MAX);
+        resources.cpu = Some(Cpu {
+            quota: Some(quota),
+            period: Some(CPU_PERIOD_US),
+            ..resources.cpu.take().unwrap_or_default()
+        });
+    }
+    if let Some(pids) = resource_limits.pids {
+        resources.pids = Some(Pids {
+            limit: i64::try_from(pids).unwrap_or(i64::MAX),
+            ..resources.pids.take().unwrap_or_default()
+        });
+    }
+    if let Some(block_io_weight) = resource_limits.block_io_weight {
+        resources.block_io = Some(BlockIo {
+            weight: Some(block_io_weight),
+            ..resources.block_io.take().unwrap_or_default()
+        });
+    }
+}
+
+/// An event of `runc events` (and `runsc events`): `oom` when the OOM killer hit the container,
+/// and periodic `stats`.
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct Event {
+    #[serde(rename = "type")]
+    pub type_: String,
+    #[serde(rename = "data", default)]
+    pub data: Option<Stats>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct Stats {
+    #[serde(rename = "memory", default)]
+    pub memory: Option<MemoryStats>,
+    #[serde(rename = "pids", default)]
+    pub pids: Option<PidsStats>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct MemoryStats {
+    #[serde(rename = "usage", default)]
+    pub usage: Option<MemoryEntry>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct MemoryEntry {
+    #[serde(rename = "limit", default)]
+    pub limit: u64,
+    #[serde(rename = "failcnt", default)]
+    pub failcnt: u64,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct PidsStats {
+    #[serde(rename = "current", default)]
+    pub current: u64,
+    #[serde(rename = "limit", default)]
+    pub limit: u64,
+}
+
+/// Describes the limit that the container ran into according to a line of `runc events`, if any.
+pub fn limit_hit(event_line: &str) -> Option<String> {
+    let event: Event = serde_json::from_str(event_line).ok()?;
+    if event.type_ == "oom" {
+        return Some("the sandbox ran out of memory (OOM killer)".to_string());
+    }
+    let stats = event.data?;
+    if let Some(MemoryEntry { limit, failcnt }) = stats.memory.and_then(|memory| memory.usage) {
+        if failcnt > 0 {
+            return Some(format!(
+                "the sandbox hit its memory limit of {} bytes {} time(s)",
+                limit, failcnt
+            ));
+        }
+    }
+    if let Some(PidsStats { current, limit }) = stats.pids {
+        if limit > 0 && current >= limit {
+            return Some(format!("the sandbox hit its limit of {} processes", limit));
+        }
+    }
+    None
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::runc::ConfigJson;
+    use crate::runc::{limit_hit, ConfigJson};
+    use common::ResourceLimits;
+    use serde_json::{json, Value};
     use std::path::PathBuf;
 
     #[test]
@@ -151,6 +614,125 @@ mod tests {
         let actual: ConfigJson = serde_json::from_str(&serialized).unwrap();
 
         assert_eq!(expected, actual);
+        // nothing of the base config gets lost on the way
+        assert_eq!(
+            serde_json::to_value(&actual).unwrap(),
+            serde_json::from_str::<Value>(&config_json_string).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_deserialize_security_settings() {
+        let path = get_sample_config_json_path();
+
+        let config_json_string = std::fs::read_to_string(path).unwrap();
+        let config_json: ConfigJson = serde_json::from_str(&config_json_string).unwrap();
+
+        let capabilities = config_json.process.capabilities.unwrap();
+        assert_eq!(capabilities.bounding, Some(vec![]));
+        assert_eq!(capabilities.inheritable, None);
+        let rlimits = config_json.process.rlimits.unwrap();
+        assert_eq!(rlimits[0].type_, "RLIMIT_NOFILE");
+        assert_eq!(rlimits[0].hard, 1024);
+        assert_eq!(config_json.process.no_new_privileges, Some(true));
+        assert!(config_json.process.other.is_empty());
+    }
+
+    #[test]
+    fn test_patch_keeps_unknown_fields() {
+        let mut config_json: Value =
+            serde_json::from_str(&std::fs::read_to_string(get_sample_config_json_path()).unwrap())
+                .unwrap();
+        config_json["annotations"] = json!({ "org.example": "value" });
+        config_json["process"]["apparmorProfile"] = json!("sandbox");
+        config_json["linux"]["sysctl"] = json!({ "net.ipv4.ip_forward": "0" });
+        config_json["mounts"][0]["uidMappings"] = json!([]);
+        config_json["linux"]["resources"]["cpu"] = json!({ "mems": "0" });
+        config_json["linux"]["resources"]["memory"] = json!({ "swappiness": 0 });
+        config_json["linux"]["resources"]["blockIO"] =
+            json!({ "weightDevice": [{ "major": 8, "minor": 0, "weight": 500 }] });
+        config_json["process"]["capabilities"]["bounding"] = json!(["CAP_CHOWN"]);
+        config_json["process"]["capabilities"]["extra"] = json!(true);
+
+        let parsed: ConfigJson = serde_json::from_value(config_json.clone()).unwrap();
+        let patched = super::patch_config_json(
+            parsed,
+            Some("echo hello".to_string()),
+            None,
+            None,
+            None,
+            None,
+            Some(ResourceLimits {
+                memory_bytes: Some(1 << 30),
+                milli_cpus: Some(1500),
+                ..Default::default()
+            }),
+        );
+        let patched = serde_json::to_value(patched).unwrap();
+        let resources = &patched["linux"]["resources"];
+        assert_eq!(resources["cpu"]["mems"], json!("0"));
+        assert_eq!(resources["cpu"]["quota"], json!(150000));
+        assert_eq!(resources["memory"]["swappiness"], json!(0));
+        assert_eq!(resources["memory"]["limit"], json!(1 << 30));
+        assert_eq!(
+            resources["blockIO"],
+            config_json["linux"]["resources"]["blockIO"]
+        );
+        assert_eq!(patched["process"]["capabilities"]["extra"], json!(true));
+        assert_eq!(patched["annotations"], config_json["annotations"]);
+        assert_eq!(patched["process"]["apparmorProfile"], json!("sandbox"));
+        assert_eq!(patched["linux"]["sysctl"], config_json["linux"]["sysctl"]);
+        assert_eq!(patched["mounts"][0]["uidMappings"], json!([]));
+        assert_eq!(patched["process"]["args"], json!(["echo", "hello"]));
+    }
+
+    #[test]
+    fn test_with_default_seccomp() {
+        let path = get_sample_config_json_path();
+
+        let config_json_string = std::fs::read_to_string(path).unwrap();
+        let config_json: ConfigJson = serde_json::from_str(&config_json_string).unwrap();
+
+        let hardened = config_json.with_default_seccomp(super::hardened_seccomp_profile());
+        let seccomp = serde_json::to_value(&hardened.linux.seccomp).unwrap();
+        assert_eq!(seccomp["defaultAction"], "SCMP_ACT_ALLOW");
+        let denied = seccomp["syscalls"][0]["names"].as_array().unwrap();
+        for syscall in ["mount", "unshare", "setns", "ptrace", "bpf", "keyctl"] {
+            assert!(denied.contains(&json!(syscall)), "{} is allowed", syscall);
+        }
+        assert_eq!(seccomp["syscalls"][0]["errnoRet"], 1);
+        assert_eq!(
+            seccomp["syscalls"][1]["args"][0]["op"],
+            "SCMP_CMP_MASKED_EQ"
+        );
+
+        // a profile of the base config wins
+        let mut custom = super::hardened_seccomp_profile();
+        custom.default_action = "SCMP_ACT_ERRNO".to_string();
+        let mut config_json = hardened;
+        config_json.linux.seccomp = Some(custom.clone());
+        let patched = config_json.with_default_seccomp(super::hardened_seccomp_profile());
+        assert_eq!(patched.linux.seccomp, Some(custom));
+    }
+
+    #[test]
+    fn test_with_network_namespace() {
+        let path = get_sample_config_json_path();
+
+        let config_json_string = std::fs::read_to_string(path).unwrap();
+        let config_json: ConfigJson = serde_json::from_str(&config_json_string).unwrap();
+        let namespaces = config_json.linux.namespaces.len();
+
+        let patched = config_json.with_network_namespace("/var/run/netns/job".to_string());
+        assert_eq!(patched.linux.namespaces.len(), namespaces);
+        let network: Vec<_> = patched
+            .linux
+            .namespaces
+            .iter()
+            .filter(|n| n.type_ == "network")
+            .collect();
+        assert_eq!(network.len(), 1);
+        assert_eq!(network[0].path.as_deref(), Some("/var/run/netns/job"));
     }
 
     #[test]
@@ -161,7 +743,7 @@ mod tests {
         let config_json: ConfigJson = serde_json::from_str(&config_json_string).unwrap();
 
         let expected = config_json.clone();
-        let actual = super::patch_config_json(config_json, None, None, None, None, None);
+        let actual = super::patch_config_json(config_json, None, None, None, None, None, None);
 
         assert_eq!(expected, actual);
     }
@@ -178,6 +760,7 @@ mod tests {
         let user = Some(super::User {
             uid: 1000,
             gid: 1000,
+            ..Default::default()
         });
         let cwd = Some("/tmp".to_string());
         let additional_mounts = Some(vec![super::Mount {
@@ -185,6 +768,7 @@ mod tests {
             type_: "bind".to_string(),
             source: "/tmp".to_string(),
             options: None,
+            ..Default::default()
         }]);
 
         let mut expected_mounts = config_json.mounts.clone();
@@ -200,18 +784,87 @@ mod tests {
                 user: super::User {
                     uid: 1000,
                     gid: 1000,
+                    ..Default::default()
                 },
                 cwd: "/tmp".to_string(),
-                ..config_json.process
+                ..config_json.process.clone()
             },
             mounts: expected_mounts,
             ..config_json.clone()
         };
 
-        let actual = super::patch_config_json(config_json, args, env, user, cwd, additional_mounts);
+        let actual =
+            super::patch_config_json(config_json, args, env, user, cwd, additional_mounts, None);
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn test_patch_resource_limits() {
+        let path = get_sample_config_json_path();
+
+        let config_json_string = std::fs::read_to_string(path).unwrap();
+        let config_json: ConfigJson = serde_json::from_str(&config_json_string).unwrap();
+        let resource_limits = ResourceLimits {
+            memory_bytes: Some(2 << 30),
+            milli_cpus: Some(1500),
+            pids: Some(512),
+            block_io_weight: Some(100),
+        };
+
+        let patched = super::patch_config_json(
+            config_json.clone(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(resource_limits),
+        );
+        let resources = serde_json::to_value(&patched.linux.resources).unwrap();
+        assert_eq!(resources["memory"]["limit"], 2i64 << 30);
+        assert_eq!(resources["cpu"]["quota"], 150_000);
+        assert_eq!(resources["cpu"]["period"], 100_000);
+        assert_eq!(resources["pids"]["limit"], 512);
+        assert_eq!(resources["blockIO"]["weight"], 100);
+        // the devices of the base config are kept
+        assert_eq!(
+            patched.linux.resources.devices,
+            config_json.linux.resources.devices
+        );
+
+        // what is not configured stays unlimited
+        let patched = super::patch_config_json(
+            config_json.clone(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(ResourceLimits::default()),
+        );
+        assert_eq!(patched, config_json);
+    }
+
+    #[test]
+    fn test_limit_hit() {
+        assert!(limit_hit(r#"{"type":"oom","id":"sandbox"}"#).is_some());
+        assert!(limit_hit(
+            r#"{"type":"stats","id":"sandbox","data":{"memory":{"usage":{"limit":1024,"usage":1000,"failcnt":3}}}}"#
+        )
+        .is_some());
+        assert!(limit_hit(
+            r#"{"type":"stats","id":"sandbox","data":{"pids":{"current":512,"limit":512}}}"#
+        )
+        .is_some());
+
+        assert!(limit_hit(
+            r#"{"type":"stats","id":"sandbox","data":{"memory":{"usage":{"limit":1024,"failcnt":0}},"pids":{"current":3,"limit":0}}}"#
+        )
+        .is_none());
+        assert!(limit_hit(r#"{"type":"intelrdt","id":"sandbox"}"#).is_none());
+        assert!(limit_hit("container not running").is_none());
+    }
+
     fn get_sample_config_json_path() -> PathBuf {
         std::env::current_dir()
             .unwrap()
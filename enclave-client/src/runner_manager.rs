@@ -1,26 +1,75 @@
-use crate::runc::{patch_config_json, ConfigJson, Mount, User};
+use crate::artifacts;
+use crate::attestation::MeasuredCheckout;
+use crate::file_tailer::FileTailer;
+use crate::hook_protocol::{parse_hook_line, HookEvent};
+use crate::network;
+use crate::oci_image::{self, ImageAllowlist};
+use crate::overlay;
+use crate::runc::{self, patch_config_json, ConfigJson, Mount, User};
 use anyhow::anyhow;
-use common::{FakeRunnerArgs, RunnerArgs, RunnerStartMode};
+use common::claims::BuildEnvironment;
+use common::{git, lockfiles, rootfs, source_tree};
+use common::{BuildEnvironmentImage, FakeRunnerArgs, ResourceLimits, RunnerArgs, RunnerStartMode};
+use std::fmt;
+use std::fmt::{Display, Formatter};
 use std::path::{Path, PathBuf};
-use tokio::io::AsyncBufReadExt;
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
-use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
-use tokio::task;
-use tokio::task::JoinHandle;
+use tokio::sync::{mpsc, oneshot};
+use tokio::{task, time};
 use tracing::field::debug;
 use tracing::{debug, warn};
 
 pub const RUNNER_NAME: &str = "NitroNorris";
 
+/// How long the runner gets to shut down after SIGTERM before it is killed.
+const RUNNER_TERMINATION_GRACE_PERIOD: time::Duration = time::Duration::from_secs(10);
+
+/// The file in the output directory in which the enclave client answers `checkout_complete` with
+/// the commit that it measured (the pre hook waits for it).
+const COMMIT_HASH_FILE: &str = "commit_hash";
+
+/// The directory in the output directory into which the attestation hook copies the artifacts.
+const ARTIFACTS_DIR: &str = "artifacts";
+
+/// Where the dependency cache of a hermetic build is mounted (read-only) in the sandbox.
+const SANDBOX_DEPENDENCY_CACHE_PATH: &str = "/dependency-cache";
+
+/// The prefix of the container IDs, which also name the bundle directories of the jobs.
+const CONTAINER_ID_PREFIX: &str = "stampssandbox";
+
+/// The directory (relative to the working directory) with a bundle directory for every job.
+const BUNDLES_DIR: &str = "tmp/sandbox";
+
+/// The allowlist of build environment images in the sandbox build directory, see
+/// `oci_image::ImageAllowlist`.
+const IMAGE_ALLOWLIST_FILE: &str = "image-allowlist.txt";
+
+/// Where a build environment image is unpacked in the bundle directory (and the layout that it
+/// came in, until then).
+const IMAGE_ROOTFS_DIR: &str = "image/rootfs";
+const IMAGE_LAYOUT_DIR: &str = "image/layout";
+
+/// How long to wait before following the events of the container again, e.g. because it has not
+/// been created yet.
+const CONTAINER_EVENTS_RETRY_INTERVAL: time::Duration = time::Duration::from_millis(500);
+
+/// Resolves once the host cancelled the job. Dropping the sender does not cancel the runner.
+pub type CancelReceiver = oneshot::Receiver<()>;
+
 pub enum RunnerMessage {
     ConfigurationComplete,
-    CommitHash {
-        commit_hash: String,
+    /// The checkout as measured by the enclave client, see `measure_checkout`.
+    CheckoutMeasured {
+        checkout: MeasuredCheckout,
     },
     ArtifactNameAndHash {
         artifact_name: String,
         artifact_hash: String,
+    },
+    /// All artifacts have been reported; the attestation is expected in the input log.
+    BuildComplete {
         local_input_log_path: PathBuf,
     },
     LogMessage {
@@ -30,6 +79,52 @@ pub enum RunnerMessage {
         marker: String,
         datetime: String,
     },
+    Failed {
+        detail: String,
+    },
+    /// The sandbox ran into one of its resource limits (and failed), see `ResourceLimitExceeded`.
+    ResourceLimitExceeded {
+        detail: String,
+    },
+}
+
+/// The container failed after running into one of its resource limits, e.g. the OOM killer
+/// stopped the build. Reported separately, as a bigger limit (and not a fix) might be the answer.
+#[derive(Debug)]
+struct ResourceLimitExceeded {
+    detail: String,
+}
+
+impl Display for ResourceLimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.detail)
+    }
+}
+
+impl std::error::Error for ResourceLimitExceeded {}
+
+/// The paths (as seen by the enclave client) through which it interacts with the hooks.
+#[derive(Debug)]
+struct HookPaths {
+    /// The attestation document for the attestation hook.
+    local_input_log_path: PathBuf,
+
+    /// The answer to `checkout_complete`, see `COMMIT_HASH_FILE`.
+    local_commit_hash_path: PathBuf,
+
+    /// The checkout of the pre hook (`GITHUB_WORKSPACE`).
+    local_checkout_path: PathBuf,
+
+    /// The artifacts that the attestation hook reports, see `ARTIFACTS_DIR`.
+    local_artifacts_path: PathBuf,
+
+    /// The digests of the lower layer of the sandbox's rootfs (and of the image that it was
+    /// unpacked from) and of the dependency cache, if the build is hermetic.
+    build_environment: BuildEnvironment,
+
+    /// The link of the job's own network (see `network::JobNetwork`), which is removed once the
+    /// checkout has been measured, if the build is hermetic.
+    cut_off_link: Option<String>,
 }
 
 /**
@@ -58,6 +153,7 @@ impl DirectRunnerManager {
         self,
         runner_args: RunnerArgs,
         tx: Sender<RunnerMessage>,
+        cancel_rx: CancelReceiver,
     ) -> anyhow::Result<()> {
         // clean up any potential leftovers
         self.remove_runner_config().await?;
@@ -66,19 +162,33 @@ impl DirectRunnerManager {
         self.configure_runner(&runner_args).await?;
         tx.send(RunnerMessage::ConfigurationComplete).await?;
 
-        let local_input_log_path = get_output_log_path(&self.runner_path)?.join("input.log");
-        ensure_empty_input_log_file(&local_input_log_path).await?;
+        let output_path = get_output_log_path(&self.runner_path)?;
+        let hook_paths = HookPaths {
+            local_input_log_path: output_path.join("input.log"),
+            local_commit_hash_path: output_path.join(COMMIT_HASH_FILE),
+            local_checkout_path: get_checkout_path(
+                &self.runner_path,
+                self.fake_runner_args.is_some(),
+                &runner_args.github_repository,
+            )?,
+            local_artifacts_path: output_path.join(ARTIFACTS_DIR),
+            build_environment: BuildEnvironment::default(),
+            cut_off_link: None,
+        };
+        ensure_empty_input_log_file(&hook_paths.local_input_log_path).await?;
+        remove_stale_file(&hook_paths.local_commit_hash_path)?;
+        ensure_empty_artifacts_dir(&hook_paths.local_artifacts_path).await?;
 
         // run everything in a separate task
         let (line_tx, mut line_rx) = mpsc::channel(32);
         let runner_task_handle = task::spawn(async move {
-            if let Err(e) = self.run_runner(runner_args, line_tx).await {
+            if let Err(e) = self.run_runner(runner_args, line_tx, cancel_rx).await {
                 debug!("Error running the runner: {:?}", e);
             }
         });
 
         while let Some(line) = line_rx.recv().await {
-            if let Some(message) = handle_incoming_log_message(&line, &local_input_log_path).await {
+            if let Some(message) = handle_incoming_log_message(&line, &hook_paths).await {
                 tx.send(message).await?;
             }
         }
@@ -145,6 +255,7 @@ impl DirectRunnerManager {
         self,
         runner_args: RunnerArgs,
         line_output: Sender<String>,
+        cancel_rx: CancelReceiver,
     ) -> anyhow::Result<()> {
         let exec = self.runner_path.join("run.sh");
         let hooks_dir = get_hooks_dir(&self.runner_path)?;
@@ -174,14 +285,41 @@ impl DirectRunnerManager {
         add_fake_runner_env(&mut command, &self.fake_runner_args);
 
         let child = command.spawn()?;
+        let pid = child.id();
 
         // start tailing the output log file while the child is running
-        let tail_handle = spawn_file_tailer(line_output, &output_log_path);
-
-        // wait for the runner to finish
-        let output = child.wait_with_output().await?;
-        tail_handle.abort();
+        let tailer = FileTailer::spawn(&output_log_path, line_output);
+
+        // wait for the runner to finish, unless the host cancels the job
+        let wait = child.wait_with_output();
+        tokio::pin!(wait);
+        let mut cancelled = false;
+        let output = tokio::select! {
+            output = &mut wait => output?,
+            Ok(()) = cancel_rx => {
+                debug!("Cancelling the runner");
+                cancelled = true;
+                // sudo relays SIGTERM to the runner, but SIGKILL would only hit sudo itself
+                signal_process(pid, "TERM").await?;
+                match time::timeout(RUNNER_TERMINATION_GRACE_PERIOD, &mut wait).await {
+                    Ok(output) => output?,
+                    Err(_) => {
+                        warn!("The runner did not terminate in time, killing it");
+                        signal_process(pid, "KILL").await?;
+                        wait.await?
+                    }
+                }
+            }
+        };
+        // the runner has exited, so everything it wrote is in the file by now
+        if let Err(e) = tailer.stop().await {
+            warn!("Failed to follow the output log: {:?}", e);
+        }
 
+        if cancelled {
+            debug!("The runner was cancelled: {:?}", output.status);
+            return Ok(());
+        }
         if !output.status.success() {
             warn!("Runner STDOUT: {}", String::from_utf8_lossy(&output.stdout));
             warn!("Runner STDERR: {}", String::from_utf8_lossy(&output.stderr));
@@ -193,23 +331,35 @@ impl DirectRunnerManager {
 }
 
 /**
- * The `SandboxRunnerManager` runs the GitHub action runner in a container using `runc`.
+ * The `SandboxRunnerManager` runs the GitHub action runner in a container using `runc`. Every
+ * job gets its own container ID and bundle directory (with the `config.json`, the output
+ * directory and a copy-on-write overlay of the shared rootfs) and its own network namespace (see
+ * `network::JobNetwork`), so that several jobs can run side by side and none of them sees what a
+ * previous one left behind.
  */
 #[derive(Debug)]
 pub(crate) struct SandboxRunnerManager {
     sandbox_runner_path: PathBuf,
+    local_bundle_path: PathBuf,
     local_output_path: PathBuf,
     local_output_log_path: PathBuf,
     local_input_log_path: PathBuf,
     local_sandbox_build_path: PathBuf,
     container_id: String,
     fake_runner_args: Option<FakeRunnerArgs>,
+    hermetic_cache_path: Option<PathBuf>,
+    resource_limits: ResourceLimits,
+    build_environment_image: Option<BuildEnvironmentImage>,
 }
 
 impl SandboxRunnerManager {
     pub(crate) fn new(
         fake_runner_args: Option<FakeRunnerArgs>,
         runner_version: String,
+        hermetic_cache_path: Option<PathBuf>,
+        resource_limits: ResourceLimits,
+        build_environment_image: Option<BuildEnvironmentImage>,
+        run_id: u32,
     ) -> anyhow::Result<Self> {
         let sandbox_base_path = PathBuf::from("/app/");
         let runner_dir = build_runner_path(fake_runner_args.is_some(), runner_version);
@@ -217,11 +367,14 @@ impl SandboxRunnerManager {
 
         let local_base_path = std::env::current_dir()?;
 
-        // create a temporary directory for the output log relative to the current directory
-        let local_output_path = local_base_path.join("tmp/output");
-        if !local_output_path.exists() {
-            std::fs::create_dir_all(&local_output_path)?;
-        }
+        // the process ID tells apart the clients that (in `local` mode) share a host, and every
+        // client runs a single job
+        let container_id = format!("{}-{}-{}", CONTAINER_ID_PREFIX, run_id, std::process::id());
+
+        // the bundle and the output directory (created by `run`) are relative to the current
+        // directory
+        let local_bundle_path = local_base_path.join(BUNDLES_DIR).join(&container_id);
+        let local_output_path = local_bundle_path.join("output");
         let local_output_log_path = local_output_path.join("output.log");
         let local_input_log_path = local_output_path.join("input.log");
 
@@ -230,12 +383,16 @@ impl SandboxRunnerManager {
 
         let result = Self {
             sandbox_runner_path,
+            local_bundle_path,
             local_output_path,
             local_output_log_path,
             local_input_log_path,
             local_sandbox_build_path,
-            container_id: "stampssandbox".to_string(),
+            container_id,
             fake_runner_args,
+            hermetic_cache_path,
+            resource_limits,
+            build_environment_image,
         };
         debug!("SandboxRunnerManager: {:?}", &result);
         Ok(result)
@@ -246,24 +403,107 @@ impl SandboxRunnerManager {
         runner_args: RunnerArgs,
         tx: Sender<RunnerMessage>,
         runner_mode: RunnerStartMode,
+        cancel_rx: CancelReceiver,
     ) -> anyhow::Result<()> {
-        // patch the config.base.json file
+        let program = match runner_mode {
+            RunnerStartMode::Sandbox => "runc",
+            RunnerStartMode::SandboxPlus => "runsc",
+            _ => anyhow::bail!("Invalid runner mode for SandboxRunnerManager"),
+        };
+
+        // removes the container and the bundle however the job ends
+        let _bundle_cleanup = BundleCleanup {
+            program,
+            container_id: self.container_id.clone(),
+            local_bundle_path: self.local_bundle_path.clone(),
+        };
+        std::fs::create_dir_all(&self.local_output_path)?;
+
+        // every job gets a network of its own, so that concurrent jobs share no network state:
+        // cutting off a hermetic build after the checkout affects nobody else (and nobody else
+        // can bring it back)
+        let job_network = network::JobNetwork::create(&self.container_id)?;
+
+        // the cache is mounted read-only, so measuring it once before the build suffices
+        let hermetic_cache_digest = match &self.hermetic_cache_path {
+            Some(hermetic_cache_path) => {
+                let hermetic_cache_path = hermetic_cache_path.clone();
+                let digest = task::spawn_blocking(move || {
+                    source_tree::source_tree_digest(&hermetic_cache_path)
+                })
+                .await??;
+                debug!("Measured the dependency cache: {}", digest);
+                Some(digest)
+            }
+            None => None,
+        };
+
+        // the build environment: the lower layer is read-only, so measuring it once suffices
+        let mut build_environment = BuildEnvironment {
+            hermetic_cache_digest,
+            ..Default::default()
+        };
+        let local_default_rootfs_path = self.local_sandbox_build_path.join("rootfs");
+        let local_lower_rootfs_path = match &self.build_environment_image {
+            Some(image) => {
+                let allowlist = ImageAllowlist::read(
+                    &self.local_sandbox_build_path.join(IMAGE_ALLOWLIST_FILE),
+                )?;
+                allowlist.ensure_allowed(&image.digest)?;
+                let local_image_rootfs_path = self.local_bundle_path.join(IMAGE_ROOTFS_DIR);
+                self.unpack_build_environment_image(
+                    image,
+                    &local_image_rootfs_path,
+                    &local_default_rootfs_path,
+                )
+                .await?;
+                build_environment.image_digest = Some(image.digest.clone());
+                build_environment.image_allowlist_digest = Some(allowlist.digest);
+                local_image_rootfs_path
+            }
+            None => local_default_rootfs_path,
+        };
+        let rootfs_digest = {
+            let local_lower_rootfs_path = local_lower_rootfs_path.clone();
+            task::spawn_blocking(move || rootfs::rootfs_digest(&local_lower_rootfs_path)).await??
+        };
+        debug!("Measured the rootfs: {}", rootfs_digest);
+        build_environment.rootfs_digest = Some(rootfs_digest);
+        let local_rootfs_path =
+            overlay::mount_rootfs_overlay(&local_lower_rootfs_path, &self.local_bundle_path)?;
+
+        // the container writes into its rootfs, so that we can measure the checkout from here
+        let local_runner_path = local_rootfs_path.join(self.sandbox_runner_path.strip_prefix("/")?);
+        let hook_paths = HookPaths {
+            local_input_log_path: self.local_input_log_path.clone(),
+            local_commit_hash_path: self.local_output_path.join(COMMIT_HASH_FILE),
+            local_checkout_path: get_checkout_path(
+                &local_runner_path,
+                self.fake_runner_args.is_some(),
+                &runner_args.github_repository,
+            )?,
+            local_artifacts_path: self.local_output_path.join(ARTIFACTS_DIR),
+            build_environment,
+            cut_off_link: self
+                .hermetic_cache_path
+                .as_ref()
+                .map(|_| job_network.link().to_string()),
+        };
+
+        // patch the config.base.json file into the bundle of the job
         let local_base_config_json_path = self.local_sandbox_build_path.join("config.base.json");
-        let local_config_json_path = self.local_sandbox_build_path.join("config.json");
+        let local_config_json_path = self.local_bundle_path.join("config.json");
         self.patch_config_json(
             &local_base_config_json_path,
             &local_config_json_path,
+            &local_rootfs_path,
+            job_network.namespace_path(),
             runner_args,
+            &runner_mode,
         )
         .await?;
 
-        let program = match runner_mode {
-            RunnerStartMode::Sandbox => "runc",
-            RunnerStartMode::SandboxPlus => "runsc",
-            _ => anyhow::bail!("Invalid runner mode for SandboxRunnerManager"),
-        };
-
-        // remove all container instances from runc that might be around
+        // a crashed client with the same process ID might have left the container behind
         let _ = Command::new(program)
             .arg("delete")
             .arg(self.container_id.clone())
@@ -273,44 +513,111 @@ impl SandboxRunnerManager {
 
         ensure_empty_output_log_file(&self.local_output_log_path).await?;
 
-        let local_input_log_path = self.local_input_log_path.clone();
-        ensure_empty_input_log_file(&local_input_log_path).await?;
+        ensure_empty_input_log_file(&hook_paths.local_input_log_path).await?;
+        remove_stale_file(&hook_paths.local_commit_hash_path)?;
+        ensure_empty_artifacts_dir(&hook_paths.local_artifacts_path).await?;
 
         let (line_tx, mut line_rx) = mpsc::channel(32);
         let container_task_handle = task::spawn(async move {
-            if let Err(e) = self.run_container(line_tx, program).await {
-                debug!("Error running the container: {:?}", e);
-            }
+            let result = self.run_container(line_tx, program, cancel_rx).await;
             debug("Container task finished");
+            result
         });
 
         while let Some(line) = line_rx.recv().await {
-            if let Some(message) = handle_incoming_log_message(&line, &local_input_log_path).await {
+            if let Some(message) = handle_incoming_log_message(&line, &hook_paths).await {
                 tx.send(message).await?;
             }
         }
 
-        container_task_handle.await?;
+        if let Err(e) = container_task_handle.await? {
+            match e.downcast::<ResourceLimitExceeded>() {
+                Ok(limit) => {
+                    warn!("The container ran into a resource limit: {}", limit);
+                    // nobody listens anymore if the build has been attested already
+                    let _ = tx
+                        .send(RunnerMessage::ResourceLimitExceeded {
+                            detail: limit.detail,
+                        })
+                        .await;
+                }
+                Err(e) => debug!("Error running the container: {:?}", e),
+            }
+        }
         Ok(())
     }
 
-    async fn run_container(&self, line_tx: Sender<String>, program: &str) -> anyhow::Result<()> {
-        let running_container_child = Command::new(program)
+    async fn run_container(
+        &self,
+        line_tx: Sender<String>,
+        program: &str,
+        cancel_rx: CancelReceiver,
+    ) -> anyhow::Result<()> {
+        let mut command = Command::new(program);
+        if program == "runsc" {
+            // gVisor keeps writes to the rootfs in memory by default, but we have to see the
+            // checkout to measure it
+            command.arg("--overlay2=none");
+        }
+        let running_container_child = command
             .arg("run")
             .arg("--bundle")
-            .arg(&self.local_sandbox_build_path)
+            .arg(&self.local_bundle_path)
             .arg(self.container_id.clone())
             .stdout(std::process::Stdio::piped())
             .stderr(std::process::Stdio::piped())
             .spawn()?;
         debug!("Started container");
 
-        let tail_handle = spawn_file_tailer(line_tx, &self.local_output_log_path);
-
-        // wait for the container to finish
-        let container_result = running_container_child.wait_with_output().await?;
-        tail_handle.abort();
-
+        let tailer = FileTailer::spawn(&self.local_output_log_path, line_tx);
+        let (limit_tx, mut limit_rx) = mpsc::unbounded_channel();
+        let limit_watcher = task::spawn(watch_resource_limits(
+            program.to_string(),
+            self.container_id.clone(),
+            limit_tx,
+        ));
+
+        // wait for the container to finish, unless the host cancels the job
+        let wait = running_container_child.wait_with_output();
+        tokio::pin!(wait);
+        let mut cancelled = false;
+        let container_result = tokio::select! {
+            container_result = &mut wait => container_result?,
+            Ok(()) = cancel_rx => {
+                debug!("Cancelling the container");
+                cancelled = true;
+                let output = Command::new(program)
+                    .arg("kill")
+                    .arg(&self.container_id)
+                    .arg("KILL")
+                    .output()
+                    .await?;
+                if !output.status.success() {
+                    warn!("Failed to kill the container: {:?}", output);
+                }
+                wait.await?
+            }
+        };
+        // the container has exited, so everything it wrote is in the file by now
+        if let Err(e) = tailer.stop().await {
+            warn!("Failed to follow the output log: {:?}", e);
+        }
+        limit_watcher.abort();
+        let limit_hit = limit_rx.try_recv().ok();
+
+        if cancelled {
+            let output = Command::new(program)
+                .arg("delete")
+                .arg("--force")
+                .arg(&self.container_id)
+                .output()
+                .await?;
+            if !output.status.success() {
+                warn!("Failed to delete the container: {:?}", output);
+            }
+            debug!("The container was cancelled: {:?}", container_result.status);
+            return Ok(());
+        }
         if !container_result.status.success() {
             warn!(
                 "Container STDOUT: {}",
@@ -320,17 +627,64 @@ impl SandboxRunnerManager {
                 "Container STDERR: {}",
                 String::from_utf8_lossy(&container_result.stderr)
             );
+            if let Some(detail) = limit_hit {
+                return Err(ResourceLimitExceeded { detail }.into());
+            }
             anyhow::bail!("Failed to run the container: {:?}", container_result.status);
         }
+        if let Some(detail) = limit_hit {
+            warn!("The container succeeded, but {}", detail);
+        }
 
         Ok(())
     }
 
+    /// Unpacks the (allowlisted) build environment image into the bundle, as the lower layer of
+    /// the rootfs overlay. The image brings its own runner and hooks in `/app` (it is meant to be
+    /// based on the sandbox image), only the DNS setup of the default rootfs is copied over.
+    async fn unpack_build_environment_image(
+        &self,
+        image: &BuildEnvironmentImage,
+        local_image_rootfs_path: &Path,
+        local_default_rootfs_path: &Path,
+    ) -> anyhow::Result<()> {
+        let tarball = image.path.clone();
+        let digest = image.digest.clone();
+        let local_layout_path = self.local_bundle_path.join(IMAGE_LAYOUT_DIR);
+        let local_rootfs_path = local_image_rootfs_path.to_path_buf();
+        task::spawn_blocking(move || {
+            let result =
+                oci_image::unpack_image(&tarball, &digest, &local_layout_path, &local_rootfs_path);
+            // the layout is not needed anymore, and the enclave keeps its files in memory
+            let _ = std::fs::remove_dir_all(&local_layout_path);
+            result
+        })
+        .await??;
+
+        let resolv_conf = local_image_rootfs_path.join("etc/resolv.conf");
+        if std::fs::symlink_metadata(&resolv_conf).is_ok() {
+            std::fs::remove_file(&resolv_conf)?;
+        }
+        std::fs::create_dir_all(local_image_rootfs_path.join("etc"))?;
+        std::fs::copy(
+            local_default_rootfs_path.join("etc/resolv.conf"),
+            &resolv_conf,
+        )?;
+        debug!(
+            "Unpacked the build environment image {} into {:?}",
+            image.digest, local_image_rootfs_path
+        );
+        Ok(())
+    }
+
     async fn patch_config_json(
         &self,
         local_base_config_json_path: &PathBuf,
         local_config_json_path: &PathBuf,
+        local_rootfs_path: &Path,
+        network_namespace_path: String,
         runner_args: RunnerArgs,
+        runner_mode: &RunnerStartMode,
     ) -> anyhow::Result<()> {
         let sandbox_hooks_path = get_hooks_dir(&self.sandbox_runner_path)?;
         let sandbox_output_path = get_output_log_path(&self.sandbox_runner_path)?;
@@ -378,6 +732,18 @@ impl SandboxRunnerManager {
             ));
         }
 
+        // hermetic builds: the network is gone after the checkout, so the package managers have
+        // to stay offline and use the dependency cache (see the mounts below)
+        if self.hermetic_cache_path.is_some() {
+            env.push(format!(
+                "DEPENDENCY_CACHE={}",
+                SANDBOX_DEPENDENCY_CACHE_PATH
+            ));
+            env.push("CARGO_NET_OFFLINE=true".to_string());
+            env.push("NPM_CONFIG_OFFLINE=true".to_string());
+            env.push("GOPROXY=off".to_string());
+        }
+
         let env = Some(env);
 
         // cwd: set to /app as per Dockerfile
@@ -387,20 +753,50 @@ impl SandboxRunnerManager {
         let user = Some(User {
             uid: runner_args.runner_uid,
             gid: runner_args.runner_gid,
+            ..Default::default()
         });
 
         // additional_mounts: mount the output directory
         let mount_options = Some(vec!["rbind".to_string(), "rw".to_string()]);
-        let additional_mounts = Some(vec![Mount {
+        let mut additional_mounts = vec![Mount {
             destination: sandbox_output_path.to_string_lossy().to_string(),
             type_: "none".to_string(),
             source: self.local_output_path.to_string_lossy().to_string(),
             options: mount_options,
-        }]);
-
-        // patch the config.base.json
-        let patched_config_json =
-            patch_config_json(config_json, args, env, user, cwd, additional_mounts);
+            ..Default::default()
+        }];
+
+        // hermetic builds: the dependency cache, read-only
+        if let Some(hermetic_cache_path) = &self.hermetic_cache_path {
+            additional_mounts.push(Mount {
+                destination: SANDBOX_DEPENDENCY_CACHE_PATH.to_string(),
+                type_: "none".to_string(),
+                source: hermetic_cache_path.to_string_lossy().to_string(),
+                options: Some(vec!["rbind".to_string(), "ro".to_string()]),
+                ..Default::default()
+            });
+        }
+        let additional_mounts = Some(additional_mounts);
+
+        // patch the config.base.json, with the overlay of the job as its rootfs
+        let patched_config_json = patch_config_json(
+            config_json,
+            args,
+            env,
+            user,
+            cwd,
+            additional_mounts,
+            Some(self.resource_limits.clone()),
+        )
+        .with_root_path(local_rootfs_path.to_string_lossy().to_string())
+        .with_network_namespace(network_namespace_path);
+        // gVisor (`runsc`) filters the syscalls in its own kernel and ignores this by default
+        let patched_config_json = match runner_mode {
+            RunnerStartMode::Sandbox => {
+                patched_config_json.with_default_seccomp(runc::hardened_seccomp_profile())
+            }
+            _ => patched_config_json,
+        };
 
         // and write it back
         let serialized = serde_json::to_string(&patched_config_json)?;
@@ -415,65 +811,255 @@ impl SandboxRunnerManager {
     }
 }
 
-fn spawn_file_tailer(line_output: Sender<String>, output_log: &Path) -> JoinHandle<()> {
-    let output_log = output_log.to_owned();
-    let tail_handle = tokio::task::spawn(async move {
-        let mut tail = tokio::process::Command::new("tail")
-            .arg("-f")
-            .arg(&output_log)
+/// Deletes the container, the rootfs overlay and the bundle directory (with the logs of the job)
+/// once dropped. `runc delete --force` also stops the container, if it is still running.
+struct BundleCleanup {
+    program: &'static str,
+    container_id: String,
+    local_bundle_path: PathBuf,
+}
+
+impl Drop for BundleCleanup {
+    fn drop(&mut self) {
+        match std::process::Command::new(self.program)
+            .arg("delete")
+            .arg("--force")
+            .arg(&self.container_id)
+            .output()
+        {
+            // fails as well if the container is gone already
+            Ok(output) if !output.status.success() => debug!(
+                "Did not delete the container {}: {}",
+                self.container_id,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Ok(_) => debug!("Deleted the container {}", self.container_id),
+            Err(e) => warn!(
+                "Failed to delete the container {}: {:?}",
+                self.container_id, e
+            ),
+        }
+        // removing the files through a mounted overlay would take ages (but be harmless)
+        if let Err(e) = overlay::unmount_rootfs_overlay(&self.local_bundle_path) {
+            warn!(
+                "Failed to unmount the rootfs overlay, keeping {:?}: {:?}",
+                self.local_bundle_path, e
+            );
+            return;
+        }
+        if let Err(e) = std::fs::remove_dir_all(&self.local_bundle_path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!(
+                    "Failed to remove the bundle {:?}: {:?}",
+                    self.local_bundle_path, e
+                );
+            }
+        }
+    }
+}
+
+/// Follows the events of the container (`runc events`) and reports every resource limit that it
+/// runs into. The container does not exist right after `runc run` has been spawned and `events`
+/// stops with it, so this retries until it is aborted.
+async fn watch_resource_limits(
+    program: String,
+    container_id: String,
+    limit_tx: mpsc::UnboundedSender<String>,
+) {
+    loop {
+        let child = Command::new(&program)
+            .arg("events")
+            .arg("--interval=1s")
+            .arg(&container_id)
             .stdout(std::process::Stdio::piped())
-            .spawn()
-            .expect("Failed to start tail");
-
-        let tail_stdout = tail.stdout.take().expect("Failed to get tail stdout");
-        let mut tail_buf_reader = tokio::io::BufReader::new(tail_stdout);
-
-        loop {
-            let mut buf = String::new();
-            tail_buf_reader
-                .read_line(&mut buf)
-                .await
-                .expect("Failed to read line");
-            line_output.send(buf).await.expect("Failed to send line");
+            .stderr(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to follow the events of the container: {:?}", e);
+                return;
+            }
+        };
+        if let Some(stdout) = child.stdout.take() {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if let Some(limit) = runc::limit_hit(&line) {
+                    let _ = limit_tx.send(limit);
+                }
+            }
         }
-    });
-    tail_handle
+        let _ = child.wait().await;
+        time::sleep(CONTAINER_EVENTS_RETRY_INTERVAL).await;
+    }
 }
 
-async fn handle_incoming_log_message(
-    line: &str,
-    local_input_log_path: &PathBuf,
-) -> Option<RunnerMessage> {
-    if line.starts_with("RUNNER_CONFIGURATION_DONE") {
-        Some(RunnerMessage::ConfigurationComplete)
-    } else if line.starts_with("RUNNER_FINISHED") {
-        debug!("Runner finished");
-        None
-    } else if line.starts_with("GIT_HASH") {
-        let commit_hash = extract_value_from_line(line).unwrap();
-        Some(RunnerMessage::CommitHash { commit_hash })
-    } else if line.starts_with("ARTIFACT_NAME_AND_HASH") {
-        let artifact_name_and_hash = extract_value_from_line(line).unwrap();
-        let (artifact_name, artifact_hash) = artifact_name_and_hash
-            .split_once(';')
-            .expect("bad ARTIFACT_NAME_AND_HASH format");
-        Some(RunnerMessage::ArtifactNameAndHash {
-            artifact_name: artifact_name.to_string(),
-            artifact_hash: artifact_hash.to_string(),
-            local_input_log_path: local_input_log_path.to_owned(),
-        })
-    } else if line.starts_with("LOG") {
-        // message is everything after LOG
-        let message = line.split_whitespace().nth(1).unwrap().to_string();
-        Some(RunnerMessage::LogMessage { message })
-    } else if line.starts_with("TIMESTAMP") {
-        // starts with TIMESTAMP followed by a marker and a datetime (all separated by a space)
-        let mut parts = line.split_whitespace();
-        let marker = parts.nth(1).unwrap().to_string();
-        let datetime = parts.next().unwrap().to_string();
-        Some(RunnerMessage::TimestampMessage { marker, datetime })
+async fn signal_process(pid: Option<u32>, signal: &str) -> anyhow::Result<()> {
+    let Some(pid) = pid else {
+        // the process has already been reaped
+        return Ok(());
+    };
+    let output = Command::new("kill")
+        .arg(format!("-{}", signal))
+        .arg(pid.to_string())
+        .output()
+        .await?;
+    if !output.status.success() {
+        warn!("Failed to send SIG{} to {}: {:?}", signal, pid, output);
+    }
+    Ok(())
+}
+
+/// Turns a line of the output log into a message for the enclave client. A malformed hook event
+/// fails the job, as it would otherwise be impossible to tell which artifacts were reported.
+async fn handle_incoming_log_message(line: &str, hook_paths: &HookPaths) -> Option<RunnerMessage> {
+    let event = match parse_hook_line(line) {
+        Ok(Some(event)) => event,
+        Ok(None) => return None,
+        Err(e) => {
+            warn!("Malformed hook event {:?}: {:#}", line, e);
+            return Some(RunnerMessage::Failed {
+                detail: format!("malformed hook event: {:#}", e),
+            });
+        }
+    };
+    match event {
+        HookEvent::RunnerConfigurationDone => Some(RunnerMessage::ConfigurationComplete),
+        HookEvent::RunnerFinished => {
+            debug!("Runner finished");
+            None
+        }
+        HookEvent::CheckoutComplete { workflow_ref } => {
+            // hashing the source tree takes a while for large repositories
+            let local_checkout_path = hook_paths.local_checkout_path.clone();
+            let local_commit_hash_path = hook_paths.local_commit_hash_path.clone();
+            let build_environment = hook_paths.build_environment.clone();
+            let cut_off_link = hook_paths.cut_off_link.clone();
+            let measured = task::spawn_blocking(move || {
+                measure_checkout(
+                    &local_checkout_path,
+                    &local_commit_hash_path,
+                    build_environment,
+                    cut_off_link.as_deref(),
+                )
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|measured| measured);
+            match measured {
+                Ok(checkout) => Some(RunnerMessage::CheckoutMeasured {
+                    checkout: MeasuredCheckout {
+                        workflow_ref,
+                        ..checkout
+                    },
+                }),
+                Err(e) => Some(RunnerMessage::Failed {
+                    detail: format!("failed to measure the checkout: {:#}", e),
+                }),
+            }
+        }
+        HookEvent::Artifact { path } => {
+            // hashing large artifacts takes a while
+            let artifacts_path = hook_paths.local_artifacts_path.clone();
+            let artifact_name = path.clone();
+            let hashed =
+                task::spawn_blocking(move || artifacts::hash_artifact(&artifacts_path, &path))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|hash| hash);
+            match hashed {
+                Ok(artifact_hash) => {
+                    debug!("Hashed artifact {}: {}", artifact_name, artifact_hash);
+                    Some(RunnerMessage::ArtifactNameAndHash {
+                        artifact_name,
+                        artifact_hash,
+                    })
+                }
+                Err(e) => Some(RunnerMessage::Failed {
+                    detail: format!("failed to hash artifact {:?}: {:#}", artifact_name, e),
+                }),
+            }
+        }
+        HookEvent::BuildComplete => Some(RunnerMessage::BuildComplete {
+            local_input_log_path: hook_paths.local_input_log_path.clone(),
+        }),
+        HookEvent::Log { message } => Some(RunnerMessage::LogMessage { message }),
+        HookEvent::Timestamp { marker, datetime } => {
+            Some(RunnerMessage::TimestampMessage { marker, datetime })
+        }
+    }
+}
+
+/// Reads the checked out commit ourselves instead of trusting a value from the output log, which
+/// any build step can write to, and hashes the source tree (submodules and local modifications
+/// are not covered by the commit). The downloads pinned by its lockfiles are listed as well. The
+/// pre hook blocks until we answer, so that no build step can run (and change the checkout)
+/// before the measurement. For hermetic builds, this is also the moment to cut off the network
+/// (by removing `cut_off_link`).
+fn measure_checkout(
+    local_checkout_path: &Path,
+    local_commit_hash_path: &Path,
+    build_environment: BuildEnvironment,
+    cut_off_link: Option<&str>,
+) -> anyhow::Result<MeasuredCheckout> {
+    let commit_hash = git::read_head_commit(local_checkout_path)?;
+    let source_tree_digest = source_tree::source_tree_digest(local_checkout_path)?;
+    let fetched_inputs = lockfiles::fetched_inputs(local_checkout_path)?;
+    debug!(
+        "Measured commit {} with source tree {} and {} fetched input(s) in {:?}",
+        commit_hash,
+        source_tree_digest,
+        fetched_inputs.len(),
+        local_checkout_path
+    );
+    if let Some(link) = cut_off_link {
+        network::cut_off(link)?;
+    }
+    std::fs::write(local_commit_hash_path, &commit_hash)?;
+    Ok(MeasuredCheckout {
+        commit_hash,
+        source_tree_digest,
+        build_environment,
+        fetched_inputs,
+        workflow_ref: None,
+    })
+}
+
+/// Where the pre hook checks out the repository (`GITHUB_WORKSPACE`): `_work/<name>/<name>` for
+/// the actual runner and `simulated_workspace/<name>` for the simulated one.
+fn get_checkout_path(
+    runner_path: &Path,
+    use_fake_runner: bool,
+    github_repository: &str,
+) -> anyhow::Result<PathBuf> {
+    let repository_name = github_repository
+        .split_once('/')
+        .map_or(github_repository, |(_, name)| name);
+    let mut components = Path::new(repository_name).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(std::path::Component::Normal(_)), None)
+    ) {
+        anyhow::bail!("Invalid repository name: {:?}", github_repository);
+    }
+
+    if use_fake_runner {
+        Ok(runner_path
+            .join("simulated_workspace")
+            .join(repository_name))
     } else {
-        None
+        Ok(runner_path
+            .join("_work")
+            .join(repository_name)
+            .join(repository_name))
+    }
+}
+
+fn remove_stale_file(path: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
     }
 }
 
@@ -505,10 +1091,6 @@ fn get_output_log_path(runner_path: &Path) -> anyhow::Result<PathBuf> {
     Ok(path)
 }
 
-fn extract_value_from_line(line: &str) -> Option<String> {
-    Some(line.split('=').nth(1).unwrap().trim().to_string())
-}
-
 async fn ensure_empty_output_log_file(local_output_log_path: &Path) -> anyhow::Result<()> {
     // replace any previous output file content
     std::fs::write(local_output_log_path, "")?;
@@ -529,6 +1111,30 @@ async fn ensure_empty_output_log_file(local_output_log_path: &Path) -> anyhow::R
     Ok(())
 }
 
+async fn ensure_empty_artifacts_dir(local_artifacts_path: &Path) -> anyhow::Result<()> {
+    // remove the artifacts of any previous job
+    match std::fs::remove_dir_all(local_artifacts_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    std::fs::create_dir_all(local_artifacts_path)?;
+
+    // change ownership to runner user, so that the attestation hook can copy the artifacts
+    let _ = tokio::process::Command::new("chown")
+        .arg("runner:runner")
+        .arg(local_artifacts_path)
+        .output()
+        .await?;
+
+    // change permission to so that runner can write and everyone can read
+    let _ = tokio::process::Command::new("chmod")
+        .arg("755")
+        .arg(local_artifacts_path)
+        .output()
+        .await?;
+    Ok(())
+}
+
 async fn ensure_empty_input_log_file(local_input_log_path: &Path) -> anyhow::Result<()> {
     // replace any previous output file content
     std::fs::write(local_input_log_path, "")?;
@@ -567,25 +1173,181 @@ mod tests {
     use super::*;
     use common::parse_fake_runner_args;
 
-    #[test]
-    fn test_extract_value_from_line() {
-        let line = "GIT_HASH=123456";
-        let value = extract_value_from_line(line).unwrap();
-        assert_eq!(value, "123456");
+    fn sample_hook_paths(dir: &Path) -> HookPaths {
+        HookPaths {
+            local_input_log_path: dir.join("input.log"),
+            local_commit_hash_path: dir.join(COMMIT_HASH_FILE),
+            local_checkout_path: dir.join("checkout"),
+            local_artifacts_path: dir.join(ARTIFACTS_DIR),
+            build_environment: BuildEnvironment::default(),
+            cut_off_link: None,
+        }
     }
 
     #[test]
-    fn test_extract_value_from_line_with_separator_in_value() {
-        let line = "ARTIFACT_NAME_AND_HASH=artifact-name;artifact-hash";
-        let value = extract_value_from_line(line).unwrap();
-        assert_eq!(value, "artifact-name;artifact-hash");
+    fn test_sandbox_jobs_get_their_own_container_and_bundle() {
+        let a =
+            SandboxRunnerManager::new(None, "2.0".to_string(), None, Default::default(), None, 1)
+                .unwrap();
+        let b =
+            SandboxRunnerManager::new(None, "2.0".to_string(), None, Default::default(), None, 2)
+                .unwrap();
+        assert_ne!(a.container_id, b.container_id);
+        assert!(a.container_id.starts_with(CONTAINER_ID_PREFIX));
+        assert_ne!(a.local_bundle_path, b.local_bundle_path);
+        assert!(a.local_output_path.starts_with(&a.local_bundle_path));
+
+        // the bundle is gone however the job ends
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("bundle");
+        std::fs::create_dir_all(dir.join("output")).unwrap();
+        std::fs::write(dir.join("output/output.log"), "log").unwrap();
+        drop(BundleCleanup {
+            program: "true",
+            container_id: a.container_id,
+            local_bundle_path: dir.clone(),
+        });
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_handle_incoming_artifacts_and_build_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let hook_paths = sample_hook_paths(dir.path());
+        std::fs::create_dir_all(&hook_paths.local_artifacts_path).unwrap();
+        std::fs::write(hook_paths.local_artifacts_path.join("app.tar.gz"), "test").unwrap();
+
+        let message = handle_incoming_log_message(
+            r#"{"version":4,"type":"artifact","path":"app.tar.gz"}"#,
+            &hook_paths,
+        )
+        .await;
+        let Some(RunnerMessage::ArtifactNameAndHash {
+            artifact_name,
+            artifact_hash,
+        }) = message
+        else {
+            panic!("expected an artifact");
+        };
+        assert_eq!(artifact_name, "app.tar.gz");
+        assert_eq!(
+            artifact_hash,
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+        );
+
+        // the artifact has to be in the artifacts directory
+        let message = handle_incoming_log_message(
+            r#"{"version":4,"type":"artifact","path":"missing.tar.gz"}"#,
+            &hook_paths,
+        )
+        .await;
+        assert!(matches!(message, Some(RunnerMessage::Failed { .. })));
+
+        let message =
+            handle_incoming_log_message(r#"{"version":4,"type":"build_complete"}"#, &hook_paths)
+                .await;
+        let Some(RunnerMessage::BuildComplete {
+            local_input_log_path,
+        }) = message
+        else {
+            panic!("expected the end of the build");
+        };
+        assert_eq!(local_input_log_path, hook_paths.local_input_log_path);
+    }
+
+    #[tokio::test]
+    async fn test_handle_incoming_log_message_with_bad_input() {
+        let hook_paths = sample_hook_paths(Path::new("output"));
+
+        let message = handle_incoming_log_message("some output", &hook_paths).await;
+        assert!(message.is_none());
+
+        // used to panic the enclave client
+        let message = handle_incoming_log_message(
+            r#"{"version":4,"type":"artifact","name":"app"}"#,
+            &hook_paths,
+        )
+        .await;
+        assert!(matches!(message, Some(RunnerMessage::Failed { .. })));
+
+        let message = handle_incoming_log_message(
+            r#"{"version":4,"type":"log","message":"two words"}"#,
+            &hook_paths,
+        )
+        .await;
+        let Some(RunnerMessage::LogMessage { message }) = message else {
+            panic!("expected a log message");
+        };
+        assert_eq!(message, "two words");
+
+        // the commit hash is measured by us, not reported
+        let message = handle_incoming_log_message(
+            r#"{"version":4,"type":"commit_hash","commit_hash":"0123456789abcdef0123456789abcdef01234567"}"#,
+            &hook_paths,
+        )
+        .await;
+        assert!(matches!(message, Some(RunnerMessage::Failed { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_checkout_complete_measures_the_checkout() {
+        let dir = tempfile::tempdir().unwrap();
+        let hook_paths = sample_hook_paths(dir.path());
+        let commit = "0123456789abcdef0123456789abcdef01234567";
+        std::fs::create_dir_all(hook_paths.local_checkout_path.join(".git")).unwrap();
+        std::fs::write(hook_paths.local_checkout_path.join(".git/HEAD"), commit).unwrap();
+        std::fs::write(hook_paths.local_checkout_path.join("main.rs"), "").unwrap();
+        std::fs::write(
+            hook_paths.local_checkout_path.join("go.sum"),
+            "github.com/a/b v1.0.0 h1:aaaa=\n",
+        )
+        .unwrap();
+
+        let message = handle_incoming_log_message(
+            r#"{"version":4,"type":"checkout_complete","workflow_ref":"o/r/b.yml@refs/heads/main"}"#,
+            &hook_paths,
+        )
+        .await;
+        let Some(RunnerMessage::CheckoutMeasured { checkout }) = message else {
+            panic!("expected the measured checkout");
+        };
+        assert_eq!(checkout.commit_hash, commit);
+        assert_eq!(
+            checkout.source_tree_digest,
+            source_tree::source_tree_digest(&hook_paths.local_checkout_path).unwrap()
+        );
+        assert_eq!(checkout.fetched_inputs.len(), 1);
+        assert_eq!(
+            checkout.workflow_ref.as_deref(),
+            Some("o/r/b.yml@refs/heads/main")
+        );
+        // the pre hook waits for this
+        assert_eq!(
+            std::fs::read_to_string(&hook_paths.local_commit_hash_path).unwrap(),
+            commit
+        );
+
+        std::fs::remove_dir_all(hook_paths.local_checkout_path.join(".git")).unwrap();
+        let message =
+            handle_incoming_log_message(r#"{"version":4,"type":"checkout_complete"}"#, &hook_paths)
+                .await;
+        assert!(matches!(message, Some(RunnerMessage::Failed { .. })));
     }
 
     #[test]
-    fn test_extract_value_from_line_with_no_value() {
-        let line = "GIT_HASH=";
-        let value = extract_value_from_line(line).unwrap();
-        assert_eq!(value, "");
+    fn test_get_checkout_path() {
+        let runner_path = PathBuf::from("github-runner/2.278.0");
+        assert_eq!(
+            get_checkout_path(&runner_path, false, "owner/repo").unwrap(),
+            PathBuf::from("github-runner/2.278.0/_work/repo/repo")
+        );
+        let runner_path = PathBuf::from("github-runner/simulated");
+        assert_eq!(
+            get_checkout_path(&runner_path, true, "owner/repo").unwrap(),
+            PathBuf::from("github-runner/simulated/simulated_workspace/repo")
+        );
+        assert!(get_checkout_path(&runner_path, true, "owner/..").is_err());
+        assert!(get_checkout_path(&runner_path, true, "owner/a/b").is_err());
     }
 
     #[test]
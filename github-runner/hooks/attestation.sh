@@ -1,7 +1,7 @@
 #!/bin/bash
 set -e;
 
-echo "Running ATTESTATION_HOOK ($0) with ARTIFACT_PATH=$1"
+echo "Running ATTESTATION_HOOK ($0) with ARTIFACT_PATHS=$*"
 
 SCRIPT_PATH=$(realpath "$0")
 SCRIPT_DIR=$(dirname "$SCRIPT_PATH")
@@ -12,17 +12,45 @@ OUTPUT_LOG="$SCRIPT_DIR/../output/output.log"
 INPUT_LOG="$SCRIPT_DIR/../output/input.log"
 # echo "INPUT_LOG=$INPUT_LOG"
 
-# Output the artifact name and hash to the output log (which then gets picked up by the Enclave Client)
-ARTIFACT_PATH=$1
-echo "ARTIFACT_NAME_AND_HASH=$(basename $ARTIFACT_PATH);$(sha256sum $ARTIFACT_PATH | cut -d ' ' -f 1)" >> "$OUTPUT_LOG"
+ARTIFACTS_DIR="$SCRIPT_DIR/../output/artifacts"
 
-# Wait for the input log to contain at least one line and then write it into a .cert file based on the artifact path
-CERT_PATH="$ARTIFACT_PATH.cert"
+if [ "$#" -eq 0 ]; then
+  echo "Usage: $0 ARTIFACT_PATH..."
+  exit 1
+fi
+
+# The artifacts are attested under their file name, so two artifacts with the same name would overwrite each other
+declare -A ARTIFACT_NAMES
+for ARTIFACT_PATH in "$@"; do
+  ARTIFACT_NAME=$(basename "$ARTIFACT_PATH")
+  if [ -n "${ARTIFACT_NAMES[$ARTIFACT_NAME]}" ]; then
+    echo "Artifacts ${ARTIFACT_NAMES[$ARTIFACT_NAME]} and $ARTIFACT_PATH have the same name $ARTIFACT_NAME, rename one of them"
+    exit 1
+  fi
+  ARTIFACT_NAMES[$ARTIFACT_NAME]="$ARTIFACT_PATH"
+done
+
+# Copy each artifact into the artifacts directory and name it in the output log (which then gets picked up by the
+# Enclave Client, which hashes the copy itself) and mark the end of the build, so that all artifacts are attested
+# together (see enclave-client/src/hook_protocol.rs)
+for ARTIFACT_PATH in "$@"; do
+  ARTIFACT_NAME=$(basename "$ARTIFACT_PATH")
+  cp -- "$ARTIFACT_PATH" "$ARTIFACTS_DIR/$ARTIFACT_NAME"
+  jq -cn --arg path "$ARTIFACT_NAME" '{version: 4, type: "artifact", path: $path}' >> "$OUTPUT_LOG"
+done
+echo '{"version":4,"type":"build_complete"}' >> "$OUTPUT_LOG"
+
+# Wait for the input log to contain at least one line and then write it into a .cert file next to each artifact,
+# together with the attested SLSA provenance as .intoto.json (byte for byte, so that its SHA-256 matches the claims)
 while [ ! -s "$INPUT_LOG" ]; do
   echo "Waiting for attestation result..."
   sleep 1
 done
-cp "$INPUT_LOG" "$CERT_PATH"
+for ARTIFACT_PATH in "$@"; do
+  CERT_PATH="$ARTIFACT_PATH.cert"
+  cp "$INPUT_LOG" "$CERT_PATH"
+  jq -j .provenance "$INPUT_LOG" > "$ARTIFACT_PATH.intoto.json"
+done
 
 echo "Content of $CERT_PATH:"
 cat "$CERT_PATH" | jq
@@ -5,4 +5,10 @@ SCRIPT_PATH=$(realpath "$0")
 SCRIPT_DIR=$(dirname "$SCRIPT_PATH")
 OUTPUT_LOG="$SCRIPT_DIR/../output/output.log"
 
-echo "$@" >> "$OUTPUT_LOG"
+if [ "$#" -eq 0 ]; then
+  echo "Usage: $0 MESSAGE..."
+  exit 1
+fi
+
+# Forward the whole message (all arguments) as a hook event, see enclave-client/src/hook_protocol.rs
+jq -cn --arg message "$*" '{version: 4, type: "log", message: $message}' >> "$OUTPUT_LOG"
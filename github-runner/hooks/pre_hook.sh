@@ -12,7 +12,7 @@ SCRIPT_DIR=$(dirname "$SCRIPT_PATH")
 OUTPUT_LOG="$SCRIPT_DIR/../output/output.log"
 
 # Checkout repository using PAT do working dir
-echo "TIMESTAMP PRE_CHECKOUT $(date -Ins)" >> "$OUTPUT_LOG"
+echo "{\"version\":4,\"type\":\"timestamp\",\"marker\":\"PRE_CHECKOUT\",\"datetime\":\"$(date -Ins)\"}" >> "$OUTPUT_LOG"
 
 rm -rf "$RUNNER_WORKSPACE"
 mkdir -p "$RUNNER_WORKSPACE"
@@ -35,11 +35,17 @@ git submodule init
 git submodule update --depth 1
 popd
 
-echo "TIMESTAMP POST_CHECKOUT $(date -Ins)" >> "$OUTPUT_LOG"
+echo "{\"version\":4,\"type\":\"timestamp\",\"marker\":\"POST_CHECKOUT\",\"datetime\":\"$(date -Ins)\"}" >> "$OUTPUT_LOG"
 popd
 
-pushd "$GITHUB_WORKSPACE"
-GIT_HASH=$(git rev-parse HEAD)
-echo "OUTPUT_LOG=$OUTPUT_LOG"
-echo "GIT_HASH=$GIT_HASH" >> "$OUTPUT_LOG"
-popd
\ No newline at end of file
+# The enclave client measures the checked out commit itself (anything in the output log could be forged by the
+# build), so we wait until it has done so before any build step can touch the checkout. The workflow goes into the
+# provenance of the build.
+COMMIT_HASH_FILE="$SCRIPT_DIR/../output/commit_hash"
+jq -cn --arg workflow_ref "${GITHUB_WORKFLOW_REF:-}" \
+  '{version: 4, type: "checkout_complete"} + if $workflow_ref == "" then {} else {workflow_ref: $workflow_ref} end' \
+  >> "$OUTPUT_LOG"
+while [ ! -s "$COMMIT_HASH_FILE" ]; do
+  sleep 0.1
+done
+echo "Measured commit: $(cat "$COMMIT_HASH_FILE")"
\ No newline at end of file
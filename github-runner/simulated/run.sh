@@ -14,6 +14,9 @@ export RUNNER_WORKSPACE="$SCRIPT_DIR/simulated_workspace"
 REPOSITORY_NAME=$(echo "$GITHUB_REPOSITORY" | cut -d'/' -f2)
 export GITHUB_WORKSPACE="$RUNNER_WORKSPACE/$REPOSITORY_NAME"
 
+# There is no workflow file, so we name the build script instead (in the format of the actual runner)
+export GITHUB_WORKFLOW_REF="$GITHUB_REPOSITORY/${SUBPROJECT_DIR:-.}/build.sh@refs/heads/${GITHUB_REF_NAME:-main}"
+
 /bin/bash "$ACTIONS_RUNNER_HOOK_JOB_STARTED"
 pushd "$GITHUB_WORKSPACE"
 
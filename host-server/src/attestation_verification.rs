@@ -0,0 +1,249 @@
+use anyhow::Context;
+use aws_nitro_enclaves_cose::crypto::Openssl;
+use aws_nitro_enclaves_cose::CoseSign1;
+use nsm_io::AttestationDoc;
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509StoreContext, X509};
+
+/// The root certificate of the AWS Nitro Enclaves PKI (same as `verifier-client/src/root.pem`).
+static NITRO_ROOT_CERT: &[u8] = include_bytes!("root.pem");
+
+/// Verify the attestation document that the enclave client sent along with the public key of the
+/// secure channel. We check that the document is signed by a certificate that chains up to the
+/// Nitro root (or the given PEM root, e.g. the one of the NSM emulator), that it covers exactly this
+/// public key and our nonce, and (if given) that PCR0 matches the expected enclave image.
+pub fn verify_channel_key_attestation(
+    attestation_document: &[u8],
+    public_key: &[u8],
+    nonce: &[u8],
+    expected_pcr0: Option<&[u8]>,
+    root_cert_pem: Option<&[u8]>,
+) -> anyhow::Result<()> {
+    let root_cert = X509::from_pem(root_cert_pem.unwrap_or(NITRO_ROOT_CERT))
+        .context("Failed to parse the root cert")?;
+    verify_channel_key_attestation_with_root(
+        attestation_document,
+        public_key,
+        nonce,
+        expected_pcr0,
+        &root_cert,
+    )
+}
+
+fn verify_channel_key_attestation_with_root(
+    attestation_document: &[u8],
+    public_key: &[u8],
+    nonce: &[u8],
+    expected_pcr0: Option<&[u8]>,
+    root_cert: &X509,
+) -> anyhow::Result<()> {
+    let attestation_doc = verify_attestation_document(attestation_document, root_cert)?;
+
+    if attestation_doc.public_key.as_deref().map(|k| k.as_slice()) != Some(public_key) {
+        anyhow::bail!("The attestation document does not cover the channel key");
+    }
+    if attestation_doc.nonce.as_deref().map(|n| n.as_slice()) != Some(nonce) {
+        anyhow::bail!("The attestation document does not cover our nonce");
+    }
+    if let Some(expected_pcr0) = expected_pcr0 {
+        let pcr0 = attestation_doc
+            .pcrs
+            .get(&0)
+            .context("The attestation document has no PCR0")?;
+        if pcr0.as_slice() != expected_pcr0 {
+            anyhow::bail!(
+                "PCR0 mismatch: expected {} but the enclave reports {}",
+                hex::encode(expected_pcr0),
+                hex::encode(pcr0)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Check the COSE signature and the certificate chain, and return the signed payload.
+fn verify_attestation_document(
+    attestation_document: &[u8],
+    root_cert: &X509,
+) -> anyhow::Result<AttestationDoc> {
+    let cose_sign_1 = CoseSign1::from_bytes(attestation_document)
+        .context("Failed to parse the attestation document")?;
+    let payload = cose_sign_1
+        .get_payload::<Openssl>(None)
+        .context("Failed to read the attestation document payload")?;
+    let attestation_doc = AttestationDoc::from_binary(&payload)
+        .map_err(|e| anyhow::anyhow!("Failed to decode the attestation document: {:?}", e))?;
+
+    let certificate = X509::from_der(&attestation_doc.certificate)
+        .context("Failed to parse the attestation certificate")?;
+    let signature_valid = cose_sign_1
+        .verify_signature::<Openssl>(certificate.public_key()?.as_ref())
+        .context("Failed to verify the attestation signature")?;
+    if !signature_valid {
+        anyhow::bail!("Invalid attestation signature");
+    }
+
+    let mut trusted_builder = X509StoreBuilder::new()?;
+    trusted_builder.add_cert(root_cert.clone())?;
+    let trusted_store = trusted_builder.build();
+
+    let mut intermediate_certs = Stack::new()?;
+    for cert in &attestation_doc.cabundle {
+        intermediate_certs.push(X509::from_der(cert).context("Failed to parse the CA bundle")?)?;
+    }
+
+    let mut store_context = X509StoreContext::new()?;
+    let chain_error = store_context.init(
+        &trusted_store,
+        &certificate,
+        &intermediate_certs,
+        |context| {
+            Ok(match context.verify_cert()? {
+                true => None,
+                false => Some(context.error()),
+            })
+        },
+    )?;
+    if let Some(e) = chain_error {
+        anyhow::bail!("Invalid attestation certificate chain: {}", e);
+    }
+
+    Ok(attestation_doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_nitro_enclaves_cose::header_map::HeaderMap;
+    use nsm_io::Digest;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::X509NameBuilder;
+    use std::collections::BTreeMap;
+
+    fn new_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn new_cert(
+        name: &str,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+        ca: bool,
+    ) -> X509 {
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        if ca {
+            builder
+                .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                .unwrap();
+        }
+        let (issuer_name, signing_key) = match issuer {
+            Some((issuer_cert, issuer_key)) => (issuer_cert.subject_name(), issuer_key),
+            None => (subject.as_ref(), key),
+        };
+        builder.set_issuer_name(issuer_name).unwrap();
+        builder.sign(signing_key, MessageDigest::sha384()).unwrap();
+        builder.build()
+    }
+
+    /// Returns a test root and a document signed by a leaf certificate issued by that root.
+    fn signed_document(public_key: &[u8], nonce: &[u8], pcr0: &[u8]) -> (X509, Vec<u8>) {
+        let root_key = new_key();
+        let root_cert = new_cert("root", &root_key, None, true);
+        let leaf_key = new_key();
+        let leaf_cert = new_cert("leaf", &leaf_key, Some((&root_cert, &root_key)), false);
+
+        let attestation_doc = AttestationDoc::new(
+            "test-module".to_string(),
+            Digest::SHA384,
+            0,
+            BTreeMap::from([(0, pcr0.to_vec())]),
+            leaf_cert.to_der().unwrap(),
+            vec![root_cert.to_der().unwrap()],
+            None,
+            Some(nonce.to_vec()),
+            Some(public_key.to_vec()),
+        );
+        let cose_sign_1 =
+            CoseSign1::new::<Openssl>(&attestation_doc.to_binary(), &HeaderMap::new(), &leaf_key)
+                .unwrap();
+        (root_cert, cose_sign_1.as_bytes(false).unwrap())
+    }
+
+    #[test]
+    fn test_valid_attestation() {
+        let (root_cert, document) = signed_document(b"key", b"nonce", b"pcr0");
+        verify_channel_key_attestation_with_root(
+            &document,
+            b"key",
+            b"nonce",
+            Some(b"pcr0"),
+            &root_cert,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_rejects_mismatches() {
+        let (root_cert, document) = signed_document(b"key", b"nonce", b"pcr0");
+        let verify = |public_key: &[u8], nonce: &[u8], pcr0: &[u8]| {
+            verify_channel_key_attestation_with_root(
+                &document,
+                public_key,
+                nonce,
+                Some(pcr0),
+                &root_cert,
+            )
+        };
+        assert!(verify(b"other key", b"nonce", b"pcr0").is_err());
+        assert!(verify(b"key", b"other nonce", b"pcr0").is_err());
+        assert!(verify(b"key", b"nonce", b"other pcr0").is_err());
+    }
+
+    #[test]
+    fn test_rejects_untrusted_root() {
+        let (_, document) = signed_document(b"key", b"nonce", b"pcr0");
+        let other_root = new_cert("other root", &new_key(), None, true);
+        let err = verify_channel_key_attestation_with_root(
+            &document,
+            b"key",
+            b"nonce",
+            None,
+            &other_root,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("certificate chain"));
+
+        // and of course the Nitro root does not trust our test root either
+        assert!(verify_channel_key_attestation(&document, b"key", b"nonce", None, None).is_err());
+    }
+
+    #[test]
+    fn test_verify_with_custom_root_pem() {
+        let (root_cert, document) = signed_document(b"key", b"nonce", b"pcr0");
+        let root_pem = root_cert.to_pem().unwrap();
+        verify_channel_key_attestation(&document, b"key", b"nonce", None, Some(&root_pem)).unwrap();
+    }
+}
@@ -1,48 +1,86 @@
-use crate::backend::shared::interact_with_enclave_client;
+use crate::backend::shared::{cancel_interaction, interact_with_enclave_client, InteractionConfig};
 use crate::log_publishing_service::AttestationEntry;
 use crate::BackendCommand;
+use anyhow::Context;
+use clap::ValueEnum;
+use common::transport::{Transport, TransportAddr};
 use common::{short_wait, EnclaveClientArgs};
 use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
 use std::process::Stdio;
 use tokio::process::{Child, Command};
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, Mutex};
 use tokio::task;
 use tokio_vsock::VsockAddr;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
+
+const FAILED_RUN_BUFFER_SIZE: usize = 10;
 
 pub struct LocalService {
     runner_args: EnclaveClientArgs,
     backend_command_rx: Receiver<BackendCommand>,
     log_entry_tx: Sender<AttestationEntry>,
+    interaction_config: InteractionConfig,
+    transport: Transport,
+    nsm_emulator: Option<LocalNsmEmulator>,
     active_children: Mutex<HashMap<u32, Box<LocalClient>>>,
+    failed_run_tx: Sender<u32>,
+    failed_run_rx: Receiver<u32>,
+}
+
+/// Lets the local clients produce real (but emulated) attestations, see the `--nsm-emulator-*`
+/// options of the enclave client.
+#[derive(Debug, Clone)]
+pub struct LocalNsmEmulator {
+    pub dir: PathBuf,
+
+    /// As `INDEX=HEX`.
+    pub pcrs: Vec<String>,
 }
 
 pub struct LocalClient {
     pub process: Child,
     pub interaction_task: task::JoinHandle<()>,
+    pub cancel_tx: oneshot::Sender<String>,
 }
 
-/// The local service starts clients as processes on the same system, but still communicates with
-/// them over vsock.
+/// The local service starts clients as processes on the same system. By default it communicates
+/// with them over vsock (via `vsock_loopback`), but Unix sockets and TCP are supported as well.
 impl LocalService {
     pub fn new(
         runner_args: EnclaveClientArgs,
         backend_command_rx: Receiver<BackendCommand>,
         log_entry_tx: Sender<AttestationEntry>,
+        interaction_config: InteractionConfig,
+        transport: Transport,
+        nsm_emulator: Option<LocalNsmEmulator>,
     ) -> Self {
         let active_children = Mutex::new(HashMap::new());
+        let (failed_run_tx, failed_run_rx) = mpsc::channel(FAILED_RUN_BUFFER_SIZE);
         Self {
             runner_args,
             backend_command_rx,
             log_entry_tx,
+            interaction_config,
+            transport,
+            nsm_emulator,
             active_children,
+            failed_run_tx,
+            failed_run_rx,
         }
     }
 
     pub async fn run(&mut self) -> anyhow::Result<()> {
         debug!("Local service is running");
         loop {
+            // tear down the clients whose interaction failed, e.g. because they stopped responding
+            while let Ok(run_id) = self.failed_run_rx.try_recv() {
+                error!("Run {} failed, tearing down its enclave client", run_id);
+                self.stop_client(run_id).await?;
+            }
+
             let maybe_command = self.backend_command_rx.try_recv();
             let Ok(command) = maybe_command else {
                 short_wait().await;
@@ -52,27 +90,35 @@ impl LocalService {
             debug!("Received a command: {:?}", command);
             match command {
                 BackendCommand::Start { run_id } => {
-                    let port_id = run_id + 10000;
+                    let addr = local_client_addr(self.transport, run_id)?;
                     let runner_args = self.runner_args.clone();
                     let log_entry_tx = self.log_entry_tx.clone();
+                    let interaction_config = self.interaction_config.clone();
+                    let failed_run_tx = self.failed_run_tx.clone();
 
-                    let process = spawn_local_client(port_id).await?;
+                    let process = spawn_local_client(&addr, self.nsm_emulator.as_ref()).await?;
+                    let (cancel_tx, cancel_rx) = oneshot::channel();
                     let interaction_task = task::spawn(async move {
                         debug!("Starting the interaction task with the enclave client");
                         let result = interact_with_enclave_client(
-                            VsockAddr::new(libc::VMADDR_CID_LOCAL, port_id),
+                            addr,
+                            run_id,
                             runner_args,
                             log_entry_tx,
+                            interaction_config,
+                            cancel_rx,
                         )
                         .await;
                         if let Err(e) = result {
                             error!("Failed to interact with the enclave client: {:?}", e);
+                            let _ = failed_run_tx.send(run_id).await;
                         }
                     });
 
                     let enclave_client = LocalClient {
                         process,
                         interaction_task,
+                        cancel_tx,
                     };
                     self.active_children
                         .lock()
@@ -81,29 +127,75 @@ impl LocalService {
                 }
 
                 BackendCommand::Stop { run_id } => {
-                    if let Some(mut enclave_client) =
-                        self.active_children.lock().await.remove(&run_id)
-                    {
-                        if enclave_client.process.try_wait()?.is_none() {
-                            debug!(
-                                "Killing the local enclave client process: {:?}",
-                                enclave_client.process
-                            );
-                            enclave_client.interaction_task.abort();
-                            enclave_client.process.kill().await?;
-                        } else {
-                            debug!("The local enclave client is already stopped");
-                        }
-                    }
+                    self.stop_client(run_id).await?;
                 }
             }
         }
     }
+
+    async fn stop_client(&self, run_id: u32) -> anyhow::Result<()> {
+        if let Some(enclave_client) = self.active_children.lock().await.remove(&run_id) {
+            let LocalClient {
+                mut process,
+                mut interaction_task,
+                cancel_tx,
+            } = *enclave_client;
+
+            // give the enclave client the chance to tear down the runner first
+            let reason = format!("run {} was stopped", run_id);
+            if !cancel_interaction(cancel_tx, &mut interaction_task, reason).await {
+                warn!("The enclave client did not acknowledge the cancellation");
+                interaction_task.abort();
+            }
+
+            if process.try_wait()?.is_none() {
+                debug!("Killing the local enclave client process: {:?}", process);
+                process.kill().await?;
+            } else {
+                debug!("The local enclave client is already stopped");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The address on which the local client listens and the host connects to. Run IDs come from
+/// GitHub and do not fit into a TCP port, so TCP uses a free port that the OS picks.
+fn local_client_addr(transport: Transport, run_id: u32) -> anyhow::Result<TransportAddr> {
+    Ok(match transport {
+        Transport::Vsock => {
+            let port_id = run_id
+                .checked_add(10000)
+                .with_context(|| format!("Run ID {} is too large for a vsock port", run_id))?;
+            TransportAddr::Vsock(VsockAddr::new(libc::VMADDR_CID_LOCAL, port_id))
+        }
+        Transport::Unix => {
+            TransportAddr::Unix(std::env::temp_dir().join(format!("enclave-client-{run_id}.sock")))
+        }
+        Transport::Tcp => {
+            let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+                .context("Failed to find a free port for the local client")?;
+            TransportAddr::Tcp(listener.local_addr()?)
+        }
+    })
 }
 
-pub async fn spawn_local_client(port_id: u32) -> anyhow::Result<Child> {
-    let child = Command::new("target/debug/enclave-client")
-        .arg(format!("1:{port_id}"))
+pub async fn spawn_local_client(
+    addr: &TransportAddr,
+    nsm_emulator: Option<&LocalNsmEmulator>,
+) -> anyhow::Result<Child> {
+    let mut command = Command::new("target/debug/enclave-client");
+    command
+        .arg(addr.to_string())
+        .arg("--transport")
+        .arg(addr.transport().to_possible_value().unwrap().get_name());
+    if let Some(nsm_emulator) = nsm_emulator {
+        command.arg("--nsm-emulator-dir").arg(&nsm_emulator.dir);
+        for pcr in &nsm_emulator.pcrs {
+            command.arg("--nsm-emulator-pcr").arg(pcr);
+        }
+    }
+    let child = command
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
         .spawn()?;
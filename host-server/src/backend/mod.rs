@@ -1,3 +1,3 @@
 pub mod local;
 pub mod nitro;
-mod shared;
+pub mod shared;
@@ -1,21 +1,23 @@
-use crate::backend::shared::interact_with_enclave_client;
+use crate::backend::shared::{cancel_interaction, interact_with_enclave_client, InteractionConfig};
 use crate::log_publishing_service::AttestationEntry;
 use crate::BackendCommand;
 use anyhow::Result;
+use common::transport::TransportAddr;
 use common::{short_wait, EnclaveClientArgs, RunnerStartMode};
 use std::collections::HashMap;
 use tokio::process::{Child, Command};
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, Mutex};
 use tokio::task;
 use tokio_vsock::VsockAddr;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 const HOST_VSOCK_ADDR_FOR_PROXY: &str = "3:5000"; // Ubuntu is normally "2" while AWS is "3"
 const NITRO_IMAGE_NAME_STAMP: &str = "enclave.eif"; // with the sandbox
 const NITRO_IMAGE_NAME_WET: &str = "enclave-wet.eif"; // without the sandbox but the GitHub runer
 const ENCLAVE_CLIENT_VSOCK_PORT: u32 = 11000; // keep in sync with `enclave-container/content/run.sh`
 const NITRO_ENCLAVE_CID: u32 = 42; // TODO: choose dynamically
+const FAILED_RUN_BUFFER_SIZE: usize = 10;
 
 #[derive(Debug, Clone)]
 struct NitroConfiguration {
@@ -51,7 +53,10 @@ pub struct NitroService {
     nitro_size: NitroSize,
     backend_command_rx: Receiver<BackendCommand>,
     log_entry_tx: Sender<AttestationEntry>,
+    interaction_config: InteractionConfig,
     active_enclaves: Mutex<HashMap<u32, Box<NitroClient>>>,
+    failed_run_tx: Sender<u32>,
+    failed_run_rx: Receiver<u32>,
 
     #[allow(dead_code)]
     host_proxy: Child,
@@ -60,6 +65,7 @@ pub struct NitroService {
 pub struct NitroClient {
     pub cid: u32,
     pub interaction_task: task::JoinHandle<()>,
+    pub cancel_tx: oneshot::Sender<String>,
 }
 
 impl NitroService {
@@ -68,16 +74,21 @@ impl NitroService {
         nitro_size: NitroSize,
         backend_command_rx: Receiver<BackendCommand>,
         log_entry_tx: Sender<AttestationEntry>,
+        interaction_config: InteractionConfig,
     ) -> Result<Self> {
         let active_enclaves = Mutex::new(HashMap::new());
         let host_proxy = start_host_proxy().await?;
+        let (failed_run_tx, failed_run_rx) = mpsc::channel(FAILED_RUN_BUFFER_SIZE);
 
         Ok(Self {
             enclave_client_args,
             nitro_size,
             backend_command_rx,
             log_entry_tx,
+            interaction_config,
             active_enclaves,
+            failed_run_tx,
+            failed_run_rx,
             host_proxy,
         })
     }
@@ -85,6 +96,12 @@ impl NitroService {
     pub async fn run(&mut self) -> Result<()> {
         debug!("Local service is running");
         loop {
+            // tear down the enclaves whose interaction failed, e.g. because they stopped responding
+            while let Ok(run_id) = self.failed_run_rx.try_recv() {
+                error!("Run {} failed, tearing down its enclave", run_id);
+                self.stop_enclave(run_id).await?;
+            }
+
             let maybe_command = self.backend_command_rx.try_recv();
             let Ok(command) = maybe_command else {
                 short_wait().await;
@@ -97,6 +114,8 @@ impl NitroService {
                     let cid = NITRO_ENCLAVE_CID;
                     let enclave_client_args = self.enclave_client_args.clone();
                     let log_entry_tx = self.log_entry_tx.clone();
+                    let interaction_config = self.interaction_config.clone();
+                    let failed_run_tx = self.failed_run_tx.clone();
 
                     spawn_nitro_enclave_client(
                         cid,
@@ -104,22 +123,28 @@ impl NitroService {
                         &self.nitro_size,
                     )
                     .await?;
+                    let (cancel_tx, cancel_rx) = oneshot::channel();
                     let interaction_task = task::spawn(async move {
                         debug!("Starting the interaction task with the enclave client");
                         let result = interact_with_enclave_client(
-                            VsockAddr::new(cid, ENCLAVE_CLIENT_VSOCK_PORT),
+                            TransportAddr::Vsock(VsockAddr::new(cid, ENCLAVE_CLIENT_VSOCK_PORT)),
+                            run_id,
                             enclave_client_args,
                             log_entry_tx,
+                            interaction_config,
+                            cancel_rx,
                         )
                         .await;
                         if let Err(e) = result {
                             error!("Failed to interact with the enclave client: {:?}", e);
+                            let _ = failed_run_tx.send(run_id).await;
                         }
                     });
 
                     let enclave_client = NitroClient {
                         cid,
                         interaction_task,
+                        cancel_tx,
                     };
                     self.active_enclaves
                         .lock()
@@ -128,15 +153,30 @@ impl NitroService {
                 }
 
                 BackendCommand::Stop { run_id } => {
-                    if let Some(enclave_client) = self.active_enclaves.lock().await.remove(&run_id)
-                    {
-                        enclave_client.interaction_task.abort();
-                        terminate_nitro_enclave(enclave_client.cid).await?;
-                    }
+                    self.stop_enclave(run_id).await?;
                 }
             }
         }
     }
+
+    async fn stop_enclave(&self, run_id: u32) -> Result<()> {
+        if let Some(enclave_client) = self.active_enclaves.lock().await.remove(&run_id) {
+            let NitroClient {
+                cid,
+                mut interaction_task,
+                cancel_tx,
+            } = *enclave_client;
+
+            // give the enclave client the chance to tear down the runner first
+            let reason = format!("run {} was stopped", run_id);
+            if !cancel_interaction(cancel_tx, &mut interaction_task, reason).await {
+                warn!("The enclave client did not acknowledge the cancellation");
+                interaction_task.abort();
+            }
+            terminate_nitro_enclave(cid).await?;
+        }
+        Ok(())
+    }
 }
 
 pub async fn start_host_proxy() -> Result<Child> {
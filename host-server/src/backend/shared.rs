@@ -1,21 +1,62 @@
+use crate::attestation_verification::verify_channel_key_attestation;
 use crate::log_publishing_service::AttestationEntry;
+use anyhow::Context;
+use common::attestation_document::AttestationDocument;
+use common::claims::Artifact;
 use common::messages::{
-    create_new_timestamp_now, log_timestamp, EnclaveToHostMessage, HostToEnclaveMessage, Message,
+    create_new_timestamp_now, log_timestamp, Capability, EnclaveStage, EnclaveToHostMessage, Hello,
+    HostToEnclaveMessage, Message, HEARTBEAT_INTERVAL,
 };
-use common::{protocol, EnclaveClientArgs};
+use common::protocol::{ProtocolConfig, ProtocolError};
+use common::secure_channel::{SecureChannel, SecureWriteHalf};
+use common::transport::{BoxedStream, TransportAddr};
+use common::{
+    protocol, secure_channel, transport, AttestationBackend, EnclaveClientArgs, RunnerStartMode,
+};
+use std::path::PathBuf;
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc::Sender;
-use tokio::time;
+use tokio::sync::{mpsc, oneshot};
 use tokio::time::{sleep, Instant};
-use tokio_vsock::{VsockAddr, VsockStream};
+use tokio::{task, time};
 use tracing::{debug, info, warn};
 
 const ENCLAVE_CONNECTION_TIMEOUT_SECS: u64 = 60;
+const ENCLAVE_CANCEL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Several missed heartbeats in a row, so that a busy enclave is not mistaken for a hung one.
+pub const DEFAULT_LIVENESS_TIMEOUT: Duration =
+    Duration::from_secs(6 * HEARTBEAT_INTERVAL.as_secs());
+const CHANNEL_NONCE_LEN: usize = 32;
+const ATTESTATION_CHALLENGE_LEN: usize = 32;
+
+/// Settings for the interaction with each enclave client.
+#[derive(Debug, Clone, Default)]
+pub struct InteractionConfig {
+    pub protocol_config: ProtocolConfig,
+
+    /// The PCR0 (enclave image measurement) the channel key attestation must report. Without it,
+    /// any Nitro enclave could receive the runner secrets.
+    pub expected_pcr0: Option<Vec<u8>>,
+
+    /// How long the enclave client may stay silent (it sends heartbeats every
+    /// `HEARTBEAT_INTERVAL`) before the job is considered failed. `None` waits forever.
+    pub liveness_timeout: Option<Duration>,
+
+    /// The root certificate (PEM) that channel key attestations have to chain up to instead of the
+    /// AWS Nitro root, e.g. the one of the NSM emulator. It is read for every job, as the emulator
+    /// only creates it once the first enclave client starts.
+    pub root_cert_path: Option<PathBuf>,
+}
 
 pub async fn interact_with_enclave_client(
-    addr: VsockAddr,
+    addr: TransportAddr,
+    run_id: u32,
     runner_args: EnclaveClientArgs,
     log_entry_tx: Sender<AttestationEntry>,
+    interaction_config: InteractionConfig,
+    cancel_rx: oneshot::Receiver<String>,
 ) -> anyhow::Result<()> {
     log_timestamp(&create_new_timestamp_now("ENCLAVE_STARTED"));
     debug!("Connecting to the enclave client on {:?}", addr);
@@ -25,8 +66,8 @@ pub async fn interact_with_enclave_client(
 
     // allow for multiple tries to connect to the enclave client
     let deadline = Instant::now() + Duration::from_secs(ENCLAVE_CONNECTION_TIMEOUT_SECS);
-    let stream: anyhow::Result<VsockStream> = loop {
-        match VsockStream::connect(addr).await {
+    let stream: anyhow::Result<BoxedStream> = loop {
+        match transport::connect(&addr).await {
             Ok(stream) => break Ok(stream),
             Err(e) => {
                 warn!("Failed to connect to the enclave client: {:?}", e);
@@ -38,73 +79,208 @@ pub async fn interact_with_enclave_client(
             }
         }
     };
-    let mut stream = stream?;
+    let stream = stream?;
     info!("Connected to the enclave client");
     log_timestamp(&create_new_timestamp_now("ENCLAVE_CONNECTED"));
 
-    // send runner args to the client
+    interact_over_stream(
+        stream,
+        run_id,
+        runner_args,
+        log_entry_tx,
+        interaction_config,
+        cancel_rx,
+    )
+    .await
+}
+
+/// Ask the enclave client to cancel the job and give it some time to tear down the runner.
+/// Returns `false` if the interaction is still running afterwards.
+pub async fn cancel_interaction(
+    cancel_tx: oneshot::Sender<String>,
+    interaction_task: &mut task::JoinHandle<()>,
+    reason: String,
+) -> bool {
+    if interaction_task.is_finished() {
+        return true;
+    }
+    if cancel_tx.send(reason).is_err() {
+        debug!("The interaction is already winding down");
+    }
+    time::timeout(ENCLAVE_CANCEL_TIMEOUT, interaction_task)
+        .await
+        .is_ok()
+}
+
+/// Runs the protocol with an already connected enclave client. This is independent of the
+/// transport, so that it can be tested over in-memory streams.
+async fn interact_over_stream<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    mut stream: S,
+    run_id: u32,
+    runner_args: EnclaveClientArgs,
+    log_entry_tx: Sender<AttestationEntry>,
+    interaction_config: InteractionConfig,
+    cancel_rx: oneshot::Receiver<String>,
+) -> anyhow::Result<()> {
+    // make sure that we speak the same protocol version before sending anything else
+    let host_hello = Hello::new(Capability::ALL);
+    protocol::write_hello(&mut stream, &host_hello).await?;
+    let enclave_hello =
+        protocol::read_hello(&mut stream, &interaction_config.protocol_config).await?;
+    host_hello.ensure_compatible(&enclave_hello, "enclave-client")?;
+    let capabilities = host_hello.negotiate(&enclave_hello);
+    debug!(
+        "Negotiated capabilities with the enclave client: {:?}",
+        capabilities
+    );
+
+    // the runner args contain secrets, so we only send them over the secure channel
+    let channel =
+        establish_secure_channel(stream, runner_args.attestation_backend, &interaction_config)
+            .await?;
+    let (reader, mut writer) = channel.split();
+    let attestation_nonce = attestation_nonce(run_id)?;
+    let hermetic = runner_args.hermetic_cache_path.is_some();
+    let image_digest = runner_args
+        .build_environment_image
+        .as_ref()
+        .map(|image| image.digest.clone());
+    let sandboxed = !matches!(runner_args.runner_start_mode, RunnerStartMode::Direct);
     let message = Message::HostToEnclave(HostToEnclaveMessage::StartRunner {
-        enclave_client_args: runner_args,
+        enclave_client_args: Box::new(runner_args),
+        run_id,
+        attestation_nonce: attestation_nonce.clone(),
     });
-    protocol::write_message(&mut stream, &message).await?;
+    writer.write_message(&message).await?;
     debug!("Sent the runner args to the enclave client");
+    let liveness_timeout = if capabilities.contains(&Capability::Heartbeat) {
+        interaction_config.liveness_timeout
+    } else {
+        warn!("The enclave client does not send heartbeats, a hung enclave will not be detected");
+        None
+    };
+    let mut connection = EnclaveConnection {
+        messages: reader.into_message_receiver(),
+        writer,
+        cancel_rx,
+        cancel_requested: false,
+        liveness_timeout,
+        last_seen: Instant::now(),
+        last_stage: None,
+    };
 
     // expect an OK message
-    let message = protocol::read_next_message(&mut stream).await?;
-    let Message::EnclaveToHost(EnclaveToHostMessage::Ok { info }) = message else {
+    let Some(message) = connection
+        .next_message()
+        .await
+        .context("Failed to read the configuration result from the enclave client")?
+    else {
+        return Ok(());
+    };
+    let EnclaveToHostMessage::Ok { info } = message else {
         anyhow::bail!("Expected an OK message, got: {:?}", message);
     };
     debug!("Received an OK message: {:?}", info);
     log_timestamp(&create_new_timestamp_now("CONFIG_DONE"));
 
     // now we can start the main loop of interacting with the enclave client
-    // we wait for a commit hash, an artifact report, and an attestation report (after which we end)
+    // we wait for a commit hash, the artifact reports, and an attestation report (after which we end)
     // we might also get log and timestamp messages
-    let mut maybe_commit_hash = None;
-    let mut maybe_artifact_hash = None;
-    let mut maybe_artifact_name = None;
+    let mut maybe_repository_root = None;
+    let mut artifacts = vec![];
 
-    while let Ok(message) = protocol::read_next_message(&mut stream).await {
+    loop {
+        // the enclave is only semi-trusted: a closed stream, an oversized frame or a timeout
+        // all end the interaction with a descriptive error
+        let Some(message) = connection.next_message().await? else {
+            return Ok(());
+        };
         match message {
-            Message::EnclaveToHost(EnclaveToHostMessage::ReportRepositoryRoot { commit_hash }) => {
-                maybe_commit_hash = Some(commit_hash.clone());
-                debug!("Received the commit hash: {}", commit_hash);
+            EnclaveToHostMessage::ReportRepositoryRoot {
+                commit_hash,
+                source_tree_digest,
+            } => {
+                debug!(
+                    "Received the commit hash: {} (source tree {})",
+                    commit_hash, source_tree_digest
+                );
+                maybe_repository_root = Some((commit_hash, source_tree_digest));
             }
-            Message::EnclaveToHost(EnclaveToHostMessage::ReportArtifact {
+            EnclaveToHostMessage::ReportArtifact {
                 artifact_hash,
                 artifact_name,
-            }) => {
-                maybe_artifact_hash = Some(artifact_hash.clone());
-                maybe_artifact_name = Some(artifact_name.clone());
+            } => {
                 debug!(
                     "Received the artifact report: {} {}",
                     artifact_name, artifact_hash
                 );
+                artifacts.push(Artifact {
+                    name: artifact_name,
+                    hash: artifact_hash,
+                });
             }
-            Message::EnclaveToHost(EnclaveToHostMessage::ReportAttestation {
+            EnclaveToHostMessage::ReportAttestation {
                 attestation_document,
-            }) => {
+            } => {
                 debug!(
                     "Received the attestation report: {:?}",
                     attestation_document
                 );
-                let commit_hash = maybe_commit_hash.take().expect("Missing commit hash");
-                let artifact_hash = maybe_artifact_hash.take().expect("Missing artifact hash");
-                let artifact_name = maybe_artifact_name.take().expect("Missing artifact name");
-
-                let attestation_entry = AttestationEntry {
-                    commit_hash,
-                    artifact_hash,
-                    artifact_name,
-                    attestation_document,
-                };
-                log_entry_tx.send(attestation_entry).await?;
+                let (commit_hash, source_tree_digest) = maybe_repository_root
+                    .take()
+                    .context("The attestation was reported before the commit hash")?;
+                if artifacts.is_empty() {
+                    anyhow::bail!("The attestation was reported without any artifacts");
+                }
+
+                // the document is published as is, so it must describe what has been reported
+                let document = AttestationDocument::from_json(&attestation_document)
+                    .context("The enclave client reported an invalid attestation document")?;
+                if document.commit_hash != commit_hash
+                    || document.source_tree_digest != source_tree_digest
+                    || document.artifacts != artifacts
+                {
+                    anyhow::bail!(
+                        "The attestation document does not match the reported commit and artifacts"
+                    );
+                }
+                if document.build_environment.hermetic_cache_digest.is_some() != hermetic {
+                    anyhow::bail!(
+                        "The attestation document does not match the requested (non-)hermetic build"
+                    );
+                }
+                if document.build_environment.rootfs_digest.is_some() != sandboxed {
+                    anyhow::bail!(
+                        "The attestation document does not match the requested (non-)sandboxed build"
+                    );
+                }
+                if document.build_environment.image_digest != image_digest {
+                    anyhow::bail!(
+                        "The attestation document does not match the requested build environment image"
+                    );
+                }
+                // the entries carry our nonce, so the document must not be one of another job
+                if document.nonce != hex::encode(&attestation_nonce) {
+                    anyhow::bail!("The attestation document does not carry the nonce of this job");
+                }
+
+                // all artifacts link back to the same attestation, which covers all of them
+                for artifact in artifacts.drain(..) {
+                    let attestation_entry = AttestationEntry {
+                        commit_hash: commit_hash.clone(),
+                        artifact_hash: artifact.hash,
+                        artifact_name: artifact.name,
+                        attestation_document: attestation_document.clone(),
+                        nonce: hex::encode(&attestation_nonce),
+                    };
+                    log_entry_tx.send(attestation_entry).await?;
+                }
                 break;
             }
-            Message::EnclaveToHost(EnclaveToHostMessage::Log { message }) => {
+            EnclaveToHostMessage::Log { message } => {
                 debug!("LOG: {}", message);
             }
-            Message::EnclaveToHost(EnclaveToHostMessage::Timestamp { marker, datetime }) => {
+            EnclaveToHostMessage::Timestamp { marker, datetime } => {
                 log_timestamp(&EnclaveToHostMessage::Timestamp { marker, datetime });
             }
             _ => {
@@ -116,3 +292,660 @@ pub async fn interact_with_enclave_client(
     info!("Finished interacting with the enclave client");
     Ok(())
 }
+
+/// The established connection to the enclave client together with the backend's request to
+/// cancel the job and the liveness tracking.
+struct EnclaveConnection<W> {
+    messages: mpsc::Receiver<Result<Message, ProtocolError>>,
+    writer: SecureWriteHalf<W>,
+    cancel_rx: oneshot::Receiver<String>,
+    cancel_requested: bool,
+    liveness_timeout: Option<Duration>,
+    last_seen: Instant,
+    last_stage: Option<EnclaveStage>,
+}
+
+impl<W: AsyncWrite + Unpin> EnclaveConnection<W> {
+    /// Wait for the next message and forward a cancellation to the enclave client in the
+    /// meantime. Heartbeats are consumed here, reported errors and a missed liveness deadline
+    /// are turned into an `Err`, and `None` is returned once the enclave client acknowledged
+    /// the cancellation.
+    async fn next_message(&mut self) -> anyhow::Result<Option<EnclaveToHostMessage>> {
+        loop {
+            let liveness_deadline = self
+                .liveness_timeout
+                .map(|timeout| self.last_seen + timeout);
+            tokio::select! {
+                message = self.messages.recv() => {
+                    let message = message
+                        .context("The connection to the enclave client is closed")?
+                        .context("Failed to read the next message from the enclave client")?;
+                    self.last_seen = Instant::now();
+                    match message {
+                        Message::EnclaveToHost(EnclaveToHostMessage::Heartbeat { stage }) => {
+                            debug!("Heartbeat from the enclave client: {:?}", stage);
+                            self.last_stage = Some(stage);
+                        }
+                        Message::EnclaveToHost(EnclaveToHostMessage::Error { stage, kind, detail }) => {
+                            anyhow::bail!(
+                                "The enclave client failed in stage {:?} ({:?}): {}",
+                                stage,
+                                kind,
+                                detail
+                            );
+                        }
+                        Message::EnclaveToHost(EnclaveToHostMessage::Cancelled) => {
+                            info!("The enclave client cancelled the job");
+                            return Ok(None);
+                        }
+                        Message::EnclaveToHost(message) => return Ok(Some(message)),
+                        _ => anyhow::bail!("Unexpected message: {:?}", message),
+                    }
+                }
+                _ = sleep_until_deadline(liveness_deadline) => {
+                    anyhow::bail!(
+                        "The enclave client has not been heard of for {:?} (last reported stage: {:?}), \
+                        assuming that it hangs",
+                        self.liveness_timeout.unwrap_or_default(),
+                        self.last_stage
+                    );
+                }
+                result = &mut self.cancel_rx, if !self.cancel_requested => {
+                    self.cancel_requested = true;
+                    if let Ok(reason) = result {
+                        info!("Cancelling the job in the enclave client: {}", reason);
+                        let message = Message::HostToEnclave(HostToEnclaveMessage::Cancel { reason });
+                        self.writer.write_message(&message).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The nonce for the build attestation: the run ID followed by a random challenge. The run ID
+/// lets verifiers tell which job an attestation belongs to, the challenge makes it unpredictable.
+fn attestation_nonce(run_id: u32) -> anyhow::Result<Vec<u8>> {
+    let mut challenge = [0u8; ATTESTATION_CHALLENGE_LEN];
+    openssl::rand::rand_bytes(&mut challenge)?;
+    let mut nonce = run_id.to_be_bytes().to_vec();
+    nonce.extend_from_slice(&challenge);
+    Ok(nonce)
+}
+
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Ask the enclave client for an attested channel key and run the handshake with it. The nonce
+/// ensures that the attestation is fresh and also serves as the prologue of the handshake.
+async fn establish_secure_channel<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    attestation_backend: AttestationBackend,
+    interaction_config: &InteractionConfig,
+) -> anyhow::Result<SecureChannel<S>> {
+    let mut nonce = [0u8; CHANNEL_NONCE_LEN];
+    openssl::rand::rand_bytes(&mut nonce)?;
+    let message = Message::HostToEnclave(HostToEnclaveMessage::RequestChannelKey {
+        nonce: nonce.to_vec(),
+        attestation_backend,
+    });
+    protocol::write_message(&mut stream, &message).await?;
+
+    let message = protocol::read_next_message(&mut stream, &interaction_config.protocol_config)
+        .await
+        .context("Failed to read the channel key from the enclave client")?;
+    let Message::EnclaveToHost(EnclaveToHostMessage::ChannelKey {
+        public_key,
+        attestation_document,
+    }) = message
+    else {
+        anyhow::bail!("Expected a channel key, got: {:?}", message);
+    };
+
+    if attestation_backend == AttestationBackend::None {
+        warn!("Not verifying the channel key (no attestation), the secrets are not protected");
+    } else {
+        let root_cert_pem = interaction_config
+            .root_cert_path
+            .as_ref()
+            .map(|path| {
+                std::fs::read(path)
+                    .with_context(|| format!("Failed to read the root cert from {:?}", path))
+            })
+            .transpose()?;
+        verify_channel_key_attestation(
+            &attestation_document,
+            &public_key,
+            &nonce,
+            interaction_config.expected_pcr0.as_deref(),
+            root_cert_pem.as_deref(),
+        )
+        .context("Failed to verify the channel key attestation")?;
+        debug!("Verified the channel key attestation");
+    }
+
+    let channel = secure_channel::initiate(
+        stream,
+        &public_key,
+        &nonce,
+        &interaction_config.protocol_config,
+    )
+    .await
+    .context("Failed to establish the secure channel")?;
+    debug!("Established the secure channel with the enclave client");
+    Ok(channel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::claims::BuildEnvironment;
+    use common::messages::ErrorKind;
+    use common::{ResourceLimits, RunnerArgs};
+    use tokio::io::DuplexStream;
+    use tokio::sync::mpsc;
+
+    fn sample_enclave_client_args() -> EnclaveClientArgs {
+        EnclaveClientArgs {
+            runner_args: RunnerArgs {
+                github_repository: "owner/repo".to_string(),
+                github_reg_token: "reg_token".to_string(),
+                github_pat_token: "pat_token".to_string(),
+                runner_version: "2.328.0".to_string(),
+                runner_user: "runner".to_string(),
+                runner_uid: 1001,
+                runner_gid: 1001,
+            },
+            runner_start_mode: RunnerStartMode::Direct,
+            fake_runner_args: None,
+            attestation_backend: AttestationBackend::None,
+            hermetic_cache_path: None,
+            resource_limits: ResourceLimits::default(),
+            build_environment_image: None,
+        }
+    }
+
+    fn sample_attestation_document(artifacts: &[(&str, &str)], nonce: &[u8]) -> String {
+        let artifacts: Vec<Artifact> = artifacts
+            .iter()
+            .map(|(name, hash)| Artifact {
+                name: name.to_string(),
+                hash: hash.to_string(),
+            })
+            .collect();
+        AttestationDocument {
+            schema_version: common::attestation_document::ATTESTATION_DOCUMENT_SCHEMA_VERSION,
+            commit_hash: "commit".to_string(),
+            source_tree_digest: "tree".to_string(),
+            build_environment: BuildEnvironment::default(),
+            build_environment_digest: common::claims::build_environment_digest(
+                &BuildEnvironment::default(),
+            ),
+            fetched_inputs: vec![],
+            fetched_inputs_digest: common::claims::fetched_inputs_digest(&[]),
+            artifacts_digest: common::claims::artifacts_digest(&artifacts),
+            artifacts,
+            run_id: 42,
+            provenance: "{}".to_string(),
+            nonce: hex::encode(nonce),
+            pcr0: "fake0".to_string(),
+            pcr1: "fake1".to_string(),
+            pcr2: "fake2".to_string(),
+            attestation: "fake signature".to_string(),
+        }
+        .to_json()
+        .unwrap()
+    }
+
+    /// Read the `StartRunner` message and return its attestation nonce.
+    async fn start_runner_nonce(channel: &mut SecureChannel<DuplexStream>) -> Vec<u8> {
+        let message = channel.read_next_message().await.unwrap();
+        let Message::HostToEnclave(HostToEnclaveMessage::StartRunner {
+            attestation_nonce, ..
+        }) = message
+        else {
+            panic!("unexpected message received: {:?}", message);
+        };
+        attestation_nonce
+    }
+
+    async fn send(channel: &mut SecureChannel<DuplexStream>, message: EnclaveToHostMessage) {
+        channel
+            .write_message(&Message::EnclaveToHost(message))
+            .await
+            .unwrap();
+    }
+
+    /// Play the enclave client up to the established secure channel.
+    async fn accept_as_enclave(mut stream: DuplexStream) -> SecureChannel<DuplexStream> {
+        let config = ProtocolConfig::default();
+        let host_hello = protocol::read_hello(&mut stream, &config).await.unwrap();
+        assert_eq!(host_hello, Hello::new(Capability::ALL));
+        protocol::write_hello(&mut stream, &Hello::new(Capability::ALL))
+            .await
+            .unwrap();
+
+        let message = protocol::read_next_message(&mut stream, &config)
+            .await
+            .unwrap();
+        let Message::HostToEnclave(HostToEnclaveMessage::RequestChannelKey {
+            nonce,
+            attestation_backend: AttestationBackend::None,
+        }) = message
+        else {
+            panic!("unexpected message received: {:?}", message);
+        };
+        let keypair = secure_channel::generate_keypair().unwrap();
+        let message = Message::EnclaveToHost(EnclaveToHostMessage::ChannelKey {
+            public_key: keypair.public.clone(),
+            attestation_document: vec![],
+        });
+        protocol::write_message(&mut stream, &message)
+            .await
+            .unwrap();
+
+        secure_channel::respond(stream, &keypair, &nonce, &config)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_interact_over_stream_publishes_attestation() {
+        let (host_stream, enclave_stream) = tokio::io::duplex(4096);
+        let (log_entry_tx, mut log_entry_rx) = mpsc::channel(2);
+
+        let host_task = tokio::spawn(interact_over_stream(
+            host_stream,
+            42,
+            sample_enclave_client_args(),
+            log_entry_tx,
+            InteractionConfig::default(),
+            oneshot::channel().1,
+        ));
+
+        // play the enclave client
+        let mut enclave_stream = accept_as_enclave(enclave_stream).await;
+        let message = enclave_stream.read_next_message().await.unwrap();
+        let Message::HostToEnclave(HostToEnclaveMessage::StartRunner {
+            run_id,
+            attestation_nonce,
+            ..
+        }) = message
+        else {
+            panic!("unexpected message received: {:?}", message);
+        };
+        assert_eq!(run_id, 42);
+        assert_eq!(attestation_nonce[..4], 42u32.to_be_bytes());
+        assert_eq!(attestation_nonce.len(), 4 + ATTESTATION_CHALLENGE_LEN);
+
+        send(&mut enclave_stream, EnclaveToHostMessage::Ok { info: None }).await;
+        send(
+            &mut enclave_stream,
+            EnclaveToHostMessage::ReportRepositoryRoot {
+                commit_hash: "commit".to_string(),
+                source_tree_digest: "tree".to_string(),
+            },
+        )
+        .await;
+        let artifacts = [("name", "hash"), ("other name", "other hash")];
+        for (name, hash) in artifacts {
+            send(
+                &mut enclave_stream,
+                EnclaveToHostMessage::ReportArtifact {
+                    artifact_hash: hash.to_string(),
+                    artifact_name: name.to_string(),
+                },
+            )
+            .await;
+        }
+        send(
+            &mut enclave_stream,
+            EnclaveToHostMessage::ReportAttestation {
+                attestation_document: sample_attestation_document(&artifacts, &attestation_nonce),
+            },
+        )
+        .await;
+
+        host_task.await.unwrap().unwrap();
+
+        // one entry per artifact, all with the same attestation
+        let entry = log_entry_rx.recv().await.unwrap();
+        assert_eq!(entry.commit_hash, "commit");
+        assert_eq!(entry.artifact_name, "name");
+        assert_eq!(entry.artifact_hash, "hash");
+        assert_eq!(
+            entry.attestation_document,
+            sample_attestation_document(&artifacts, &attestation_nonce)
+        );
+        assert_eq!(entry.nonce, hex::encode(&attestation_nonce));
+        let entry = log_entry_rx.recv().await.unwrap();
+        assert_eq!(entry.commit_hash, "commit");
+        assert_eq!(entry.artifact_name, "other name");
+        assert_eq!(entry.artifact_hash, "other hash");
+        assert_eq!(
+            entry.attestation_document,
+            sample_attestation_document(&artifacts, &attestation_nonce)
+        );
+        assert!(log_entry_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_interact_over_stream_rejects_attestation_without_artifacts() {
+        let (host_stream, enclave_stream) = tokio::io::duplex(4096);
+        let (log_entry_tx, mut log_entry_rx) = mpsc::channel(1);
+
+        let host_task = tokio::spawn(interact_over_stream(
+            host_stream,
+            42,
+            sample_enclave_client_args(),
+            log_entry_tx,
+            InteractionConfig::default(),
+            oneshot::channel().1,
+        ));
+
+        let mut enclave_stream = accept_as_enclave(enclave_stream).await;
+        enclave_stream.read_next_message().await.unwrap();
+        send(&mut enclave_stream, EnclaveToHostMessage::Ok { info: None }).await;
+        send(
+            &mut enclave_stream,
+            EnclaveToHostMessage::ReportRepositoryRoot {
+                commit_hash: "commit".to_string(),
+                source_tree_digest: "tree".to_string(),
+            },
+        )
+        .await;
+        send(
+            &mut enclave_stream,
+            EnclaveToHostMessage::ReportAttestation {
+                attestation_document: "document".to_string(),
+            },
+        )
+        .await;
+
+        let err = host_task.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("without any artifacts"));
+        assert!(log_entry_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_interact_over_stream_rejects_mismatching_attestation_document() {
+        let (host_stream, enclave_stream) = tokio::io::duplex(4096);
+        let (log_entry_tx, mut log_entry_rx) = mpsc::channel(1);
+
+        let host_task = tokio::spawn(interact_over_stream(
+            host_stream,
+            42,
+            sample_enclave_client_args(),
+            log_entry_tx,
+            InteractionConfig::default(),
+            oneshot::channel().1,
+        ));
+
+        let mut enclave_stream = accept_as_enclave(enclave_stream).await;
+        let attestation_nonce = start_runner_nonce(&mut enclave_stream).await;
+        send(&mut enclave_stream, EnclaveToHostMessage::Ok { info: None }).await;
+        send(
+            &mut enclave_stream,
+            EnclaveToHostMessage::ReportRepositoryRoot {
+                commit_hash: "commit".to_string(),
+                source_tree_digest: "tree".to_string(),
+            },
+        )
+        .await;
+        send(
+            &mut enclave_stream,
+            EnclaveToHostMessage::ReportArtifact {
+                artifact_hash: "hash".to_string(),
+                artifact_name: "name".to_string(),
+            },
+        )
+        .await;
+        send(
+            &mut enclave_stream,
+            EnclaveToHostMessage::ReportAttestation {
+                attestation_document: sample_attestation_document(
+                    &[("name", "other hash")],
+                    &attestation_nonce,
+                ),
+            },
+        )
+        .await;
+
+        let err = host_task.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("does not match"));
+        assert!(log_entry_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_interact_over_stream_rejects_attestation_with_other_nonce() {
+        let (host_stream, enclave_stream) = tokio::io::duplex(4096);
+        let (log_entry_tx, mut log_entry_rx) = mpsc::channel(1);
+
+        let host_task = tokio::spawn(interact_over_stream(
+            host_stream,
+            42,
+            sample_enclave_client_args(),
+            log_entry_tx,
+            InteractionConfig::default(),
+            oneshot::channel().1,
+        ));
+
+        let mut enclave_stream = accept_as_enclave(enclave_stream).await;
+        start_runner_nonce(&mut enclave_stream).await;
+        send(&mut enclave_stream, EnclaveToHostMessage::Ok { info: None }).await;
+        send(
+            &mut enclave_stream,
+            EnclaveToHostMessage::ReportRepositoryRoot {
+                commit_hash: "commit".to_string(),
+                source_tree_digest: "tree".to_string(),
+            },
+        )
+        .await;
+        send(
+            &mut enclave_stream,
+            EnclaveToHostMessage::ReportArtifact {
+                artifact_hash: "hash".to_string(),
+                artifact_name: "name".to_string(),
+            },
+        )
+        .await;
+        // e.g. the document of an earlier run of the same job
+        let replayed_nonce = attestation_nonce(42).unwrap();
+        send(
+            &mut enclave_stream,
+            EnclaveToHostMessage::ReportAttestation {
+                attestation_document: sample_attestation_document(
+                    &[("name", "hash")],
+                    &replayed_nonce,
+                ),
+            },
+        )
+        .await;
+
+        let err = host_task.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("nonce"));
+        assert!(log_entry_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_interact_over_stream_rejects_version_mismatch() {
+        let (host_stream, mut enclave_stream) = tokio::io::duplex(4096);
+        let (log_entry_tx, _log_entry_rx) = mpsc::channel(1);
+
+        let host_task = tokio::spawn(interact_over_stream(
+            host_stream,
+            42,
+            sample_enclave_client_args(),
+            log_entry_tx,
+            InteractionConfig::default(),
+            oneshot::channel().1,
+        ));
+
+        let newer_hello = Hello {
+            protocol_version: common::messages::PROTOCOL_VERSION + 1,
+            capabilities: vec![],
+        };
+        protocol::write_hello(&mut enclave_stream, &newer_hello)
+            .await
+            .unwrap();
+
+        let err = host_task.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("protocol version mismatch"));
+    }
+
+    #[tokio::test]
+    async fn test_interact_over_stream_rejects_unattested_channel_key() {
+        let (host_stream, mut enclave_stream) = tokio::io::duplex(4096);
+        let (log_entry_tx, _log_entry_rx) = mpsc::channel(1);
+        let mut enclave_client_args = sample_enclave_client_args();
+        enclave_client_args.attestation_backend = AttestationBackend::Nitro;
+
+        let host_task = tokio::spawn(interact_over_stream(
+            host_stream,
+            42,
+            enclave_client_args,
+            log_entry_tx,
+            InteractionConfig::default(),
+            oneshot::channel().1,
+        ));
+
+        let config = ProtocolConfig::default();
+        protocol::read_hello(&mut enclave_stream, &config)
+            .await
+            .unwrap();
+        protocol::write_hello(&mut enclave_stream, &Hello::new(Capability::ALL))
+            .await
+            .unwrap();
+        protocol::read_next_message(&mut enclave_stream, &config)
+            .await
+            .unwrap();
+        let keypair = secure_channel::generate_keypair().unwrap();
+        let message = Message::EnclaveToHost(EnclaveToHostMessage::ChannelKey {
+            public_key: keypair.public,
+            attestation_document: vec![],
+        });
+        protocol::write_message(&mut enclave_stream, &message)
+            .await
+            .unwrap();
+
+        let err = host_task.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("channel key attestation"));
+    }
+
+    #[tokio::test]
+    async fn test_interact_over_stream_reports_enclave_error() {
+        let (host_stream, enclave_stream) = tokio::io::duplex(4096);
+        let (log_entry_tx, _log_entry_rx) = mpsc::channel(1);
+
+        let host_task = tokio::spawn(interact_over_stream(
+            host_stream,
+            42,
+            sample_enclave_client_args(),
+            log_entry_tx,
+            InteractionConfig::default(),
+            oneshot::channel().1,
+        ));
+
+        let mut enclave_stream = accept_as_enclave(enclave_stream).await;
+        enclave_stream.read_next_message().await.unwrap();
+        send(
+            &mut enclave_stream,
+            EnclaveToHostMessage::Error {
+                stage: EnclaveStage::Configuring,
+                kind: ErrorKind::RunnerFailed,
+                detail: "config.sh failed".to_string(),
+            },
+        )
+        .await;
+
+        let err = host_task.await.unwrap().unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(err.contains("Configuring"));
+        assert!(err.contains("config.sh failed"));
+    }
+
+    #[tokio::test]
+    async fn test_interact_over_stream_forwards_cancellation() {
+        let (host_stream, enclave_stream) = tokio::io::duplex(4096);
+        let (log_entry_tx, mut log_entry_rx) = mpsc::channel(1);
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+
+        let mut host_task = tokio::spawn(async move {
+            interact_over_stream(
+                host_stream,
+                42,
+                sample_enclave_client_args(),
+                log_entry_tx,
+                InteractionConfig::default(),
+                cancel_rx,
+            )
+            .await
+            .unwrap()
+        });
+
+        let mut enclave_stream = accept_as_enclave(enclave_stream).await;
+        enclave_stream.read_next_message().await.unwrap();
+        send(&mut enclave_stream, EnclaveToHostMessage::Ok { info: None }).await;
+
+        // answer the cancellation like the enclave client would
+        let enclave_task = tokio::spawn(async move {
+            let message = enclave_stream.read_next_message().await.unwrap();
+            assert!(matches!(
+                message,
+                Message::HostToEnclave(HostToEnclaveMessage::Cancel { .. })
+            ));
+            send(&mut enclave_stream, EnclaveToHostMessage::Cancelled).await;
+        });
+
+        let cancelled =
+            cancel_interaction(cancel_tx, &mut host_task, "stop requested".to_string()).await;
+        assert!(cancelled);
+        enclave_task.await.unwrap();
+        assert!(log_entry_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_interact_over_stream_detects_hung_enclave() {
+        let (host_stream, enclave_stream) = tokio::io::duplex(4096);
+        let (log_entry_tx, _log_entry_rx) = mpsc::channel(1);
+        let interaction_config = InteractionConfig {
+            liveness_timeout: Some(Duration::from_millis(200)),
+            ..InteractionConfig::default()
+        };
+
+        let host_task = tokio::spawn(interact_over_stream(
+            host_stream,
+            42,
+            sample_enclave_client_args(),
+            log_entry_tx,
+            interaction_config,
+            oneshot::channel().1,
+        ));
+
+        let mut enclave_stream = accept_as_enclave(enclave_stream).await;
+        enclave_stream.read_next_message().await.unwrap();
+
+        // heartbeats keep the job alive, even without any other progress
+        for _ in 0..4 {
+            send(
+                &mut enclave_stream,
+                EnclaveToHostMessage::Heartbeat {
+                    stage: EnclaveStage::Configuring,
+                },
+            )
+            .await;
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert!(!host_task.is_finished());
+
+        // but silence does not
+        let err = host_task.await.unwrap().unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(err.contains("assuming that it hangs"));
+        assert!(err.contains("Configuring"));
+    }
+}
@@ -1,8 +1,13 @@
 use anyhow::bail;
-use common::{redact_token, EnclaveClientArgs, RunnerArgs, RunnerStartMode};
+use common::{
+    redact_token, AttestationBackend, BuildEnvironmentImage, EnclaveClientArgs, ResourceLimits,
+    RunnerArgs, RunnerStartMode,
+};
 use serde::Deserialize;
+use std::path::PathBuf;
 use tracing::debug;
 
+pub mod attestation_verification;
 pub mod backend;
 pub mod log_publishing_service;
 pub mod webhook_service;
@@ -16,9 +21,12 @@ pub enum BackendCommand {
 
 pub async fn load_enclave_client_args(
     fake_runner_args: Option<String>,
-    use_fake_attestation: bool,
+    attestation_backend: AttestationBackend,
     runner_start_mode: RunnerStartMode,
     runner_version: String,
+    hermetic_cache_path: Option<PathBuf>,
+    resource_limits: ResourceLimits,
+    build_environment_image: Option<BuildEnvironmentImage>,
 ) -> anyhow::Result<EnclaveClientArgs> {
     let github_repository = std::env::var("GITHUB_REPOSITORY")?;
     debug!("github_repository: {}", github_repository);
@@ -43,7 +51,7 @@ pub async fn load_enclave_client_args(
         None => None,
     };
 
-    Ok(EnclaveClientArgs {
+    let enclave_client_args = EnclaveClientArgs {
         runner_args: RunnerArgs {
             github_repository,
             github_reg_token,
@@ -55,8 +63,14 @@ pub async fn load_enclave_client_args(
         },
         runner_start_mode,
         fake_runner_args,
-        use_fake_attestation,
-    })
+        attestation_backend,
+        hermetic_cache_path,
+        resource_limits,
+        build_environment_image,
+    };
+    // the enclave client checks this as well, but we rather fail before starting any enclave
+    enclave_client_args.validate()?;
+    Ok(enclave_client_args)
 }
 
 #[derive(Debug, Deserialize)]
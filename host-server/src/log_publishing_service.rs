@@ -36,6 +36,10 @@ pub struct AttestationEntry {
     pub artifact_hash: String,
     pub artifact_name: String,
     pub attestation_document: String,
+
+    /// The hex encoded nonce that the attestation document covers (empty for older entries).
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub nonce: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
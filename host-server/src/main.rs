@@ -1,10 +1,15 @@
+use backend::local::LocalNsmEmulator;
 use backend::nitro::NitroSize;
+use backend::shared::{InteractionConfig, DEFAULT_LIVENESS_TIMEOUT};
 use clap::{Parser, ValueEnum};
 use common::messages::{create_new_timestamp_now, log_timestamp};
-use common::RunnerStartMode;
+use common::protocol::{ProtocolConfig, DEFAULT_MAX_FRAME_SIZE, DEFAULT_READ_TIMEOUT};
+use common::transport::Transport;
+use common::{AttestationBackend, BuildEnvironmentImage, ResourceLimits, RunnerStartMode};
 use dotenv::dotenv;
 use host_server::log_publishing_service::TransparencyLogConfiguration;
 use host_server::{backend, webhook_service, BackendCommand};
+use std::path::PathBuf;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
 use tokio::{task, time};
@@ -25,6 +30,11 @@ struct Args {
     /// The mode in which the host server should run.
     mode: HostMode,
 
+    /// The transport used to talk to enclave clients in `local` mode. The `nitro` mode always
+    /// uses vsock.
+    #[clap(long, default_value = "vsock")]
+    local_transport: Transport,
+
     /// Which runner start mode to use
     #[clap(long, default_value = "direct")]
     runner_start_mode: RunnerStartMode,
@@ -76,6 +86,76 @@ struct Args {
     /// For large jobs: this removes the timeout and uses the large enclave configuration.
     #[clap(long, action)]
     big_job: bool,
+
+    /// The maximum size in bytes of a single frame received from the enclave client.
+    #[clap(long, default_value_t = DEFAULT_MAX_FRAME_SIZE)]
+    max_frame_size: usize,
+
+    /// How long (in seconds) the enclave client may take to deliver a frame once it started.
+    #[clap(long, default_value_t = DEFAULT_READ_TIMEOUT.as_secs())]
+    frame_read_timeout_secs: u64,
+
+    /// How long (in seconds) to wait for the next frame from the enclave client before giving up.
+    /// By default we wait forever, as builds can run for a long time without any output.
+    #[clap(long)]
+    frame_idle_timeout_secs: Option<u64>,
+
+    /// The expected PCR0 (hex) of the enclave image. The host only sends the runner secrets to
+    /// enclaves whose attestation reports this value. Required in `nitro` mode, unless the
+    /// attestation is simulated.
+    #[clap(long)]
+    expected_pcr0: Option<String>,
+
+    /// How long (in seconds) the enclave client may go without sending a heartbeat before its job
+    /// is marked as failed and the enclave is torn down. Use 0 to wait forever.
+    #[clap(long, default_value_t = DEFAULT_LIVENESS_TIMEOUT.as_secs())]
+    liveness_timeout_secs: u64,
+
+    /// Let the clients in `local` mode emulate the NSM with the root certificate in this directory,
+    /// so that they produce real attestations (signed by that root instead of AWS).
+    #[clap(long)]
+    local_nsm_emulator_dir: Option<PathBuf>,
+
+    /// A PCR value (`INDEX=HEX`) that the emulated NSM reports. May be repeated.
+    #[clap(long = "local-nsm-emulator-pcr", requires = "local_nsm_emulator_dir")]
+    local_nsm_emulator_pcrs: Vec<String>,
+
+    /// Build hermetically with the dependency cache in this directory, as seen by the enclave
+    /// client (in `nitro` mode it has to be part of the enclave image). The sandbox has no network
+    /// access after the checkout and the digest of the cache is attested. Requires a sandbox
+    /// runner start mode and `--simulate-client-use-fake-runner`: the actual runner is not
+    /// supported yet, as it needs GitHub for the whole job.
+    #[clap(long)]
+    hermetic_cache_dir: Option<PathBuf>,
+
+    /// Limit the memory (without swap) of the sandbox to this many MiB. Builds that run out of it
+    /// fail as `ResourceLimitExceeded`. Requires a sandbox runner start mode, as do the other
+    /// `--sandbox-*` limits.
+    #[clap(long)]
+    sandbox_memory_limit_mib: Option<u64>,
+
+    /// Limit the CPU time of the sandbox, in thousandths of a CPU (e.g. `1500` for 1.5 CPUs).
+    #[clap(long)]
+    sandbox_milli_cpus: Option<u64>,
+
+    /// Limit the number of processes and threads in the sandbox.
+    #[clap(long)]
+    sandbox_pids_limit: Option<u64>,
+
+    /// The relative block I/O weight (10 to 1000) of the sandbox.
+    #[clap(long)]
+    sandbox_block_io_weight: Option<u16>,
+
+    /// Run the sandbox on this build environment image, an OCI image layout tarball as seen by
+    /// the enclave client (in `nitro` mode it has to be part of the enclave image). Requires a
+    /// sandbox runner start mode and `--build-environment-image-digest`.
+    #[clap(long, requires = "build_environment_image_digest")]
+    build_environment_image: Option<PathBuf>,
+
+    /// The digest (`sha256:<hex>`) of the manifest of the build environment image. It has to be
+    /// on the image allowlist of the enclave client and is attested.
+    #[clap(long, requires = "build_environment_image")]
+    build_environment_image_digest: Option<String>,
 }
 
 #[tokio::main]
@@ -88,11 +168,47 @@ async fn main() -> anyhow::Result<()> {
     debug!("{:?}", args);
 
     // Load service configurations
+    let nsm_emulator = match (&args.mode, args.local_nsm_emulator_dir) {
+        (HostMode::Local, Some(dir)) => Some(LocalNsmEmulator {
+            dir,
+            pcrs: args.local_nsm_emulator_pcrs,
+        }),
+        (HostMode::Nitro, Some(_)) => {
+            anyhow::bail!("The NSM emulator is only available in local mode")
+        }
+        (_, None) => None,
+    };
+    let attestation_backend = if args.simulate_client_use_fake_attestation {
+        AttestationBackend::None
+    } else if nsm_emulator.is_some() {
+        AttestationBackend::Emulated
+    } else {
+        AttestationBackend::Nitro
+    };
+    // without it, the runner secrets would go to any Nitro enclave, whatever image it runs
+    if matches!(args.mode, HostMode::Nitro)
+        && attestation_backend != AttestationBackend::None
+        && args.expected_pcr0.is_none()
+    {
+        anyhow::bail!("--expected-pcr0 is required in nitro mode");
+    }
     let runner_args = host_server::load_enclave_client_args(
         args.simulate_client_use_fake_runner,
-        args.simulate_client_use_fake_attestation,
+        attestation_backend,
         args.runner_start_mode,
         args.runner_version,
+        args.hermetic_cache_dir,
+        ResourceLimits {
+            memory_bytes: args
+                .sandbox_memory_limit_mib
+                .map(|mib| mib.saturating_mul(1024 * 1024)),
+            milli_cpus: args.sandbox_milli_cpus,
+            pids: args.sandbox_pids_limit,
+            block_io_weight: args.sandbox_block_io_weight,
+        },
+        args.build_environment_image
+            .zip(args.build_environment_image_digest)
+            .map(|(path, digest)| BuildEnvironmentImage { path, digest }),
     )
     .await?;
     let transparency_log_config = TransparencyLogConfiguration {
@@ -102,6 +218,19 @@ async fn main() -> anyhow::Result<()> {
         log_id: args.log_id,
         simulate: args.simulate_log_publishing,
     };
+    let interaction_config = InteractionConfig {
+        protocol_config: ProtocolConfig {
+            max_frame_size: args.max_frame_size,
+            read_timeout: time::Duration::from_secs(args.frame_read_timeout_secs),
+            idle_timeout: args.frame_idle_timeout_secs.map(time::Duration::from_secs),
+        },
+        expected_pcr0: args.expected_pcr0.map(hex::decode).transpose()?,
+        liveness_timeout: (args.liveness_timeout_secs > 0)
+            .then(|| time::Duration::from_secs(args.liveness_timeout_secs)),
+        root_cert_path: nsm_emulator
+            .as_ref()
+            .map(|nsm_emulator| nsm_emulator.dir.join("root.pem")),
+    };
 
     // Start the log publishing service
     let (log_entry_tx, log_entry_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
@@ -130,6 +259,7 @@ async fn main() -> anyhow::Result<()> {
                     nitro_size,
                     backend_command_rx,
                     log_entry_tx,
+                    interaction_config,
                 )
                 .await
                 .expect("Failed to create Nitro service");
@@ -143,6 +273,9 @@ async fn main() -> anyhow::Result<()> {
                     runner_args,
                     backend_command_rx,
                     log_entry_tx,
+                    interaction_config,
+                    args.local_transport,
+                    nsm_emulator,
                 );
                 local_service.run().await.expect("Local service failed");
             })
@@ -1,3 +1,4 @@
+use common::attestation_document::AttestationDocument;
 use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::State;
@@ -27,6 +28,12 @@ pub async fn add_log_entry<'a>(
         .unwrap();
 
     let log_data: LogEntry = log_entry.0;
+
+    // the log is append-only, so we do not let malformed documents in
+    if AttestationDocument::from_json(&log_data.attestation_document).is_err() {
+        return Err(Status::BadRequest);
+    }
+
     let request = tonic::Request::new(QueueLeafRequest {
         log_id,
         leaf: Option::from(LogLeaf {
@@ -7,6 +7,9 @@ pub struct LogEntry {
     pub artifact_hash: String,
     pub artifact_name: String,
     pub attestation_document: String,
+    /// Hex encoded nonce of the attestation, empty for entries that predate it
+    #[serde(default)]
+    pub nonce: String,
 }
 
 impl LogEntry {
@@ -16,6 +19,8 @@ impl LogEntry {
         result.extend(self.artifact_hash.as_bytes());
         result.extend(self.artifact_name.as_bytes());
         result.extend(self.attestation_document.as_bytes());
+        // appended only if set, so that the leaves of older entries stay the same
+        result.extend(self.nonce.as_bytes());
         result
     }
 
@@ -12,8 +12,8 @@ ls -la .;
 rm -f ".runner" ".credentials" ".credentials_rsaparams" "svc.sh" || true;
 
 ./config.sh --url "https://github.com/$GITHUB_REPOSITORY" --token "$GITHUB_REG_TOKEN" --ephemeral --disableupdate --unattended --replace --name "$GITHUB_RUNNER_NAME";
-echo "RUNNER_CONFIGURATION_DONE=true" >> /app/github-runner/output/output.log;
+echo '{"version":4,"type":"runner_configuration_done"}' >> /app/github-runner/output/output.log;
 
 # Then start the runner
 ./run.sh;
-echo "RUNNER_FINISHED=true" >> /app/github-runner/output/output.log;
+echo '{"version":4,"type":"runner_finished"}' >> /app/github-runner/output/output.log;
@@ -2,12 +2,13 @@ use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
 use nsm_io::{AttestationDoc};
 use serde_bytes::ByteBuf;
-use openssl::x509::{X509, store::X509StoreBuilder, X509StoreContext};
+use openssl::x509::{X509, store::X509StoreBuilder, verify::X509VerifyParam, X509StoreContext};
 use rustls_pki_types::{CertificateDer,pem::PemObject};
 use webpki::{EndEntityCert, TrustAnchor, TlsServerTrustAnchors};
 use openssl::stack::Stack;
-use std::time::{SystemTime, UNIX_EPOCH};
 use crate::models::attestation_data::AttestationData;
+use common::claims::{Artifact, AttestationClaims};
+use common::provenance::provenance_digest;
 
 pub(super) use aws_nitro_enclaves_cose::CoseSign1;
 pub(super) use aws_nitro_enclaves_cose::crypto::Openssl;
@@ -40,29 +41,51 @@ fn verify_pcrs(attestation_doc: &AttestationDoc, expected_pcrs: &[String]) {
     assert_eq!(pcr2, expected_pcrs[2], "PCR2 mismatch");
 }
 
-// TODO: Refactor user_data 
-// Currently array should be formated like: commit_hash,artifact_name,artifact_hash
-fn verify_user_dat(attestation_doc: &AttestationDoc, expected_user_data: &[String]) -> Result<(), anyhow::Error> {
-    let user_data_buf : ByteBuf = attestation_doc.user_data.as_ref().ok_or_else(|| anyhow::anyhow!("User data not found"))?.clone();    
-    let user_data = String::from_utf8(user_data_buf.into_vec()).map_err(|_| anyhow::anyhow!("Failed to parse user data"))?;
-    let data_parts: Vec<&str> = user_data.split(',').collect();
-    for (i,part) in data_parts.iter().enumerate() {
-        let parts: Vec<&str> = part.split('=').collect();
-        if parts.len() != 2 {
-            return Err(anyhow::anyhow!("Invalid user data format"));
-        }
-        let _key = parts[0];
-        let value = parts[1];
-        assert_eq!(value, expected_user_data[i], "User data mismatch");
+// The user data holds the CBOR encoded `AttestationClaims` (see the common crate)
+fn verify_user_dat(attestation_doc: &AttestationDoc, attestation_data: &AttestationData) -> Result<(), anyhow::Error> {
+    let user_data_buf : ByteBuf = attestation_doc.user_data.as_ref().ok_or_else(|| anyhow::anyhow!("User data not found"))?.clone();
+    let claims = AttestationClaims::from_user_data(&user_data_buf)?;
+    let expected_claims = AttestationClaims::new(
+        attestation_data.commit_hash.clone(),
+        attestation_data.source_tree_digest.clone(),
+        &attestation_data.build_environment,
+        &attestation_data.fetched_inputs,
+        &attestation_data.artifacts,
+        provenance_digest(&attestation_data.provenance));
+    assert_eq!(claims, expected_claims, "User data mismatch");
+
+    // the attested list must contain the artifact that we are verifying
+    let artifact = Artifact {
+        name: attestation_data.artifact_name.clone(),
+        hash: attestation_data.artifact_hash.clone(),
+    };
+    if !attestation_data.artifacts.contains(&artifact) {
+        return Err(anyhow::anyhow!("The artifact is not covered by the attestation"));
     }
     Ok(())
 }
 
-fn verify_signature_rustls(attestation_doc: &AttestationDoc) -> Result<(), anyhow::Error> {
+// The nonce ties the attestation to a specific job, so that it cannot be replayed for another one
+fn verify_nonce(attestation_doc: &AttestationDoc, expected_nonce: &str) -> Result<(), anyhow::Error> {
+    let expected_nonce = hex::decode(expected_nonce).map_err(|_| anyhow::anyhow!("Failed to decode the nonce"))?;
+    let nonce = attestation_doc.nonce.as_ref().map(|nonce| nonce.to_vec()).unwrap_or_default();
+    if nonce != expected_nonce {
+        return Err(anyhow::anyhow!("Nonce mismatch: expected {} but the attestation covers {}", hex::encode(expected_nonce), hex::encode(nonce)));
+    }
+    Ok(())
+}
+
+// The certificates are checked at the time of the attestation, as the ones from the NSM are only
+// valid for a few hours but the log entries are verified long after
+fn attestation_time(attestation_doc: &AttestationDoc) -> u64 {
+    attestation_doc.timestamp / 1000
+}
+
+fn verify_signature_rustls(attestation_doc: &AttestationDoc, root_cert: &[u8]) -> Result<(), anyhow::Error> {
 
     // Prepare trust anchor    
-    let x = CertificateDer::from_pem_slice(ROOT_CERT).unwrap();
-    let trust_anchor = [TrustAnchor::try_from_cert_der(&x).map_err(|_| anyhow::anyhow!("Failed"))?];    
+    let x = CertificateDer::from_pem_slice(root_cert).map_err(|_| anyhow::anyhow!("Failed to parse root cert"))?;
+    let trust_anchor = [TrustAnchor::try_from_cert_der(&x).map_err(|_| anyhow::anyhow!("Failed to create trust anchor"))?];
     let trust_anchors = TlsServerTrustAnchors(&trust_anchor);
 
     // Prepare intermediate certificates
@@ -77,76 +100,177 @@ fn verify_signature_rustls(attestation_doc: &AttestationDoc) -> Result<(), anyho
     let certa = &attestation_doc.certificate.to_vec();
     let end_entity_cert = EndEntityCert::try_from(certa.as_slice()).map_err(|_| anyhow::anyhow!("Failed to create end entity cert"))?;    
     
-    let epoc = SystemTime::now().duration_since(UNIX_EPOCH)?;
-    let epoch = webpki::Time::from_seconds_since_unix_epoch(epoc.as_secs() as u64);
-
-    let verification_result = end_entity_cert.verify_is_valid_tls_server_cert(
-        SIGNATURE_ALGORITHM,
-        &trust_anchors,
-        &intermediate_certs,
-        epoch,
-    );
-
-    match verification_result {
-        Ok(_) => println!("Certificate verification succeeded"),
-        Err(e) => println!("Certificate verification failed: {:?}", e),
-    }
-    //TODO insert assert
+    let epoch = webpki::Time::from_seconds_since_unix_epoch(attestation_time(attestation_doc));
+
+    end_entity_cert
+        .verify_is_valid_tls_server_cert(SIGNATURE_ALGORITHM, &trust_anchors, &intermediate_certs, epoch)
+        .map_err(|e| anyhow::anyhow!("Certificate verification failed: {:?}", e))?;
     Ok(())
 }
 
-fn verify_signature_openssl(attestation_doc: &AttestationDoc) -> Result<(), anyhow::Error> {
+fn verify_signature_openssl(attestation_doc: &AttestationDoc, root_cert: &[u8]) -> Result<(), anyhow::Error> {
 
     // trusted x509 store
     let mut trusted_builder = X509StoreBuilder::new()?;
-    let root_cert = X509::from_pem(ROOT_CERT).map_err(|_| anyhow::anyhow!("Failed to parse root cert"))?;
+    let root_cert = X509::from_pem(root_cert).map_err(|_| anyhow::anyhow!("Failed to parse root cert"))?;
     trusted_builder.add_cert(root_cert.clone()).map_err(|_| anyhow::anyhow!("Failed to add root cert"))?;
-    //trusted_builder.set_flags(X509VerifyFlags::NO_CHECK_TIME)?;
+    let mut param = X509VerifyParam::new()?;
+    param.set_time(attestation_time(attestation_doc) as _);
+    trusted_builder.set_param(&param)?;
     let trusted_store = trusted_builder.build();
 
     //certificate to be verified   
     let cert_to_be_verified = X509::from_der(&attestation_doc.certificate).map_err(|_| anyhow::anyhow!("Failed to parse certificate"))?;
-    //println!("Certificate to be verified: {:?}", cert_to_be_verified);
 
     //certificate chain
     let mut intermediate_certs = Stack::new()?;
-    let ca_bundle = &attestation_doc.cabundle;   
-    ca_bundle.iter().for_each(|cert| {
-        let cert: X509 = X509::from_der(cert).unwrap();
-        let _ = intermediate_certs.push(cert.clone());
-    });
+    for cert in &attestation_doc.cabundle {
+        let cert = X509::from_der(cert).map_err(|_| anyhow::anyhow!("Failed to parse CA bundle certificate"))?;
+        intermediate_certs.push(cert)?;
+    }
         
     // Init X509 Context
     let mut store_context = X509StoreContext::new()?;    
-    store_context.init(&trusted_store, &cert_to_be_verified, &intermediate_certs, |context| {
-        let verify_result = context.verify_cert();
-        match verify_result {
-            Ok(ref result) => println!("Certificate verification succeeded: {:?}", result),
-            Err(ref e) => println!("Certificate verification failed: {:?}", e),
-        }
-        Ok(())            
+    let verified = store_context.init(&trusted_store, &cert_to_be_verified, &intermediate_certs, |context| {
+        let verified = context.verify_cert()?;
+        Ok(if verified { Ok(()) } else { Err(context.error()) })
     })?;
+    verified.map_err(|e| anyhow::anyhow!("Certificate verification failed: {}", e))?;
 
     Ok(())
 }
 
+// The signature has to be made with the key of the document's certificate, which has to chain up
+// to the root certificate (checked with both webpki and OpenSSL)
+fn verify_evidence(attestation_document: &[u8], root_cert: &[u8]) -> Result<AttestationDoc, anyhow::Error> {
+    let cose_sign_1 = CoseSign1::from_bytes(attestation_document)?;
+    let payload = cose_sign_1.get_payload::<Openssl>(None).map_err(|_| anyhow::anyhow!("Failed to decode the payload"))?;
+    let attestation_doc: AttestationDoc = serde_cbor::from_slice(&payload)?;
+    let cert_to_be_verified = X509::from_der(&attestation_doc.certificate).map_err(|_| anyhow::anyhow!("Failed to parse certificate"))?;
+    let signature_valid = cose_sign_1.verify_signature::<Openssl>(&cert_to_be_verified.public_key()?).map_err(|_| anyhow::anyhow!("Failed to verify signature"))?;
+    if !signature_valid {
+        return Err(anyhow::anyhow!("The signature of the attestation document is invalid"));
+    }
+
+    verify_signature_rustls(&attestation_doc, root_cert)?;
+    verify_signature_openssl(&attestation_doc, root_cert)?;
+    Ok(attestation_doc)
+}
+
+// The root certificate defaults to the AWS Nitro one, but attestations from the NSM emulator are
+// signed by the root.pem in its directory instead
 pub async fn validate_attestation_document(    
     attestation_data: AttestationData,    
+    root_cert: Option<&[u8]>,
 ) -> anyhow::Result<()> {
+    let root_cert = root_cert.unwrap_or(ROOT_CERT);
 
-    let at = BASE64_STANDARD.decode(attestation_data.attestation_document).map_err(|_| anyhow::anyhow!("Failed to decode attestation document"))?;
-    let cose_sign_1 = CoseSign1::from_bytes(&at)?;
-    let payload = cose_sign_1.get_payload::<Openssl>(None).unwrap();    
-    let attestation_doc: AttestationDoc = serde_cbor::from_slice(&payload)?;
-    let cert_to_be_verified = X509::from_der(&attestation_doc.certificate).map_err(|_| anyhow::anyhow!("Failed to parse certificate"))?;    
-    let _signature = cose_sign_1.verify_signature::<Openssl>(&cert_to_be_verified.public_key()?).map_err(|_| anyhow::anyhow!("Failed to verify signature"))?;     
-    //TODO verify signature
+    let at = BASE64_STANDARD.decode(&attestation_data.attestation_document).map_err(|_| anyhow::anyhow!("Failed to decode attestation document"))?;
+    let attestation_doc = verify_evidence(&at, root_cert)?;
 
+    verify_user_dat(&attestation_doc, &attestation_data)?;
+    verify_nonce(&attestation_doc, &attestation_data.nonce)?;
     verify_pcrs(&attestation_doc, &[attestation_data.pcr0, attestation_data.pcr1, attestation_data.pcr2]);
-    let _ =verify_signature_rustls(&attestation_doc);
-    let _ =verify_signature_openssl(&attestation_doc);
-    verify_user_dat(&attestation_doc, &[attestation_data.commit_hash, attestation_data.artifact_name, attestation_data.artifact_hash])?;
    
     Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_nitro_enclaves_cose::header_map::HeaderMap;
+    use nsm_io::Digest;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::{BasicConstraints, KeyUsage};
+    use openssl::x509::X509NameBuilder;
+    use std::collections::BTreeMap;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn new_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    // Like the certificates of the NSM emulator: a self-signed CA without an issuer
+    fn new_cert(name: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>) -> X509 {
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(128, MsbOption::MAYBE_ZERO, false).unwrap();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let (issuer_name, signing_key) = match issuer {
+            Some((issuer_cert, issuer_key)) => {
+                builder.append_extension(KeyUsage::new().critical().digital_signature().build().unwrap()).unwrap();
+                (issuer_cert.subject_name(), issuer_key)
+            }
+            None => {
+                builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+                builder.append_extension(KeyUsage::new().critical().key_cert_sign().build().unwrap()).unwrap();
+                (subject.as_ref(), key)
+            }
+        };
+        builder.set_issuer_name(issuer_name).unwrap();
+        builder.sign(signing_key, MessageDigest::sha384()).unwrap();
+        builder.build()
+    }
+
+    // An attestation document as the NSM emulator with the given root issues it
+    fn attestation_document(root_cert: &X509, root_key: &PKey<Private>, signing_key: &PKey<Private>) -> Vec<u8> {
+        let certificate = new_cert("emulated-nsm", signing_key, Some((root_cert, root_key)));
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let attestation_doc = AttestationDoc::new(
+            "i-emulated-enc0000000000000000".to_string(),
+            Digest::SHA384,
+            timestamp,
+            BTreeMap::from([(0, vec![0; 48])]),
+            certificate.to_der().unwrap(),
+            vec![root_cert.to_der().unwrap()],
+            None,
+            None,
+            None,
+        );
+        CoseSign1::new::<Openssl>(&attestation_doc.to_binary(), &HeaderMap::new(), signing_key)
+            .unwrap()
+            .as_bytes(false)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_verify_evidence_checks_the_signature_and_the_root() {
+        let root_key = new_key();
+        let root_cert = new_cert("emulated-nsm-root", &root_key, None);
+        let root_pem = root_cert.to_pem().unwrap();
+        let other_root_key = new_key();
+        let other_root_cert = new_cert("other-emulated-nsm-root", &other_root_key, None);
+
+        let signing_key = new_key();
+        let document = attestation_document(&root_cert, &root_key, &signing_key);
+        verify_evidence(&document, &root_pem).unwrap();
+
+        // issued by another (emulator) root
+        let document = attestation_document(&other_root_cert, &other_root_key, &signing_key);
+        assert!(verify_evidence(&document, &root_pem).is_err());
+        verify_evidence(&document, &other_root_cert.to_pem().unwrap()).unwrap();
+
+        // signed by a key that does not belong to the certificate
+        let mut document = CoseSign1::from_bytes(&attestation_document(&root_cert, &root_key, &signing_key)).unwrap();
+        let payload = document.get_payload::<Openssl>(None).unwrap();
+        document = CoseSign1::new::<Openssl>(&payload, &HeaderMap::new(), &new_key()).unwrap();
+        let error = verify_evidence(&document.as_bytes(false).unwrap(), &root_pem).unwrap_err();
+        assert!(error.to_string().contains("signature"), "{}", error);
+    }
+}
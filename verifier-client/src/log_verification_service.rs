@@ -75,6 +75,8 @@ mod tests {
             artifact_hash: "artifact-hash-test".to_string(),
             artifact_name: "artifact-name-test".to_string(),
             attestation_document: "attestation-test-document".to_string(),
+            // entries without a nonce must keep the leaf hash they were logged with
+            nonce: String::new(),
         };
 
         let inclusion_proof = InclusionProof {
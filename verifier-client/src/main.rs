@@ -2,7 +2,14 @@ use clap::Parser;
 
 use crate::models::log_entry::LogEntry;
 use crate::models::attestation_data::AttestationData;
+use common::attestation_document::AttestationDocument;
+use common::claims::BuildEnvironment;
+use common::lockfiles::fetched_inputs;
+use common::rootfs::rootfs_digest;
+use common::source_tree::source_tree_digest;
 use dotenv::dotenv;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
 
 mod models;
 mod transparency_service;
@@ -30,6 +37,12 @@ struct Args {
     #[clap(long)]
     artifact_name: String,
 
+    /// The hex encoded nonce that the host-server issued for the job, as listed in its log entry
+    /// (empty for entries that predate nonces). Never taken from the attestation document, which
+    /// would make any replayed document pass.
+    #[clap(long)]
+    nonce: String,
+
     #[clap(long)]
     pcr0: String,
 
@@ -39,8 +52,41 @@ struct Args {
     #[clap(long)]
     pcr2: String,
 
+    /// The attestation document as published in the log (the content of the `.cert` file)
     #[clap(long)]
     attestation_document: String,
+
+    /// The PEM root certificate the attestation has to chain up to (defaults to the AWS Nitro
+    /// root, use the `root.pem` of the NSM emulator for local attestations)
+    #[clap(long)]
+    root_cert: Option<PathBuf>,
+
+    /// A clean clone of the commit (`git clone --recurse-submodules`) whose source tree digest
+    /// and fetched inputs have to match the attested ones (defaults to those listed in the
+    /// attestation document)
+    #[clap(long)]
+    source_tree: Option<PathBuf>,
+
+    /// The dependency cache of a hermetic build, whose digest has to match the attested one
+    /// (defaults to the digest in the attestation document). Fails for non-hermetic builds.
+    #[clap(long)]
+    hermetic_cache: Option<PathBuf>,
+
+    /// The rootfs of the sandbox (`rootfs.tar` extracted as root), whose digest has to match the
+    /// attested one (defaults to the digest in the attestation document). Fails for builds
+    /// outside of a sandbox.
+    #[clap(long)]
+    rootfs: Option<PathBuf>,
+
+    /// The digest (`sha256:<hex>`) of the build environment image that the sandbox has to have
+    /// run on (defaults to the digest in the attestation document).
+    #[clap(long)]
+    image_digest: Option<String>,
+
+    /// The image allowlist of the enclave image (`image-allowlist.txt`), whose digest has to
+    /// match the attested one (defaults to the digest in the attestation document).
+    #[clap(long)]
+    image_allowlist: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -48,12 +94,42 @@ async fn main() -> anyhow::Result<()> {
     dotenv()?;
 
     let args = Args::parse();
+    let attestation_document = AttestationDocument::from_json(&args.attestation_document)?;
+    let nonce = args.nonce;
+    let build_environment = BuildEnvironment {
+        rootfs_digest: match &args.rootfs {
+            Some(rootfs) => Some(rootfs_digest(rootfs)?),
+            None => attestation_document.build_environment.rootfs_digest.clone(),
+        },
+        hermetic_cache_digest: match &args.hermetic_cache {
+            Some(hermetic_cache) => Some(source_tree_digest(hermetic_cache)?),
+            None => attestation_document.build_environment.hermetic_cache_digest.clone(),
+        },
+        image_digest: match &args.image_digest {
+            Some(image_digest) => Some(image_digest.clone()),
+            None => attestation_document.build_environment.image_digest.clone(),
+        },
+        image_allowlist_digest: match &args.image_allowlist {
+            Some(image_allowlist) => {
+                Some(format!("{:x}", Sha256::digest(std::fs::read(image_allowlist)?)))
+            }
+            None => attestation_document.build_environment.image_allowlist_digest.clone(),
+        },
+    };
+    let (source_tree_digest, fetched_inputs) = match &args.source_tree {
+        Some(source_tree) => (source_tree_digest(source_tree)?, fetched_inputs(source_tree)?),
+        None => (
+            attestation_document.source_tree_digest.clone(),
+            attestation_document.fetched_inputs.clone(),
+        ),
+    };
 
     let log_entry = LogEntry {
         commit_hash: args.commit_hash.to_string(),
         artifact_hash: args.artifact_hash.to_string(),
         artifact_name: args.artifact_name.to_string(),
         attestation_document: args.attestation_document.to_string(),
+        nonce: nonce.clone(),
     };
 
     let result = transparency_service::request_inclusion_proof(
@@ -72,16 +148,26 @@ async fn main() -> anyhow::Result<()> {
 
     let attestation_data = AttestationData {
         commit_hash: args.commit_hash.to_string(),
+        // checked against the digest in the signed user data
+        source_tree_digest,
+        build_environment,
+        fetched_inputs,
         artifact_name: args.artifact_name.to_string(),
         artifact_hash: args.artifact_hash.to_string(),
+        // checked against the digest in the signed user data
+        artifacts: attestation_document.artifacts,
+        // checked against the digest in the signed user data
+        provenance: attestation_document.provenance,
+        nonce,
         pcr0: args.pcr0.to_string(),
         pcr1: args.pcr1.to_string(),
         pcr2: args.pcr2.to_string(),
-        attestation_document: args.attestation_document.to_string(),
+        attestation_document: attestation_document.attestation,
     };
 
+    let root_cert = args.root_cert.map(std::fs::read).transpose()?;
     attestation_verification_service::validate_attestation_document(
-        attestation_data.clone()).await?;
+        attestation_data.clone(), root_cert.as_deref()).await?;
 
     Ok(())
 }
@@ -1,14 +1,29 @@
 use std::str;
 
+use common::claims::{Artifact, BuildEnvironment, FetchedInput};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AttestationData {
     pub commit_hash: String,
+    /// The digest of the checked out source tree (see `common::source_tree`)
+    pub source_tree_digest: String,
+    /// The digests of the sandbox's rootfs (see `common::rootfs`) and of the dependency cache of
+    /// a hermetic build (see `common::source_tree`)
+    pub build_environment: BuildEnvironment,
+    /// The downloads pinned by the lockfiles of the source tree (see `common::lockfiles`)
+    pub fetched_inputs: Vec<FetchedInput>,
     pub artifact_name: String,
     pub artifact_hash: String,
+    /// All artifacts attested together with this one (including itself), in the reported order
+    pub artifacts: Vec<Artifact>,
+    /// The in-toto Statement with the SLSA provenance, as published (see `common::provenance`)
+    pub provenance: String,
+    /// Hex encoded nonce that the attestation must cover (empty if it has none)
+    pub nonce: String,
     pub pcr0: String,
     pub pcr1: String,
     pub pcr2: String,
+    /// The base64 encoded COSE document from the NSM
     pub attestation_document: String
-}
\ No newline at end of file
+}